@@ -1,50 +1,132 @@
-use crate::ErrorKind;
-use reqwest::Client;
+use crate::http::{
+    parse_response, request_error, status_error, DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT,
+};
+use crate::{
+    api::lenient, http_client, Capture, CommandOutcome, CtrlResponse, ErrorKind, InverterCommand,
+    PowerLimit, RetryPolicy,
+};
+use chrono::{DateTime, Local};
+use reqwest::{header::CONTENT_TYPE, Client, StatusCode};
 use serde::{Deserialize, Serialize};
-use serde_json::from_str;
-use std::collections::HashMap;
-use std::env;
+use serde_json::json;
+use serde_json::{from_str, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+/// How often the inverter status is polled while waiting for a command to be acknowledged.
+const ACK_POLL_INTERVAL: Duration = Duration::from_secs(2);
 
 #[derive(Debug, Clone)]
 pub struct AhoyApi {
     endpoint: String,
+    password: Option<String>,
+    client: Client,
+    retry: RetryPolicy,
+    capture: Option<Capture>,
+    // shared between clones so that all inverters reuse the same session
+    token: Arc<Mutex<Option<String>>>,
     #[cfg(test)]
     offline_mode: bool,
 }
 
 impl AhoyApi {
-    pub fn from_env() -> Result<Self, ErrorKind> {
-        let endpoint = env::var("INVERTER_ENDPOINT").map_err(|_| ErrorKind::EnvVarError)?;
-        Ok(Self::new(endpoint))
-    }
-
     #[cfg(not(test))]
     pub fn new(endpoint: String) -> Self {
-        Self { endpoint }
+        Self {
+            endpoint,
+            password: None,
+            client: http_client(DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT),
+            retry: RetryPolicy::default(),
+            capture: None,
+            token: Arc::new(Mutex::new(None)),
+        }
     }
 
     #[cfg(test)]
     pub fn new(endpoint: String) -> Self {
         Self {
             endpoint,
+            password: None,
+            client: http_client(DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT),
+            retry: RetryPolicy::default(),
+            capture: None,
+            token: Arc::new(Mutex::new(None)),
             offline_mode: false,
         }
     }
+
+    /// An api answering with the fixtures of an Ahoy DTU, without requests.
+    #[cfg(test)]
+    pub(crate) fn offline() -> Self {
+        let mut api = Self::new("http://ahoy-dtu.offline".to_string());
+        api.set_offline_mode(true);
+        api
+    }
+
+    /// Use the given password to log in when the DTU reports a protected endpoint.
+    pub fn with_password(mut self, password: String) -> Self {
+        self.password = Some(password);
+        self
+    }
+
+    /// Send requests with `client`, e.g. one with different timeouts.
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Retry failed GET requests according to `retry`.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Record the response to every GET request in `capture`.
+    pub fn with_capture(mut self, capture: Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
     #[cfg(test)]
     pub fn set_offline_mode(&mut self, value: bool) {
         self.offline_mode = value;
     }
 
+    /// The current time, recorded in the capture as the start of a crawling cycle.
+    pub fn cycle_time(&self) -> DateTime<Local> {
+        let now = Local::now();
+        if let Some(capture) = &self.capture {
+            capture.record_cycle(now);
+        }
+        now
+    }
+
     async fn _request(&self, path: String) -> Result<String, ErrorKind> {
-        let client = Client::new();
         let url = format!("{}{}", self.endpoint, path);
+        let requested_at = Local::now();
+        let result = self.retry.run(|| self.get_once(&url)).await;
+        if let Some(capture) = &self.capture {
+            capture.record(&path, requested_at, &result);
+        }
+        result
+    }
+
+    async fn get_once(&self, url: &str) -> Result<String, ErrorKind> {
         log::info!("requesting {}", url);
-        let res = client
-            .get(&url)
+        let res = self
+            .client
+            .get(url)
             .send()
             .await
-            .map_err(|_| ErrorKind::NetworkError)?;
-        res.text().await.map_err(|_| ErrorKind::NetworkError)
+            .map_err(|err| request_error(url, err))?;
+        if is_unauthorized_status(res.status()) {
+            return Err(ErrorKind::PasswordRequired);
+        }
+        if let Some(err) = status_error(url, res.status()) {
+            return Err(err);
+        }
+        res.text().await.map_err(|err| request_error(url, err))
     }
 
     #[cfg(test)]
@@ -68,156 +150,431 @@ impl AhoyApi {
         self._request(path).await
     }
 
-    pub async fn get_inverter_fields(
-        &self,
-        inverter: Inverter,
-        selected_fields: Option<Vec<String>>,
-    ) -> Result<Vec<HashMap<String, UnitValue<f32>>>, ErrorKind> {
-        let inverter_channel_count = inverter.channels as usize;
-        let inverter_status = self.get_inverter_status(inverter).await?;
-        let live = self.get_live().await?;
-        let mut data = Vec::new();
+    /// Commands are not retried, a restart must not be sent twice.
+    async fn _post(&self, path: String, body: Value) -> Result<String, ErrorKind> {
+        let url = format!("{}{}", self.endpoint, path);
+        log::info!("posting to {}", url);
+        let res = self
+            .client
+            .post(&url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|err| request_error(&url, err))?;
+        if is_unauthorized_status(res.status()) {
+            return Err(ErrorKind::PasswordRequired);
+        }
+        if let Some(err) = status_error(&url, res.status()) {
+            return Err(err);
+        }
+        res.text().await.map_err(|err| request_error(&url, err))
+    }
 
-        // channel 0 is a special case, i assume it is the sum of all channels, maybe the values of the inverter itself
-        let mut channel_0 = HashMap::new();
-        for (index, fieldname) in live.ch0_fld_names.iter().enumerate() {
-            if let Some(selected_fields) = &selected_fields {
-                if !selected_fields.contains(fieldname) {
-                    continue;
+    #[cfg(test)]
+    async fn post(&self, path: String, body: Value) -> Result<String, ErrorKind> {
+        if self.offline_mode {
+            Ok(match (path.as_str(), body["cmd"].as_str()) {
+                ("/api/ctrl", Some("auth")) if body["val"] == "secret" => {
+                    "{\"success\":true,\"token\":\"offline\"}"
                 }
+                ("/api/ctrl", Some("auth")) => "{\"success\":false,\"error\":\"ERR_AUTH\"}",
+                ("/api/ctrl", _) => "{\"success\":true}",
+                _ => "",
             }
-            channel_0.insert(
-                fieldname.clone(),
-                UnitValue::new(
-                    inverter_status.ch[0][index],
-                    live.ch0_fld_units[index].clone(),
-                ),
-            );
+            .to_string())
+        } else {
+            self._post(path, body).await
         }
-        data.push(channel_0);
+    }
 
-        for channel in 1..=inverter_channel_count {
-            let mut channel_data = HashMap::new();
-            for (index, fieldname) in live.fld_names.iter().enumerate() {
-                if let Some(selected_fields) = &selected_fields {
-                    if !selected_fields.contains(fieldname) {
-                        continue;
-                    }
-                }
-                channel_data.insert(
-                    fieldname.clone(),
-                    UnitValue::new(
-                        inverter_status.ch[channel][index],
-                        live.fld_units[index].clone(),
-                    ),
+    #[cfg(not(test))]
+    async fn post(&self, path: String, body: Value) -> Result<String, ErrorKind> {
+        self._post(path, body).await
+    }
+
+    /// Log in with the configured password and store the session token for
+    /// subsequent requests.
+    pub async fn login(&self) -> Result<(), ErrorKind> {
+        let password = self.password.as_ref().ok_or(ErrorKind::PasswordRequired)?;
+        log::info!("logging in to {}", self.endpoint);
+        let res = self
+            .post(
+                "/api/ctrl".to_string(),
+                json!({"cmd": "auth", "val": password}),
+            )
+            .await?;
+        let response: CtrlResponse = parse_response("/api/ctrl", &res)?;
+        match response.token {
+            Some(token) if response.success => {
+                *self.token.lock().unwrap() = Some(token);
+                Ok(())
+            }
+            _ => {
+                log::error!(
+                    "login to {} failed: {}",
+                    self.endpoint,
+                    response.error.unwrap_or_default()
                 );
+                Err(ErrorKind::AuthenticationFailed)
             }
-            data.push(channel_data);
         }
+    }
+
+    pub fn is_logged_in(&self) -> bool {
+        self.token.lock().unwrap().is_some()
+    }
+
+    /// Perform a GET request, logging in (again) if the DTU reports the
+    /// endpoint as protected. Ahoy unlocks the GET endpoints for the address
+    /// that logged in and only checks the token on `/api/ctrl`, so the retry
+    /// is sent as is.
+    async fn authorized_request(&self, path: String) -> Result<String, ErrorKind> {
+        match self.request(path.clone()).await {
+            Ok(res) if !is_protected_response(&res) => return Ok(res),
+            Ok(_) | Err(ErrorKind::PasswordRequired) => {}
+            Err(err) => return Err(err),
+        }
+        self.login().await?;
+        let res = self.request(path).await?;
+        if is_protected_response(&res) {
+            return Err(ErrorKind::PasswordRequired);
+        }
+        Ok(res)
+    }
+
+    /// Perform a POST request with the session token attached, logging in
+    /// (again) if the DTU rejects the token.
+    async fn authorized_post(&self, path: String, body: Value) -> Result<String, ErrorKind> {
+        match self.post(path.clone(), self.with_token(body.clone())).await {
+            Ok(res) if !is_protected_response(&res) => return Ok(res),
+            Ok(_) | Err(ErrorKind::PasswordRequired) => {}
+            Err(err) => return Err(err),
+        }
+        self.login().await?;
+        let res = self.post(path, self.with_token(body)).await?;
+        if is_protected_response(&res) {
+            return Err(ErrorKind::PasswordRequired);
+        }
+        Ok(res)
+    }
 
-        Ok(data)
+    fn with_token(&self, mut body: Value) -> Value {
+        if let Some(token) = self.token.lock().unwrap().as_ref() {
+            body["token"] = json!(token);
+        }
+        body
+    }
+
+    /// Send a command to the inverter with the given id. For power limits the
+    /// inverter status is polled until `power_limit_ack` is set or `ack_timeout`
+    /// is reached.
+    pub async fn send_command(
+        &self,
+        inverter_id: u8,
+        command: InverterCommand,
+        ack_timeout: Duration,
+    ) -> Result<CommandOutcome, ErrorKind> {
+        command.validate()?;
+        let res = self
+            .authorized_post("/api/ctrl".to_string(), command.to_json(inverter_id))
+            .await?;
+        let response: CtrlResponse = parse_response("/api/ctrl", &res)?;
+        if !response.success {
+            let reason = response.error.unwrap_or("unknown error".to_string());
+            log::warn!("command {:?} was rejected: {}", command, reason);
+            return Ok(CommandOutcome::Rejected(reason));
+        }
+        if !command.expects_ack() {
+            return Ok(CommandOutcome::Accepted);
+        }
+
+        let deadline = Instant::now() + ack_timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(CommandOutcome::NotAcknowledged);
+            }
+            sleep(ACK_POLL_INTERVAL.min(remaining)).await;
+            let status = self.get_inverter_status_by_id(inverter_id).await?;
+            if status.power_limit_ack {
+                return Ok(CommandOutcome::Acknowledged {
+                    power_limit_read: status.power_limit_read,
+                });
+            }
+        }
+    }
+
+    pub async fn set_power_limit(
+        &self,
+        inverter_id: u8,
+        limit: PowerLimit,
+        persistent: bool,
+        ack_timeout: Duration,
+    ) -> Result<CommandOutcome, ErrorKind> {
+        self.send_command(
+            inverter_id,
+            InverterCommand::Limit { limit, persistent },
+            ack_timeout,
+        )
+        .await
+    }
+
+    pub async fn set_power(&self, inverter_id: u8, on: bool) -> Result<CommandOutcome, ErrorKind> {
+        self.send_command(inverter_id, InverterCommand::Power(on), Duration::ZERO)
+            .await
+    }
+
+    pub async fn restart(&self, inverter_id: u8) -> Result<CommandOutcome, ErrorKind> {
+        self.send_command(inverter_id, InverterCommand::Restart, Duration::ZERO)
+            .await
+    }
+
+    pub async fn get_inverter_fields(
+        &self,
+        inverter: Inverter,
+        selected_fields: Option<Vec<String>>,
+    ) -> Result<Vec<HashMap<String, UnitValue<f32>>>, ErrorKind> {
+        let inverter_channel_count = inverter.channels as usize;
+        let inverter_status = self.get_inverter_status(inverter).await?;
+        let live = self.get_live().await?;
+        Ok(inverter_status.fields(&live, inverter_channel_count, selected_fields.as_deref()))
     }
 
     pub async fn get_inverter_status(
         &self,
         inverter: Inverter,
     ) -> Result<InverterStatus, ErrorKind> {
-        let path = format!("/api/inverter/id/{}", inverter.id);
-        let res = self.request(path).await?;
-        from_str(&res).map_err(|_| ErrorKind::ParsingError)
+        self.get_inverter_status_by_id(inverter.id).await
+    }
+
+    pub async fn get_inverter_status_by_id(
+        &self,
+        inverter_id: u8,
+    ) -> Result<InverterStatus, ErrorKind> {
+        let path = format!("/api/inverter/id/{}", inverter_id);
+        let res = self.authorized_request(path.clone()).await?;
+        parse_response(&path, &res)
     }
 
     pub async fn get_inverter_list(&self) -> Result<InverterList, ErrorKind> {
         let path = "/api/inverter/list".to_string();
-        let res = self.request(path).await?;
-        from_str(&res).map_err(|_| ErrorKind::ParsingError)
+        let res = self.authorized_request(path.clone()).await?;
+        parse_response(&path, &res)
     }
 
     pub async fn get_live(&self) -> Result<Live, ErrorKind> {
         let path = "/api/live".to_string();
-        let res = self.request(path).await?;
-        from_str(&res).map_err(|_| ErrorKind::ParsingError)
+        let res = self.authorized_request(path.clone()).await?;
+        parse_response(&path, &res)
     }
 
     pub async fn get_index(&self) -> Result<Index, ErrorKind> {
         let path = "/api/index".to_string();
-        let res = self.request(path).await?;
-        from_str(&res).map_err(|_| ErrorKind::ParsingError)
+        let res = self.authorized_request(path.clone()).await?;
+        parse_response(&path, &res)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+fn is_unauthorized_status(status: StatusCode) -> bool {
+    status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN
+}
+
+/// Ahoy answers requests to protected endpoints with an error instead of the data.
+fn is_protected_response(res: &str) -> bool {
+    from_str::<Value>(res)
+        .ok()
+        .and_then(|value| {
+            value
+                .get("error")?
+                .as_str()
+                .map(|e| e.contains("PROTECTED"))
+        })
+        .unwrap_or(false)
+}
+
+/// Fields a firmware reports in addition to the ones of the model.
+pub type Extra = BTreeMap<String, Value>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct InverterList {
     pub inverter: Vec<Inverter>,
+    #[serde(deserialize_with = "lenient::text")]
     pub interval: String,
+    #[serde(deserialize_with = "lenient::text")]
     pub retries: String,
+    #[serde(deserialize_with = "lenient::number")]
     pub max_num_inverters: u8,
-    #[serde(rename = "rstMid")]
+    #[serde(rename = "rstMid", deserialize_with = "lenient::boolean")]
     pub rst_mid: bool,
-    #[serde(rename = "rstNAvail")]
+    #[serde(rename = "rstNAvail", deserialize_with = "lenient::boolean")]
     pub rst_n_avail: bool,
-    #[serde(rename = "rstComStop")]
+    #[serde(rename = "rstComStop", deserialize_with = "lenient::boolean")]
     pub rst_com_stop: bool,
-    #[serde(rename = "strtWthtTm")]
+    #[serde(rename = "strtWthtTm", deserialize_with = "lenient::boolean")]
     pub strt_wtht_tm: bool,
-    #[serde(rename = "yldEff")]
-    pub yld_eff: u8,
+    #[serde(rename = "yldEff", deserialize_with = "lenient::number")]
+    pub yld_eff: f32,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Inverter {
+    #[serde(default, deserialize_with = "lenient::boolean")]
     pub enabled: bool,
+    #[serde(deserialize_with = "lenient::number")]
     pub id: u8,
+    #[serde(default, deserialize_with = "lenient::text")]
     pub name: String,
+    #[serde(deserialize_with = "lenient::serial")]
     pub serial: String,
+    #[serde(default, deserialize_with = "lenient::number")]
     pub channels: u8, // amount of channels -> used for naming in live
+    #[serde(default, deserialize_with = "lenient::text")]
     pub version: String,
-    pub ch_yield_cor: Vec<u8>,
+    #[serde(default, deserialize_with = "lenient::numbers")]
+    pub ch_yield_cor: Vec<f32>,
+    #[serde(default)]
     pub ch_name: Vec<String>,
+    #[serde(default, deserialize_with = "lenient::optional_numbers")]
     pub ch_max_pwr: Vec<Option<u16>>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct InverterStatus {
+    #[serde(deserialize_with = "lenient::number")]
     pub id: u8,
+    #[serde(default, deserialize_with = "lenient::boolean")]
     pub enabled: bool,
+    #[serde(default, deserialize_with = "lenient::text")]
     pub name: String,
+    #[serde(deserialize_with = "lenient::serial")]
     pub serial: String,
+    #[serde(default, deserialize_with = "lenient::text")]
     pub version: String,
-    pub power_limit_read: u16,
+    #[serde(default, deserialize_with = "lenient::number")]
+    pub power_limit_read: u32,
+    #[serde(default, deserialize_with = "lenient::boolean")]
     pub power_limit_ack: bool,
+    #[serde(default, deserialize_with = "lenient::number")]
     pub ts_last_success: u64,
+    #[serde(default, deserialize_with = "lenient::number")]
     pub generation: u32,
+    #[serde(default, deserialize_with = "lenient::number")]
     pub status: u8,
-    pub alarm_cnt: u8,
+    #[serde(default, deserialize_with = "lenient::number")]
+    pub alarm_cnt: u32,
+    #[serde(deserialize_with = "lenient::measurements")]
     pub ch: Vec<Vec<f32>>,
+    #[serde(default)]
     pub ch_name: Vec<String>,
+    #[serde(default, deserialize_with = "lenient::optional_numbers")]
     pub ch_max_pwr: Vec<Option<u16>>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl InverterStatus {
+    /// The value at `index` of `channel`, `None` if the DTU did not report it.
+    fn value(&self, channel: usize, index: usize) -> Option<f32> {
+        self.ch
+            .get(channel)
+            .and_then(|values| values.get(index))
+            .copied()
+            .filter(|value| !value.is_nan())
+    }
+
+    /// Combine the values of each channel with the field names and units of
+    /// `live`. The first entry is channel 0, followed by one entry per input.
+    /// Values that are not a number or missing (not reported by the DTU) are
+    /// left out.
+    pub fn fields(
+        &self,
+        live: &Live,
+        inverter_channel_count: usize,
+        selected_fields: Option<&[String]>,
+    ) -> Vec<HashMap<String, UnitValue<f32>>> {
+        let mut data = Vec::new();
+
+        // channel 0 is a special case, i assume it is the sum of all channels, maybe the values of the inverter itself
+        let mut channel_0 = HashMap::new();
+        for (index, fieldname) in live.ch0_fld_names.iter().enumerate() {
+            if let Some(selected_fields) = selected_fields {
+                if !selected_fields.contains(fieldname) {
+                    continue;
+                }
+            }
+            let Some(value) = self.value(0, index) else {
+                continue;
+            };
+            let unit = live.ch0_fld_units.get(index).cloned().unwrap_or_default();
+            channel_0.insert(fieldname.clone(), UnitValue::new(value, unit));
+        }
+        data.push(channel_0);
+
+        for channel in 1..=inverter_channel_count {
+            let mut channel_data = HashMap::new();
+            for (index, fieldname) in live.fld_names.iter().enumerate() {
+                if let Some(selected_fields) = selected_fields {
+                    if !selected_fields.contains(fieldname) {
+                        continue;
+                    }
+                }
+                let Some(value) = self.value(channel, index) else {
+                    continue;
+                };
+                let unit = live.fld_units.get(index).cloned().unwrap_or_default();
+                channel_data.insert(fieldname.clone(), UnitValue::new(value, unit));
+            }
+            data.push(channel_data);
+        }
+
+        data
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct Live {
     pub generic: Generic,
-    pub refresh: u8,
+    #[serde(deserialize_with = "lenient::number")]
+    pub refresh: u32,
     pub ch0_fld_units: Vec<String>,
     pub ch0_fld_names: Vec<String>,
     pub fld_units: Vec<String>,
     pub fld_names: Vec<String>,
     pub iv: Vec<bool>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct Generic {
-    pub wifi_rssi: i8,
+    #[serde(deserialize_with = "lenient::number")]
+    pub wifi_rssi: i32,
+    #[serde(deserialize_with = "lenient::number")]
     pub ts_uptime: u64,
+    #[serde(deserialize_with = "lenient::number")]
     pub ts_now: u64,
+    #[serde(deserialize_with = "lenient::text")]
     pub version: String,
+    #[serde(deserialize_with = "lenient::text")]
     pub build: String,
+    #[serde(deserialize_with = "lenient::boolean")]
     pub menu_prot: bool,
-    pub menu_mask: u8,
-    #[serde(rename = "menu_protEn")]
+    #[serde(deserialize_with = "lenient::number")]
+    pub menu_mask: u32,
+    #[serde(rename = "menu_protEn", deserialize_with = "lenient::boolean")]
     pub menu_prot_en: bool,
+    #[serde(deserialize_with = "lenient::text")]
     pub esp_type: String,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -231,29 +588,48 @@ impl UnitValue<f32> {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct Index {
     pub generic: Generic,
+    #[serde(deserialize_with = "lenient::number")]
     pub ts_now: u64,
+    #[serde(deserialize_with = "lenient::number")]
     pub ts_sunrise: u64,
+    #[serde(deserialize_with = "lenient::number")]
     pub ts_sunset: u64,
-    pub ts_offset: u64,
-    #[serde(rename = "disNightComm")]
+    /// seconds the DTU talks to the inverters before sunrise and after sunset,
+    /// negative if it starts after sunrise
+    #[serde(deserialize_with = "lenient::number")]
+    pub ts_offset: i64,
+    #[serde(rename = "disNightComm", deserialize_with = "lenient::boolean")]
     pub dis_night_comm: bool,
     pub inverter: Vec<InverterIndex>,
     pub warnings: Vec<String>,
     pub infos: Vec<String>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct InverterIndex {
+    #[serde(deserialize_with = "lenient::boolean")]
     pub enabled: bool,
+    #[serde(deserialize_with = "lenient::number")]
     pub id: u8,
+    #[serde(deserialize_with = "lenient::text")]
     pub name: String,
+    #[serde(deserialize_with = "lenient::text")]
     pub version: String,
+    #[serde(deserialize_with = "lenient::boolean")]
     pub is_avail: bool,
+    #[serde(deserialize_with = "lenient::boolean")]
     pub is_producing: bool,
+    #[serde(deserialize_with = "lenient::number")]
     pub ts_last_success: u64,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[cfg(test)]
@@ -267,12 +643,8 @@ mod test {
         static ref TEST_MUTEX: Arc<Mutex<()>> = Arc::new(Mutex::new(()));
     }
 
-    static IS_OFFLINE: bool = true;
     fn init() -> Result<AhoyApi, ErrorKind> {
-        dotenv::dotenv().ok();
-        let mut api = AhoyApi::from_env()?;
-        api.set_offline_mode(IS_OFFLINE);
-        Ok(api)
+        Ok(AhoyApi::offline())
     }
 
     #[tokio::test]
@@ -313,6 +685,44 @@ mod test {
         println!("{:#?}", res);
     }
 
+    #[tokio::test]
+    async fn send_commands() {
+        let _guard = TEST_MUTEX.lock().await;
+
+        let api = init().unwrap();
+        let res = api.set_power(0, true).await.unwrap();
+        assert_eq!(res, CommandOutcome::Accepted);
+
+        // the canned status never acknowledges the limit
+        let res = api
+            .set_power_limit(
+                0,
+                PowerLimit::Relative(50.0),
+                false,
+                Duration::from_millis(10),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res, CommandOutcome::NotAcknowledged);
+    }
+
+    #[tokio::test]
+    async fn login() {
+        let _guard = TEST_MUTEX.lock().await;
+
+        let api = init().unwrap().with_password("wrong".to_string());
+        assert!(matches!(
+            api.login().await,
+            Err(ErrorKind::AuthenticationFailed)
+        ));
+        assert!(!api.is_logged_in());
+
+        let api = api.with_password("secret".to_string());
+        api.login().await.unwrap();
+        assert!(api.is_logged_in());
+        assert!(is_protected_response("{\"error\":\"ERR_PROTECTED\"}"));
+    }
+
     #[tokio::test]
     async fn get_index() {
         let _guard = TEST_MUTEX.lock().await;
@@ -321,4 +731,191 @@ mod test {
         let res = api.get_index().await;
         println!("{:#?}", res);
     }
+
+    #[tokio::test]
+    async fn parsing_error_names_the_endpoint() {
+        let api = init().unwrap();
+        // the offline DTU answers unknown paths with an empty body
+        match api.get_inverter_status_by_id(5).await {
+            Err(ErrorKind::ParsingError { path, line, .. }) => {
+                assert_eq!(path, "/api/inverter/id/5");
+                assert_eq!(line, 1);
+            }
+            other => panic!("expected a parsing error, got {:?}", other),
+        }
+    }
+
+    /// A DTU that fails the first two requests and stalls on `/slow`.
+    async fn serve_flaky_dtu() -> std::net::SocketAddr {
+        use hyper::{
+            service::{make_service_fn, service_fn},
+            Body, Request, Response, Server,
+        };
+        use std::{
+            convert::Infallible,
+            sync::atomic::{AtomicUsize, Ordering},
+        };
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let make_service = make_service_fn(move |_| {
+            let requests = requests.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let requests = requests.clone();
+                    async move {
+                        if request.uri().path() == "/slow" {
+                            sleep(Duration::from_secs(5)).await;
+                        }
+                        let (status, body) = match requests.fetch_add(1, Ordering::SeqCst) {
+                            0 | 1 => (503, ""),
+                            _ => (200, "{\"inverter\":[],\"interval\":\"30\",\"retries\":\"5\",\"max_num_inverters\":4,\"rstMid\":false,\"rstNAvail\":false,\"rstComStop\":false,\"strtWthtTm\":false,\"yldEff\":1}"),
+                        };
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::from(body))
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        address
+    }
+
+    #[tokio::test]
+    async fn retry_and_timeout() {
+        let address = serve_flaky_dtu().await;
+        let api = AhoyApi::new(format!("http://{}", address))
+            .with_client(http_client(
+                Duration::from_millis(200),
+                Duration::from_millis(200),
+            ))
+            .with_retry_policy(RetryPolicy {
+                max_retries: 0,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(5),
+            });
+
+        match api.get_inverter_list().await {
+            Err(ErrorKind::HttpStatus { url, status }) => {
+                assert_eq!(url, format!("http://{}/api/inverter/list", address));
+                assert_eq!(status, 503);
+            }
+            other => panic!("expected a 503, got {:?}", other),
+        }
+
+        let api = api.with_retry_policy(RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        });
+        assert!(api.get_inverter_list().await.unwrap().inverter.is_empty());
+
+        let res = api.request("/slow".to_string()).await;
+        assert!(matches!(res, Err(ErrorKind::Timeout { .. })), "{:?}", res);
+    }
+
+    /// Responses of the firmware versions in `fixtures/ahoy`.
+    macro_rules! fixtures {
+        ($($version:literal),*) => {
+            [$((
+                $version,
+                include_str!(concat!("../../fixtures/ahoy/", $version, "/inverter_list.json")),
+                include_str!(concat!("../../fixtures/ahoy/", $version, "/inverter_id_0.json")),
+                include_str!(concat!("../../fixtures/ahoy/", $version, "/live.json")),
+                include_str!(concat!("../../fixtures/ahoy/", $version, "/index.json")),
+            )),*]
+        };
+    }
+
+    #[test]
+    fn firmware_fixtures() {
+        for (version, list, status, live, index) in fixtures!("0.7.36", "0.8.83") {
+            let list: InverterList = parse_response(version, list).unwrap();
+            let status: InverterStatus = parse_response(version, status).unwrap();
+            let live: Live = parse_response(version, live).unwrap();
+            let index: Index = parse_response(version, index).unwrap();
+
+            assert_eq!(live.generic.version, version);
+            assert_eq!(list.interval, "30");
+            assert_eq!(list.retries, "5");
+            assert_eq!(list.inverter[0].serial, status.serial);
+            let fields = status.fields(&live, list.inverter[0].channels as usize, None);
+            assert_eq!(fields.len(), 3);
+            assert!(fields[0].contains_key("YieldDay"));
+            assert!(!index.inverter.is_empty());
+        }
+
+        let [_, (_, list, status, live, index)] = fixtures!("0.7.36", "0.8.83");
+        let list: InverterList = serde_json::from_str(list).unwrap();
+        assert_eq!(list.yld_eff, 0.0);
+        assert_eq!(list.inverter[1].serial, "116183771004");
+        assert_eq!(list.inverter[0].ch_yield_cor, vec![0.0, 0.5]);
+        assert_eq!(list.inverter[1].ch_max_pwr[2], None);
+        assert_eq!(list.extra["rdGrid"], Value::Bool(true));
+        assert_eq!(list.inverter[0].extra["freq"], 12);
+
+        let status: InverterStatus = serde_json::from_str(status).unwrap();
+        assert_eq!(status.power_limit_read, 100000);
+        assert_eq!(status.ts_last_success, 1717236000);
+        assert_eq!(status.extra["rssi"], -62);
+        let live: Live = serde_json::from_str(live).unwrap();
+        assert_eq!(live.generic.wifi_rssi, -71);
+        assert!(!live.generic.menu_prot_en);
+        assert_eq!(live.generic.extra["host"], "AHOY-DTU");
+        // the string is parsed, the null and the missing values are left out
+        let fields = status.fields(&live, 2, None);
+        assert_eq!(fields[1]["YieldDay"].value, 842.0);
+        assert_eq!(fields[2].len(), 2);
+
+        let index: Index = serde_json::from_str(index).unwrap();
+        assert_eq!(index.ts_offset, -600);
+        assert_eq!(index.inverter[1].ts_last_success, 0);
+        assert_eq!(index.inverter[0].extra["cur_pwr"], 258.3);
+        assert_eq!(index.extra["pwrLimitAck"], Value::Bool(true));
+    }
+
+    #[test]
+    fn lenient_values() {
+        let status: InverterStatus = serde_json::from_str(
+            r#"{"id":"2","serial":116183771002,"enabled":"true","ch":[[1,"x"],"broken"]}"#,
+        )
+        .unwrap();
+        assert_eq!(status.serial, "116183771002");
+        assert_eq!(status.id, 2);
+        assert!(status.enabled);
+        assert!(status.ch[0][1].is_nan());
+        assert!(status.ch[1].is_empty());
+
+        // a value of the wrong kind still fails, with the position of the field
+        let res = parse_response::<InverterStatus>("/api/inverter/id/2", r#"{"id":"two"}"#);
+        assert!(
+            matches!(res, Err(ErrorKind::ParsingError { .. })),
+            "{:?}",
+            res
+        );
+        // error bodies and inverters without a serial are no inverters
+        for body in [
+            "{}",
+            r#"{"error":"ERR_PROTECTED"}"#,
+            r#"{"id":0,"serial":"","ch":[]}"#,
+        ] {
+            assert!(
+                serde_json::from_str::<InverterStatus>(body).is_err(),
+                "{}",
+                body
+            );
+            assert!(serde_json::from_str::<Inverter>(body).is_err(), "{}", body);
+        }
+        let res = parse_response::<Index>("/api/index", r#"{"disNightComm":"maybe"}"#);
+        assert!(
+            matches!(res, Err(ErrorKind::ParsingError { .. })),
+            "{:?}",
+            res
+        );
+    }
 }
@@ -0,0 +1,114 @@
+use crate::{create_file_with_full_path, ErrorKind};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+/// The path of the lines that record the timestamp of a crawling cycle.
+pub const CYCLE_MARKER: &str = "cycle";
+
+/// One request to a DTU and what it answered, a line of a capture file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedResponse {
+    /// when the request was sent
+    pub timestamp: DateTime<Local>,
+    /// the requested path, or [`CYCLE_MARKER`] for the start of a crawling cycle
+    pub path: String,
+    /// the raw body of a successful request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// the error of a failed request, after all retries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorKind>,
+}
+
+/// Appends every response of a DTU to a file with one json object per line,
+/// so a session can be replayed with a [`ReplayDtu`](crate::ReplayDtu).
+#[derive(Debug, Clone)]
+pub struct Capture {
+    path: String,
+    // shared between clones of the api, so lines of concurrent requests do not mix
+    file: Arc<Mutex<File>>,
+}
+
+impl Capture {
+    /// Append to the capture file at `path`, creating it and its folder if needed.
+    pub fn create(path: &str) -> Result<Self, ErrorKind> {
+        Ok(Self {
+            path: path.to_string(),
+            file: Arc::new(Mutex::new(create_file_with_full_path(
+                path.to_string(),
+                true,
+                true,
+            )?)),
+        })
+    }
+
+    /// Write the `result` of requesting `path` at `timestamp`. A capture that
+    /// cannot be written must not stop the crawler, so failures are logged.
+    pub fn record(
+        &self,
+        path: &str,
+        timestamp: DateTime<Local>,
+        result: &Result<String, ErrorKind>,
+    ) {
+        let response = CapturedResponse {
+            timestamp,
+            path: path.to_string(),
+            body: result.as_ref().ok().cloned(),
+            error: result.as_ref().err().cloned(),
+        };
+        self.write(&response);
+    }
+
+    /// Write the `timestamp` a crawling cycle was started with, so a replay
+    /// stores its rows with the same time.
+    pub fn record_cycle(&self, timestamp: DateTime<Local>) {
+        self.write(&CapturedResponse {
+            timestamp,
+            path: CYCLE_MARKER.to_string(),
+            body: None,
+            error: None,
+        });
+    }
+
+    fn write(&self, response: &CapturedResponse) {
+        let line = match serde_json::to_string(response) {
+            Ok(line) => line + "\n",
+            Err(e) => {
+                return log::error!(
+                    "Could not serialize the response of {}: {}",
+                    response.path,
+                    e
+                )
+            }
+        };
+        let mut file = self
+            .file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            log::error!("Could not write to capture {}: {}", self.path, e);
+        }
+    }
+}
+
+/// Read all responses of the capture file at `path`, in the order they were captured.
+pub fn read_capture(path: &str) -> Result<Vec<CapturedResponse>, ErrorKind> {
+    let content =
+        fs::read_to_string(path).map_err(|_| ErrorKind::CouldNotOpenFile(path.to_string()))?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .map_err(|err| ErrorKind::parsing(&format!("{}:{}", path, index + 1), line, err))
+        })
+        .collect()
+}
@@ -0,0 +1,447 @@
+use crate::{
+    report::render_table, Config, Crawler, DtuConfig, DtuEndpoint, ErrorKind, Index, OutputFormat,
+};
+
+use chrono::{Local, TimeZone};
+use futures::future::join_all;
+use serde_json::{json, Map, Value};
+
+pub const USAGE: &str = "\
+usage: ahoy-dtu-stats [command] [options]
+
+commands:
+  crawl          crawl the DTUs until stopped, the default
+  once           crawl every inverter once and print its values
+  list           list the inverters of the DTUs
+  status         print the state of the DTUs with their warnings and infos
+  report         summarize the stored rows, [--dir <path>] [--period day|month|year]
+  export         convert the stored csv files to parquet, --out <path> [--dir <path>]
+  config check   validate the configuration
+  simulate       store the rows of a simulated plant, [--from <date>] [--to <date>]
+                 [--out <path>], which defaults to ./simulated
+
+options:
+  --config <path>    the configuration file, defaults to CONFIG_FILE or ahoy.toml
+  --endpoint <url>   talk to the DTU at <url> instead of the configured ones
+  --format <format>  table or json, report also supports csv
+";
+
+/// The options of the commands that talk to the DTUs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CliOptions {
+    /// the configuration file, see [`Config::load`]
+    pub config: Option<String>,
+    /// replaces the configured DTUs, see [`DtuConfig::use_endpoint`]
+    pub endpoint: Option<String>,
+    pub format: OutputFormat,
+}
+
+impl CliOptions {
+    /// Parse `--config <path>`, `--endpoint <url>` and, if `with_format`,
+    /// `--format table|json`.
+    pub fn parse(args: &[String], with_format: bool) -> Result<Self, ErrorKind> {
+        let mut options = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or(ErrorKind::InvalidArgument(format!("{} needs a value", arg)))
+            };
+            match arg.as_str() {
+                "--config" => options.config = Some(value()?.clone()),
+                "--endpoint" => options.endpoint = Some(value()?.clone()),
+                "--format" if with_format => {
+                    options.format = match value()?.parse()? {
+                        OutputFormat::Csv => {
+                            return Err(ErrorKind::InvalidArgument(
+                                "csv is only supported by report, expected table or json"
+                                    .to_string(),
+                            ))
+                        }
+                        format => format,
+                    }
+                }
+                _ => {
+                    return Err(ErrorKind::InvalidArgument(format!(
+                        "unknown argument '{}'",
+                        arg
+                    )))
+                }
+            }
+        }
+        Ok(options)
+    }
+
+    /// The validated configuration of `--config`, overridden by the
+    /// environment and `--endpoint`.
+    pub fn load_config(&self) -> Result<Config, ErrorKind> {
+        let mut config = Config::load(self.config.as_deref())?;
+        if let Some(endpoint) = &self.endpoint {
+            DtuConfig::use_endpoint(&mut config.dtus, endpoint.clone());
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// `dir`, or the configured `out_dir` of the configuration file `config`.
+pub(crate) fn out_dir(dir: Option<String>, config: Option<&str>) -> Result<String, ErrorKind> {
+    match dir {
+        Some(dir) => Ok(dir),
+        None => Ok(Config::load(config)?.out_dir),
+    }
+}
+
+fn to_json(value: &impl serde::Serialize) -> Result<String, ErrorKind> {
+    serde_json::to_string_pretty(value)
+        .map(|json| json + "\n")
+        .map_err(|err| ErrorKind::InvalidArgument(err.to_string()))
+}
+
+/// A unix timestamp of the DTU as local time, empty if the DTU reports none.
+fn format_timestamp(timestamp: u64) -> String {
+    match timestamp {
+        0 => String::new(),
+        timestamp => Local
+            .timestamp_opt(timestamp as i64, 0)
+            .single()
+            .map(|time| time.format("%F %T").to_string())
+            .unwrap_or_default(),
+    }
+}
+
+fn format_uptime(seconds: u64) -> String {
+    format!(
+        "{}d {:02}:{:02}:{:02}",
+        seconds / 86400,
+        seconds % 86400 / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// The inverters of all `dtus` with their serial, channels and firmware version.
+pub async fn list_inverters(
+    dtus: &[DtuEndpoint],
+    format: OutputFormat,
+) -> Result<String, ErrorKind> {
+    let lists = join_all(dtus.iter().map(|dtu| dtu.api.get_inverter_list())).await;
+    let mut inverters = Vec::new();
+    for (dtu, list) in dtus.iter().zip(lists) {
+        inverters.extend(
+            list?
+                .inverter
+                .into_iter()
+                .map(|inverter| (&dtu.name, inverter)),
+        );
+    }
+
+    match format {
+        OutputFormat::Json => to_json(
+            &inverters
+                .iter()
+                .map(|(dtu, inverter)| {
+                    json!({
+                        "dtu": dtu,
+                        "id": inverter.id,
+                        "name": inverter.name,
+                        "serial": inverter.serial,
+                        "channels": inverter.channels,
+                        "version": inverter.version,
+                        "enabled": inverter.enabled,
+                        "ch_name": inverter.ch_name,
+                        "ch_max_pwr": inverter.ch_max_pwr,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        ),
+        _ => {
+            let header = [
+                "dtu", "id", "name", "serial", "channels", "version", "enabled",
+            ];
+            let mut table = vec![header.iter().map(|column| column.to_string()).collect()];
+            for (dtu, inverter) in &inverters {
+                table.push(vec![
+                    dtu.to_string(),
+                    inverter.id.to_string(),
+                    inverter.name.clone(),
+                    inverter.serial.clone(),
+                    inverter.channels.to_string(),
+                    inverter.version.clone(),
+                    inverter.enabled.to_string(),
+                ]);
+            }
+            Ok(render_table(&table))
+        }
+    }
+}
+
+fn status_table(dtu: &str, index: &Index) -> Vec<Vec<String>> {
+    let generic = &index.generic;
+    let row = |key: &str, value: String| vec![key.to_string(), value];
+    let mut table = vec![
+        row("dtu", dtu.to_string()),
+        row(
+            "version",
+            format!(
+                "{} ({}, {})",
+                generic.version, generic.build, generic.esp_type
+            ),
+        ),
+        row("wifi", format!("{} dBm", generic.wifi_rssi)),
+        row("uptime", format_uptime(generic.ts_uptime)),
+        row("time", format_timestamp(index.ts_now)),
+        row("sunrise", format_timestamp(index.ts_sunrise)),
+        row("sunset", format_timestamp(index.ts_sunset)),
+        row(
+            "night communication",
+            match index.dis_night_comm {
+                true => "disabled".to_string(),
+                false => "enabled".to_string(),
+            },
+        ),
+    ];
+    for inverter in &index.inverter {
+        let state = match (inverter.enabled, inverter.is_avail, inverter.is_producing) {
+            (false, _, _) => "disabled",
+            (true, false, _) => "unavailable",
+            (true, true, false) => "available",
+            (true, true, true) => "producing",
+        };
+        table.push(row(
+            &format!("inverter {}", inverter.id),
+            format!(
+                "{}: {}, last heard {}",
+                inverter.name,
+                state,
+                format_timestamp(inverter.ts_last_success)
+            ),
+        ));
+    }
+    table.extend(
+        index
+            .warnings
+            .iter()
+            .map(|warning| row("warning", warning.clone())),
+    );
+    table.extend(index.infos.iter().map(|info| row("info", info.clone())));
+    table
+}
+
+/// The state of all `dtus`: firmware, uptime, sunrise and sunset, the
+/// inverters and the warnings and infos the DTU shows on its start page.
+pub async fn dtu_status(dtus: &[DtuEndpoint], format: OutputFormat) -> Result<String, ErrorKind> {
+    let indices = join_all(dtus.iter().map(|dtu| dtu.api.get_index())).await;
+    let mut statuses = Vec::new();
+    for (dtu, index) in dtus.iter().zip(indices) {
+        statuses.push((&dtu.name, index?));
+    }
+
+    match format {
+        OutputFormat::Json => to_json(
+            &statuses
+                .iter()
+                .map(|(dtu, index)| json!({ "dtu": dtu, "index": index }))
+                .collect::<Vec<_>>(),
+        ),
+        _ => Ok(statuses
+            .iter()
+            .map(|(dtu, index)| render_table(&status_table(dtu, index)))
+            .collect::<Vec<_>>()
+            .join("\n")),
+    }
+}
+
+/// Crawl every inverter of `dtus` once with the settings of `config`, e.g.
+/// its selected fields, without storing or publishing the values.
+pub async fn snapshot(config: &Config, dtus: Vec<DtuEndpoint>) -> Result<Crawler, ErrorKind> {
+    let mut crawler = Crawler::from_dtus(dtus)
+        .with_settings(config.crawler)
+        .with_overrides(config.inverters.clone());
+    crawler.init().await?;
+    crawler.crawl_all_due_inverters(false).await?;
+    Ok(crawler)
+}
+
+/// The latest values of all inverters of `crawler`, sorted by inverter.
+pub fn render_snapshot(crawler: &Crawler, format: OutputFormat) -> Result<String, ErrorKind> {
+    let mut inverters: Vec<_> = crawler.inverters.values().collect();
+    inverters.sort_by_key(|inverter| inverter.key());
+
+    match format {
+        OutputFormat::Json => to_json(
+            &inverters
+                .iter()
+                .map(|inverter| {
+                    let mut channels = Map::new();
+                    for (channel, dataset) in inverter.datasets() {
+                        let Some(row) = dataset.rows().last() else {
+                            continue;
+                        };
+                        let values: Map<String, Value> = dataset
+                            .fields()
+                            .iter()
+                            .zip(&row.values)
+                            .map(|(field, value)| {
+                                (
+                                    field.name.clone(),
+                                    json!({ "value": value, "unit": field.unit }),
+                                )
+                            })
+                            .collect();
+                        channels.insert(channel.to_string(), Value::Object(values));
+                    }
+                    let measured_at = inverter
+                        .summary_dataset
+                        .rows()
+                        .last()
+                        .and_then(|row| row.measured_at);
+                    json!({
+                        "dtu": inverter.dtu,
+                        "id": inverter.id,
+                        "name": inverter.name,
+                        "serial": inverter.inverter().serial,
+                        "crawled_at": inverter.crawled_at,
+                        "measured_at": measured_at,
+                        "channels": channels,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        ),
+        _ => {
+            let header = ["inverter", "name", "channel", "field", "value", "unit"];
+            let mut table = vec![header.iter().map(|column| column.to_string()).collect()];
+            for inverter in inverters {
+                for (channel, dataset) in inverter.datasets() {
+                    let Some(row) = dataset.rows().last() else {
+                        continue;
+                    };
+                    for (field, value) in dataset.fields().iter().zip(&row.values) {
+                        table.push(vec![
+                            inverter.key().to_string(),
+                            inverter.name.clone(),
+                            channel.to_string(),
+                            field.name.clone(),
+                            value.map(|value| value.to_string()).unwrap_or_default(),
+                            field.unit.clone(),
+                        ]);
+                    }
+                }
+            }
+            Ok(render_table(&table))
+        }
+    }
+}
+
+/// Run the `once` subcommand: `once [--config <path>] [--endpoint <url>]
+/// [--format table|json]`.
+pub async fn once_entrypoint(args: &[String]) -> Result<(), ErrorKind> {
+    let options = CliOptions::parse(args, true)?;
+    let config = options.load_config()?;
+    let crawler = snapshot(&config, config.connect_dtus()?).await?;
+    print!("{}", render_snapshot(&crawler, options.format)?);
+    Ok(())
+}
+
+/// Run the `list` subcommand: `list [--config <path>] [--endpoint <url>]
+/// [--format table|json]`.
+pub async fn list_entrypoint(args: &[String]) -> Result<(), ErrorKind> {
+    let options = CliOptions::parse(args, true)?;
+    let dtus = options.load_config()?.connect_dtus()?;
+    print!("{}", list_inverters(&dtus, options.format).await?);
+    Ok(())
+}
+
+/// Run the `status` subcommand: `status [--config <path>] [--endpoint <url>]
+/// [--format table|json]`.
+pub async fn status_entrypoint(args: &[String]) -> Result<(), ErrorKind> {
+    let options = CliOptions::parse(args, true)?;
+    let dtus = options.load_config()?.connect_dtus()?;
+    print!("{}", dtu_status(&dtus, options.format).await?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::api::test_util::mock_dtu;
+
+    use std::sync::Arc;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// The mock DTU as `roof`.
+    fn mock_dtus(inverters: usize) -> Vec<DtuEndpoint> {
+        let (_, api) = mock_dtu(inverters, 2);
+        vec![DtuEndpoint::new("roof".to_string(), Arc::new(api))]
+    }
+
+    #[test]
+    fn options() {
+        let options = CliOptions::parse(
+            &args(&["--endpoint", "http://dtu", "--format", "json"]),
+            true,
+        )
+        .unwrap();
+        assert_eq!(options.endpoint.as_deref(), Some("http://dtu"));
+        assert_eq!(options.format, OutputFormat::Json);
+        assert!(CliOptions::parse(&args(&["--format", "json"]), false).is_err());
+        assert!(CliOptions::parse(&args(&["--format", "csv"]), true).is_err());
+        assert!(CliOptions::parse(&args(&["--config"]), true).is_err());
+
+        assert_eq!(out_dir(Some("/data".to_string()), None).unwrap(), "/data");
+    }
+
+    #[tokio::test]
+    async fn list_and_status() {
+        let dtus = mock_dtus(2);
+
+        let table = list_inverters(&dtus, OutputFormat::Table).await.unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("dtu   id  name"));
+        assert!(lines[2].contains("116183771001"), "{}", table);
+        let json: Value =
+            serde_json::from_str(&list_inverters(&dtus, OutputFormat::Json).await.unwrap())
+                .unwrap();
+        assert_eq!(json[1]["serial"], "116183771001");
+        assert_eq!(json[1]["channels"], 2);
+
+        let table = dtu_status(&dtus, OutputFormat::Table).await.unwrap();
+        assert!(table.starts_with("dtu "), "{}", table);
+        assert!(table.contains("inverter 1"), "{}", table);
+        let json: Value =
+            serde_json::from_str(&dtu_status(&dtus, OutputFormat::Json).await.unwrap()).unwrap();
+        assert_eq!(json[0]["dtu"], "roof");
+        assert!(json[0]["index"]["ts_sunrise"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn once() {
+        let config = Config::parse(
+            r#"
+            [[inverter]]
+            serial = "116183771000"
+            fields = ["P_AC", "U_DC"]
+            "#,
+        )
+        .unwrap();
+        let crawler = snapshot(&config, mock_dtus(1)).await.unwrap();
+
+        let table = render_snapshot(&crawler, OutputFormat::Table).unwrap();
+        // the summary and both inputs with one selected field each
+        assert_eq!(table.lines().count(), 4, "{}", table);
+        assert!(table
+            .lines()
+            .nth(1)
+            .unwrap()
+            .starts_with("roof/116183771000"));
+        let json: Value =
+            serde_json::from_str(&render_snapshot(&crawler, OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(json[0]["channels"]["summary"]["P_AC"]["unit"], "W");
+        assert!(json[0]["channels"]["1"]["U_DC"]["value"].as_f64().unwrap() > 0.0);
+    }
+}
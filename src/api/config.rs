@@ -0,0 +1,638 @@
+use crate::{
+    dtu::name_problem, Crawler, DtuConfig, DtuEndpoint, ErrorKind, HttpConfig, InfluxConfig,
+    InfluxSink, Inverter, MqttConfig, MqttSink, SchedulePolicy, StorageConfig, DEFAULT_CONCURRENCY,
+};
+
+use serde::{Deserialize, Deserializer};
+
+use std::{env, fmt::Display, fs, net::SocketAddr, path::Path, str::FromStr, time::Duration};
+
+/// Where rows and journals are written unless `out_dir` is configured.
+pub const DEFAULT_OUT_DIR: &str = "./out";
+
+/// The configuration file read if neither `--config` nor `CONFIG_FILE` is given.
+pub const DEFAULT_CONFIG_FILE: &str = "ahoy.toml";
+
+/// The value of the environment variable `name`, `None` if it is not set. A
+/// value that does not parse is an error naming the variable.
+pub(crate) fn env_var<T: FromStr>(name: &str) -> Result<Option<T>, ErrorKind>
+where
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|err| ErrorKind::InvalidConfig(format!("{}={:?}: {}", name, value, err))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Replace `field` with the value of the environment variable `name` if it is set.
+pub(crate) fn override_from_env<T: FromStr>(field: &mut T, name: &str) -> Result<(), ErrorKind>
+where
+    T::Err: Display,
+{
+    if let Some(value) = env_var(name)? {
+        *field = value;
+    }
+    Ok(())
+}
+
+/// Deserialize a string with the [`FromStr`] implementation of `T`, so the
+/// configuration file accepts the same values as the environment.
+pub(crate) fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+/// When inverters are crawled, the `[crawler]` section of the configuration file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CrawlerConfig {
+    /// seconds between crawls during the day
+    pub interval: u64,
+    /// inverters crawled at the same time
+    pub concurrency: usize,
+    /// seconds between crawls at night
+    pub night_interval: u64,
+    /// do not crawl at night at all
+    pub pause_at_night: bool,
+    /// seconds before sunrise to resume crawling
+    pub sunrise_lead: u64,
+    /// crawl less at night, `false` crawls every `interval` regardless of the time of day
+    pub adaptive: bool,
+    /// store rows of inverters that did not report since the last crawl
+    pub keep_stale_rows: bool,
+}
+
+impl Default for CrawlerConfig {
+    fn default() -> Self {
+        let schedule = SchedulePolicy::new(Duration::from_secs(60));
+        Self {
+            interval: schedule.day_interval.as_secs(),
+            concurrency: DEFAULT_CONCURRENCY,
+            night_interval: schedule.night_interval.unwrap_or_default().as_secs(),
+            pause_at_night: schedule.night_interval.is_none(),
+            sunrise_lead: schedule.sunrise_lead.as_secs(),
+            adaptive: !schedule.fixed,
+            keep_stale_rows: false,
+        }
+    }
+}
+
+impl CrawlerConfig {
+    /// Override the settings with `CRAWLING_INTERVAL`, `CRAWL_CONCURRENCY`,
+    /// `NIGHT_CRAWLING_INTERVAL` (seconds or `pause`), `SUNRISE_LEAD`,
+    /// `ADAPTIVE_SCHEDULING` and `STALE_ROWS` (`keep` or `skip`).
+    pub fn apply_env(&mut self) -> Result<(), ErrorKind> {
+        override_from_env(&mut self.interval, "CRAWLING_INTERVAL")?;
+        override_from_env(&mut self.concurrency, "CRAWL_CONCURRENCY")?;
+        match env_var::<String>("NIGHT_CRAWLING_INTERVAL")?.as_deref() {
+            Some("pause") => self.pause_at_night = true,
+            Some(_) => {
+                override_from_env(&mut self.night_interval, "NIGHT_CRAWLING_INTERVAL")?;
+                self.pause_at_night = false;
+            }
+            None => {}
+        }
+        override_from_env(&mut self.sunrise_lead, "SUNRISE_LEAD")?;
+        override_from_env(&mut self.adaptive, "ADAPTIVE_SCHEDULING")?;
+        match env_var::<String>("STALE_ROWS")?.as_deref() {
+            Some("keep") => self.keep_stale_rows = true,
+            Some("skip") => self.keep_stale_rows = false,
+            Some(value) => {
+                return Err(ErrorKind::InvalidConfig(format!(
+                    "STALE_ROWS={:?}: expected keep or skip",
+                    value
+                )))
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// The schedule of an inverter crawled every `interval` during the day.
+    pub fn schedule(&self, interval: Duration) -> SchedulePolicy {
+        SchedulePolicy {
+            day_interval: interval,
+            night_interval: (!self.pause_at_night)
+                .then(|| Duration::from_secs(self.night_interval)),
+            sunrise_lead: Duration::from_secs(self.sunrise_lead),
+            fixed: !self.adaptive,
+        }
+    }
+
+    /// What is wrong with the settings, see [`Config::problems`].
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.interval == 0 {
+            problems.push("crawler.interval must be at least 1 second".to_string());
+        }
+        if self.concurrency == 0 {
+            problems.push("crawler.concurrency must be at least 1".to_string());
+        }
+        if self.night_interval == 0 && !self.pause_at_night {
+            problems.push(
+                "crawler.night_interval must be at least 1 second, set pause_at_night to stop crawling at night"
+                    .to_string(),
+            );
+        }
+        problems
+    }
+}
+
+/// Settings of the inverters matching `serial` and `name`, an `[[inverter]]`
+/// section of the configuration file.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InverterConfig {
+    pub serial: Option<String>,
+    pub name: Option<String>,
+    /// only match inverters of the DTU with this name
+    pub dtu: Option<String>,
+    /// seconds between crawls during the day, instead of `crawler.interval`
+    pub interval: Option<u64>,
+    /// the fields stored and published, e.g. `["P_AC", "YieldDay"]`
+    pub fields: Option<Vec<String>>,
+    /// the sinks (`mqtt`, `influx`) the inverter is published to
+    pub sinks: Option<Vec<String>>,
+}
+
+impl InverterConfig {
+    /// Whether the settings apply to `inverter` of the DTU named `dtu`. An
+    /// override without serial and name matches no inverter.
+    pub fn matches(&self, dtu: &str, inverter: &Inverter) -> bool {
+        (self.serial.is_some() || self.name.is_some())
+            && self.dtu.iter().all(|name| name == dtu)
+            && self.serial.iter().all(|serial| serial == &inverter.serial)
+            && self.name.iter().all(|name| name == &inverter.name)
+    }
+
+    /// How the override is referred to in messages.
+    fn label(&self, index: usize) -> String {
+        match (&self.serial, &self.name) {
+            (Some(serial), _) => format!("inverter {}", serial),
+            (None, Some(name)) => format!("inverter {:?}", name),
+            (None, None) => format!("inverter #{}", index + 1),
+        }
+    }
+}
+
+/// Everything the crawler is configured with, read from a TOML file and
+/// overridden by the environment, see [`Config::load`].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// where rows and journals are written, in a folder per named DTU
+    pub out_dir: String,
+    /// `stdout` or the path of a log file
+    pub logging_target: Option<String>,
+    /// serve prometheus metrics on `/metrics` at this address
+    pub metrics_address: Option<SocketAddr>,
+    pub crawler: CrawlerConfig,
+    pub http: HttpConfig,
+    #[serde(rename = "dtu")]
+    pub dtus: Vec<DtuConfig>,
+    pub storage: StorageConfig,
+    pub mqtt: Option<MqttConfig>,
+    pub influx: Option<InfluxConfig>,
+    #[serde(rename = "inverter")]
+    pub inverters: Vec<InverterConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            out_dir: DEFAULT_OUT_DIR.to_string(),
+            logging_target: None,
+            metrics_address: None,
+            crawler: CrawlerConfig::default(),
+            http: HttpConfig::default(),
+            dtus: Vec::new(),
+            storage: StorageConfig::default(),
+            mqtt: None,
+            influx: None,
+            inverters: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Parse the content of a configuration file, without applying the environment.
+    pub fn parse(content: &str) -> Result<Self, ErrorKind> {
+        toml::from_str(content).map_err(|err| ErrorKind::InvalidConfig(err.to_string()))
+    }
+
+    /// Read the configuration file at `path`, without applying the environment.
+    pub fn from_file(path: &str) -> Result<Self, ErrorKind> {
+        let content =
+            fs::read_to_string(path).map_err(|_| ErrorKind::CouldNotOpenFile(path.to_string()))?;
+        toml::from_str(&content)
+            .map_err(|err| ErrorKind::InvalidConfig(format!("{}: {}", path, err)))
+    }
+
+    /// Read the configuration file at `path`, `CONFIG_FILE` or `ahoy.toml` if
+    /// it exists, and override it with the environment, see [`Config::apply_env`].
+    /// Without a file, the configuration is read from the environment alone.
+    pub fn load(path: Option<&str>) -> Result<Self, ErrorKind> {
+        let path = match path {
+            Some(path) => Some(path.to_string()),
+            None => env_var("CONFIG_FILE")?.or_else(|| {
+                Path::new(DEFAULT_CONFIG_FILE)
+                    .exists()
+                    .then(|| DEFAULT_CONFIG_FILE.to_string())
+            }),
+        };
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// Override the settings with `OUT_DIR`, `LOGGING_TARGET`,
+    /// `METRICS_ADDRESS` and the variables of every section.
+    pub fn apply_env(&mut self) -> Result<(), ErrorKind> {
+        override_from_env(&mut self.out_dir, "OUT_DIR")?;
+        if let Some(target) = env_var("LOGGING_TARGET")? {
+            self.logging_target = Some(target);
+        }
+        if let Some(address) = env_var("METRICS_ADDRESS")? {
+            self.metrics_address = Some(address);
+        }
+        self.crawler.apply_env()?;
+        self.http.apply_env()?;
+        DtuConfig::apply_env(&mut self.dtus)?;
+        self.storage.apply_env()?;
+        self.mqtt = MqttConfig::apply_env(self.mqtt.take())?;
+        self.influx = InfluxConfig::apply_env(self.influx.take())?;
+        Ok(())
+    }
+
+    /// The names of the configured sinks, see [`Sink::name`](crate::Sink::name).
+    fn sink_names(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.mqtt.is_some() {
+            names.push("mqtt");
+        }
+        if self.influx.is_some() {
+            names.push("influx");
+        }
+        names
+    }
+
+    /// Everything that is wrong with the configuration, one sentence each.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.out_dir.is_empty() {
+            problems.push("out_dir must not be empty".to_string());
+        }
+
+        if self.dtus.is_empty() {
+            problems.push(
+                "no DTU configured, add a [[dtu]] section or set INVERTER_ENDPOINT or DTU_ENDPOINTS"
+                    .to_string(),
+            );
+        }
+        for (index, dtu) in self.dtus.iter().enumerate() {
+            let label = match dtu.name.is_empty() {
+                true => format!("dtu #{}", index + 1),
+                false => format!("dtu {:?}", dtu.name),
+            };
+            if self.dtus.len() > 1 || !dtu.name.is_empty() {
+                if let Some(problem) = name_problem(&dtu.name) {
+                    problems.push(format!("{}: {}", label, problem));
+                }
+            }
+            if self.dtus[..index]
+                .iter()
+                .any(|other| other.name == dtu.name)
+            {
+                problems.push(format!(
+                    "{}: the name is used by another DTU, names must be unique",
+                    label
+                ));
+            }
+            if dtu.endpoint.is_empty() {
+                problems.push(format!("{}: endpoint must be set", label));
+            }
+            if dtu.replay_speed.is_nan() || dtu.replay_speed <= 0.0 {
+                problems.push(format!(
+                    "{}: replay_speed must be positive, got {}",
+                    label, dtu.replay_speed
+                ));
+            }
+        }
+
+        let sinks = self.sink_names();
+        for (index, inverter) in self.inverters.iter().enumerate() {
+            let label = inverter.label(index);
+            if inverter.serial.is_none() && inverter.name.is_none() {
+                problems.push(format!(
+                    "{}: set serial or name to select the inverters it applies to",
+                    label
+                ));
+            }
+            if let Some(dtu) = &inverter.dtu {
+                if !self.dtus.iter().any(|other| &other.name == dtu) {
+                    problems.push(format!(
+                        "{}: no DTU named {:?}, configured are {:?}",
+                        label,
+                        dtu,
+                        self.dtus.iter().map(|dtu| &dtu.name).collect::<Vec<_>>()
+                    ));
+                }
+            }
+            if inverter.interval == Some(0) {
+                problems.push(format!("{}: interval must be at least 1 second", label));
+            }
+            if inverter.fields.as_ref().is_some_and(Vec::is_empty) {
+                problems.push(format!(
+                    "{}: fields is empty, remove it to store all fields",
+                    label
+                ));
+            }
+            for sink in inverter.sinks.iter().flatten() {
+                if !sinks.contains(&sink.as_str()) {
+                    problems.push(format!(
+                        "{}: sink {:?} is not configured, configured are {:?}",
+                        label, sink, sinks
+                    ));
+                }
+            }
+            let selects_same = |other: &InverterConfig| {
+                (&other.serial, &other.name, &other.dtu)
+                    == (&inverter.serial, &inverter.name, &inverter.dtu)
+            };
+            if self.inverters[..index].iter().any(selects_same) {
+                problems.push(format!(
+                    "{}: configured twice, only the first [[inverter]] section applies",
+                    label
+                ));
+            }
+        }
+
+        problems.extend(self.crawler.problems());
+        problems.extend(self.http.problems());
+        problems.extend(self.storage.problems());
+        problems.extend(self.mqtt.iter().flat_map(MqttConfig::problems));
+        problems.extend(self.influx.iter().flat_map(InfluxConfig::problems));
+        problems
+    }
+
+    /// Fail with all problems of the configuration, if there are any.
+    pub fn validate(&self) -> Result<(), ErrorKind> {
+        match self.problems() {
+            problems if problems.is_empty() => Ok(()),
+            problems => Err(ErrorKind::InvalidConfig(problems.join("\n"))),
+        }
+    }
+
+    /// Create the backends of all configured DTUs.
+    pub fn connect_dtus(&self) -> Result<Vec<DtuEndpoint>, ErrorKind> {
+        self.dtus
+            .iter()
+            .map(|dtu| Ok(DtuEndpoint::new(dtu.name.clone(), dtu.connect(&self.http)?)))
+            .collect()
+    }
+
+    /// A crawler of `dtus` with the configured settings, storage and sinks.
+    /// Connects to the mqtt broker, so it must be called within the runtime.
+    pub fn crawler(&self, dtus: Vec<DtuEndpoint>) -> Result<Crawler, ErrorKind> {
+        let mut crawler = Crawler::from_dtus(dtus)
+            .with_journal(self.out_dir.clone())
+            .with_settings(self.crawler)
+            .with_overrides(self.inverters.clone());
+        let names: Vec<String> = crawler.dtu_names().map(str::to_string).collect();
+        for name in names {
+            let storage = self.storage.open(&self.out_dir, &name)?;
+            crawler = crawler.with_storage(&name, storage);
+        }
+        if let Some(config) = &self.mqtt {
+            log::info!("Publishing to mqtt broker {}:{}", config.host, config.port);
+            crawler = crawler.with_sink(Box::new(MqttSink::connect(config.clone())));
+        }
+        if let Some(config) = &self.influx {
+            log::info!("Writing to influx at {}", config.url);
+            crawler = crawler.with_sink(Box::new(InfluxSink::new(config.clone())));
+        }
+        Ok(crawler)
+    }
+}
+
+/// `config check [--config path]`: load the configuration like the crawler
+/// does and print either a summary of it or everything that is wrong with it.
+pub fn config_entrypoint(args: &[String]) -> Result<(), ErrorKind> {
+    let (command, mut args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => ("", args),
+    };
+    if command != "check" {
+        return Err(ErrorKind::InvalidArgument(format!(
+            "unknown config command {:?}, expected check",
+            command
+        )));
+    }
+    let mut path = None;
+    while let Some((arg, rest)) = args.split_first() {
+        match (arg.as_str(), rest.first()) {
+            ("--config", Some(value)) => path = Some(value.as_str()),
+            _ => return Err(ErrorKind::InvalidArgument(arg.clone())),
+        }
+        args = &rest[1..];
+    }
+
+    let config = Config::load(path)?;
+    let problems = config.problems();
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("error: {}", problem);
+        }
+        return Err(ErrorKind::InvalidConfig(format!(
+            "{} problem(s) found",
+            problems.len()
+        )));
+    }
+    for dtu in &config.dtus {
+        println!("dtu {:?}: {:?} at {}", dtu.name, dtu.dtu_type, dtu.endpoint);
+    }
+    println!(
+        "crawling every {}s, {} inverter override(s), storing {:?} rows in {}",
+        config.crawler.interval,
+        config.inverters.len(),
+        config.storage.backend,
+        config.out_dir
+    );
+    for sink in config.sink_names() {
+        println!("publishing to {}", sink);
+    }
+    println!("configuration is valid");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{DtuType, PayloadFormat, StorageKind};
+
+    const EXAMPLE: &str = r#"
+        out_dir = "/var/lib/ahoy"
+        metrics_address = "127.0.0.1:9100"
+
+        [crawler]
+        interval = 30
+        pause_at_night = true
+
+        [http]
+        retries = 1
+
+        [[dtu]]
+        name = "garage"
+        endpoint = "http://192.168.1.20"
+
+        [[dtu]]
+        name = "roof"
+        endpoint = "http://192.168.1.21"
+        type = "opendtu"
+        password = "secret"
+
+        [storage]
+        backend = "sqlite"
+        partitioning = "month"
+
+        [mqtt]
+        host = "localhost"
+        payload_format = "json"
+
+        [[inverter]]
+        serial = "116183771004"
+        dtu = "roof"
+        interval = 300
+        fields = ["P_AC", "YieldDay"]
+        sinks = ["mqtt"]
+    "#;
+
+    fn inverter(serial: &str, name: &str) -> Inverter {
+        serde_json::from_value(serde_json::json!({
+            "enabled": true, "id": 0, "name": name, "serial": serial, "channels": 2,
+            "version": "10012", "ch_yield_cor": [0, 0], "ch_name": ["A", "B"], "ch_max_pwr": [400, 400]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_file() {
+        let config = Config::parse(EXAMPLE).unwrap();
+        assert_eq!(config.out_dir, "/var/lib/ahoy");
+        assert_eq!(config.crawler.interval, 30);
+        assert_eq!(
+            config
+                .crawler
+                .schedule(Duration::from_secs(30))
+                .night_interval,
+            None
+        );
+        assert_eq!(config.http.retries, 1);
+        assert_eq!(config.dtus.len(), 2);
+        assert_eq!(config.dtus[1].dtu_type, DtuType::OpenDtu);
+        assert_eq!(config.storage.backend, StorageKind::Sqlite);
+        assert_eq!(
+            config.mqtt.as_ref().unwrap().payload_format,
+            PayloadFormat::Json
+        );
+        assert_eq!(config.influx, None);
+        assert_eq!(config.problems(), Vec::<String>::new());
+
+        let inverter_override = &config.inverters[0];
+        assert!(inverter_override.matches("roof", &inverter("116183771004", "Roof")));
+        assert!(!inverter_override.matches("garage", &inverter("116183771004", "Roof")));
+        assert!(!inverter_override.matches("roof", &inverter("116183771005", "Roof")));
+    }
+
+    #[test]
+    fn invalid_files() {
+        let res = Config::parse("[crawler]\nintervall = 30");
+        assert!(
+            matches!(&res, Err(ErrorKind::InvalidConfig(message)) if message.contains("intervall")),
+            "{:?}",
+            res
+        );
+        let res = Config::parse("[storage]\npartitioning = \"weekly\"");
+        assert!(
+            matches!(&res, Err(ErrorKind::InvalidConfig(message)) if message.contains("weekly")),
+            "{:?}",
+            res
+        );
+
+        let config = Config::parse(
+            r#"
+            [crawler]
+            concurrency = 0
+
+            [[dtu]]
+            name = "roof"
+            endpoint = "http://192.168.1.21"
+
+            [[dtu]]
+            name = "roof"
+            endpoint = ""
+
+            [[inverter]]
+            dtu = "garage"
+            fields = []
+
+            [[inverter]]
+            serial = "116183771004"
+            sinks = ["influx"]
+            "#,
+        )
+        .unwrap();
+        let problems = config.problems();
+        assert_eq!(problems.len(), 7, "{:#?}", problems);
+        assert!(problems[0].contains("names must be unique"));
+        assert!(problems[1].contains("endpoint must be set"));
+        assert!(problems[2].contains("set serial or name"));
+        assert!(problems[3].contains("no DTU named \"garage\""));
+        assert!(problems[4].contains("fields is empty"));
+        assert!(problems[5].contains("sink \"influx\" is not configured"));
+        assert!(problems[6].contains("crawler.concurrency"));
+        let res = config.validate();
+        assert!(
+            matches!(&res, Err(ErrorKind::InvalidConfig(message)) if message.lines().count() == 7),
+            "{:?}",
+            res
+        );
+
+        assert!(Config::default().problems()[0].contains("no DTU configured"));
+    }
+
+    #[test]
+    fn environment_overrides() {
+        let mut interval = 30_u64;
+        override_from_env(&mut interval, "AHOY_TEST_UNSET").unwrap();
+        assert_eq!(interval, 30);
+
+        env::set_var("AHOY_TEST_INTERVAL", "120");
+        override_from_env(&mut interval, "AHOY_TEST_INTERVAL").unwrap();
+        assert_eq!(interval, 120);
+
+        env::set_var("AHOY_TEST_INTERVAL", "2m");
+        let res = override_from_env(&mut interval, "AHOY_TEST_INTERVAL");
+        env::remove_var("AHOY_TEST_INTERVAL");
+        assert!(
+            matches!(&res, Err(ErrorKind::InvalidConfig(message)) if message.starts_with("AHOY_TEST_INTERVAL=\"2m\"")),
+            "{:?}",
+            res
+        );
+        assert_eq!(interval, 120);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::ErrorKind;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// A power limit as accepted by Ahoy's `/api/ctrl` endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PowerLimit {
+    /// limit in watts
+    Absolute(f32),
+    /// limit in percent of the inverters maximum power
+    Relative(f32),
+}
+
+/// A command that can be sent to an inverter through the DTU.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum InverterCommand {
+    /// Set a power limit. Persistent limits survive a restart of the inverter.
+    Limit {
+        limit: PowerLimit,
+        persistent: bool,
+    },
+    /// Turn the inverter on (`true`) or off (`false`).
+    Power(bool),
+    Restart,
+}
+
+impl InverterCommand {
+    fn cmd(&self) -> &'static str {
+        match self {
+            InverterCommand::Limit { limit, persistent } => match (limit, persistent) {
+                (PowerLimit::Absolute(_), true) => "limit_persistent_absolute",
+                (PowerLimit::Absolute(_), false) => "limit_nonpersistent_absolute",
+                (PowerLimit::Relative(_), true) => "limit_persistent_relative",
+                (PowerLimit::Relative(_), false) => "limit_nonpersistent_relative",
+            },
+            InverterCommand::Power(_) => "power",
+            InverterCommand::Restart => "restart",
+        }
+    }
+
+    /// Whether the inverter confirms the command through `power_limit_ack`.
+    pub fn expects_ack(&self) -> bool {
+        matches!(self, InverterCommand::Limit { .. })
+    }
+
+    pub fn validate(&self) -> Result<(), ErrorKind> {
+        match self {
+            InverterCommand::Limit {
+                limit: PowerLimit::Relative(percent),
+                ..
+            } if !(0.0..=100.0).contains(percent) => Err(ErrorKind::InvalidCommand(format!(
+                "relative limit must be between 0 and 100 %, got {}",
+                percent
+            ))),
+            InverterCommand::Limit {
+                limit: PowerLimit::Absolute(watts),
+                ..
+            } if !watts.is_finite() || *watts < 0.0 => Err(ErrorKind::InvalidCommand(format!(
+                "absolute limit must be a positive amount of watts, got {}",
+                watts
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Build the json body for `/api/ctrl`.
+    pub fn to_json(&self, inverter_id: u8) -> Value {
+        let mut body = json!({
+            "id": inverter_id,
+            "cmd": self.cmd(),
+        });
+        match self {
+            InverterCommand::Limit {
+                limit: PowerLimit::Absolute(value) | PowerLimit::Relative(value),
+                ..
+            } => body["val"] = json!(value),
+            InverterCommand::Power(on) => body["val"] = json!(u8::from(*on)),
+            InverterCommand::Restart => {}
+        }
+        body
+    }
+}
+
+/// The result of sending an [`InverterCommand`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum CommandOutcome {
+    /// The DTU accepted the command. Used for commands without acknowledgement.
+    Accepted,
+    /// The inverter acknowledged the new power limit.
+    Acknowledged { power_limit_read: u32 },
+    /// The DTU accepted the command, but the inverter did not acknowledge it in time.
+    NotAcknowledged,
+    /// The DTU refused the command, containing the reason it gave.
+    Rejected(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct CtrlResponse {
+    pub success: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+    /// only set in answer to a login
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limit_commands() {
+        let command = InverterCommand::Limit {
+            limit: PowerLimit::Absolute(400.0),
+            persistent: false,
+        };
+        assert_eq!(
+            command.to_json(1),
+            json!({"id": 1, "cmd": "limit_nonpersistent_absolute", "val": 400.0})
+        );
+
+        let command = InverterCommand::Limit {
+            limit: PowerLimit::Relative(50.0),
+            persistent: true,
+        };
+        assert_eq!(
+            command.to_json(0),
+            json!({"id": 0, "cmd": "limit_persistent_relative", "val": 50.0})
+        );
+        assert!(command.expects_ack());
+    }
+
+    #[test]
+    fn power_commands() {
+        assert_eq!(
+            InverterCommand::Power(false).to_json(0),
+            json!({"id": 0, "cmd": "power", "val": 0})
+        );
+        assert_eq!(
+            InverterCommand::Restart.to_json(2),
+            json!({"id": 2, "cmd": "restart"})
+        );
+        assert!(!InverterCommand::Restart.expects_ack());
+    }
+
+    #[test]
+    fn invalid_limits() {
+        assert!(InverterCommand::Limit {
+            limit: PowerLimit::Relative(120.0),
+            persistent: false,
+        }
+        .validate()
+        .is_err());
+        assert!(InverterCommand::Limit {
+            limit: PowerLimit::Absolute(-5.0),
+            persistent: false,
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -1,91 +1,507 @@
-use crate::{AhoyApi, CrawledInverter, ErrorKind};
+use crate::{
+    AhoyApi, CrawledInverter, CrawlerConfig, CsvStorage, DtuBackend, DtuEndpoint, ErrorKind,
+    InverterConfig, Live, Metrics, Sink, StorageBackend, DEFAULT_OUT_DIR,
+};
+
+use super::schedule::backoff;
 
 use chrono::{DateTime, Local};
+use futures::{future::join_all, stream, StreamExt};
 
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    env,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{self, Display, Formatter},
+    sync::Arc,
+    time::Duration,
 };
 
+/// Identifies an inverter among the inverters of all DTUs. The id an inverter
+/// has on its DTU is not unique once there is more than one DTU.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InverterKey {
+    pub dtu: String,
+    pub serial: String,
+}
+
+impl InverterKey {
+    pub fn new(dtu: &str, serial: &str) -> Self {
+        Self {
+            dtu: dtu.to_string(),
+            serial: serial.to_string(),
+        }
+    }
+}
+
+impl Display for InverterKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.dtu.is_empty() {
+            true => write!(f, "{}", self.serial),
+            false => write!(f, "{}/{}", self.dtu, self.serial),
+        }
+    }
+}
+
+/// The folder below `folder_path` the output of the DTU named `dtu` goes to.
+pub(crate) fn dtu_folder(folder_path: &str, dtu: &str) -> String {
+    match dtu.is_empty() {
+        true => folder_path.to_string(),
+        false => format!("{}/{}", folder_path, dtu),
+    }
+}
+
+/// Add the inverter that failed to be crawled to `error`.
+fn crawl_failed(inverter: &CrawledInverter, error: ErrorKind) -> ErrorKind {
+    ErrorKind::CrawlFailed {
+        inverter: inverter.key().to_string(),
+        source: Box::new(error),
+    }
+}
+
+/// The settings of the crawler a newly fetched inverter is set up with.
+struct InverterSetup<'a> {
+    journal_dir: Option<&'a str>,
+    settings: &'a CrawlerConfig,
+    overrides: &'a [InverterConfig],
+}
+
+struct CrawledDtu {
+    api: Arc<dyn DtuBackend>,
+    storage: Box<dyn StorageBackend>,
+    /// whether its inverters were fetched
+    initialized: bool,
+    /// consecutive failed attempts to fetch its inverters
+    failures: u32,
+    /// when fetching its inverters is attempted again
+    retry_at: Option<DateTime<Local>>,
+}
+
+/// Inverters crawled at the same time unless set with [`Crawler::with_concurrency`].
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
 pub struct Crawler {
-    api: AhoyApi,
-    pub inverters: HashMap<u8, CrawledInverter>,
+    dtus: BTreeMap<String, CrawledDtu>,
+    concurrency: usize,
+    journal_dir: Option<String>,
+    settings: CrawlerConfig,
+    overrides: Vec<InverterConfig>,
+    metrics: Option<Metrics>,
+    sinks: Vec<Box<dyn Sink>>,
+    pub inverters: HashMap<InverterKey, CrawledInverter>,
 }
 impl From<AhoyApi> for Crawler {
     fn from(api: AhoyApi) -> Self {
-        Crawler {
-            api,
-            inverters: HashMap::new(),
-        }
+        Crawler::from_backend(Arc::new(api))
     }
 }
 
 impl Crawler {
     pub fn new(endpoint: String) -> Crawler {
+        Crawler::from(AhoyApi::new(endpoint))
+    }
+
+    /// Crawl the inverters of any DTU, e.g. an [`OpenDtuApi`](crate::OpenDtuApi).
+    pub fn from_backend(api: Arc<dyn DtuBackend>) -> Crawler {
+        Crawler::from_dtus(vec![DtuEndpoint::new(String::new(), api)])
+    }
+
+    /// Crawl the inverters of several DTUs. Rows are written to csv files in
+    /// `./out/{dtu name}` unless a storage is set with [`Crawler::with_storage`].
+    pub fn from_dtus(dtus: Vec<DtuEndpoint>) -> Crawler {
         Crawler {
-            api: AhoyApi::new(endpoint),
+            dtus: dtus
+                .into_iter()
+                .map(|dtu| {
+                    let storage = CsvStorage::new(dtu_folder(DEFAULT_OUT_DIR, &dtu.name));
+                    (
+                        dtu.name,
+                        CrawledDtu {
+                            api: dtu.api,
+                            storage: Box::new(storage),
+                            initialized: false,
+                            failures: 0,
+                            retry_at: None,
+                        },
+                    )
+                })
+                .collect(),
+            concurrency: DEFAULT_CONCURRENCY,
+            journal_dir: None,
+            settings: CrawlerConfig::default(),
+            overrides: Vec::new(),
+            metrics: None,
+            sinks: Vec::new(),
             inverters: HashMap::new(),
         }
     }
 
-    /// Initialize the crawler by fetching all inverters from the API and
-    /// creating a CrawledInverter for each of them.
+    /// Journal crawled rows below `folder_path` (in a folder per named DTU)
+    /// until they are saved, and replay rows left over from a previous run.
+    pub fn with_journal(mut self, folder_path: String) -> Self {
+        self.journal_dir = Some(folder_path);
+        self
+    }
+
+    /// Crawl with the intervals and concurrency of `settings`.
+    pub fn with_settings(mut self, settings: CrawlerConfig) -> Self {
+        self.concurrency = settings.concurrency.max(1);
+        self.settings = settings;
+        self
+    }
+
+    /// Apply the first matching override to each inverter, see
+    /// [`CrawledInverter::configure`].
+    pub fn with_overrides(mut self, overrides: Vec<InverterConfig>) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Persist crawled rows of the DTU named `dtu` to `storage` instead of csv
+    /// files in `./out`.
+    pub fn with_storage(mut self, dtu: &str, storage: Box<dyn StorageBackend>) -> Self {
+        match self.dtus.get_mut(dtu) {
+            Some(crawled_dtu) => crawled_dtu.storage = storage,
+            None => log::warn!("No DTU named {:?}, ignoring its storage", dtu),
+        }
+        self
+    }
+
+    /// Crawl at most `concurrency` due inverters at the same time.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Record crawled values and errors in `metrics`.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Publish every crawl result to `sink`, in addition to the storage.
+    pub fn with_sink(mut self, sink: Box<dyn Sink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// The names of all DTUs, empty for an unnamed single DTU.
+    pub fn dtu_names(&self) -> impl Iterator<Item = &str> {
+        self.dtus.keys().map(String::as_str)
+    }
+
+    /// The time of the crawler, the earliest clock of its DTUs.
+    pub fn now(&self) -> DateTime<Local> {
+        self.dtus
+            .values()
+            .map(|dtu| dtu.api.now())
+            .min()
+            .unwrap_or_else(Local::now)
+    }
+
+    /// The timestamp of a crawling cycle, the earliest of its DTUs.
+    fn cycle_time(&self) -> DateTime<Local> {
+        self.dtus
+            .values()
+            .map(|dtu| dtu.api.cycle_time())
+            .min()
+            .unwrap_or_else(Local::now)
+    }
+
+    fn record_error(&self, error: &ErrorKind) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_error(error);
+        }
+    }
+
+    async fn fetch_inverter(
+        dtu: &str,
+        api: &Arc<dyn DtuBackend>,
+        setup: &InverterSetup<'_>,
+        inverter_id: u8,
+    ) -> Result<CrawledInverter, ErrorKind> {
+        let inverter = CrawledInverter::fetch(api.clone(), inverter_id)
+            .await?
+            .with_dtu(dtu)
+            .configure(setup.settings, setup.overrides);
+        Ok(match setup.journal_dir {
+            Some(journal_dir) => inverter.with_journal(&dtu_folder(journal_dir, dtu)),
+            None => inverter,
+        })
+    }
+
+    /// Initialize the crawler by fetching all inverters from the API of every
+    /// DTU and creating a CrawledInverter for each of them. Fails only if no
+    /// DTU could be reached, the others are retried while crawling.
     pub async fn init(&mut self) -> Result<(), ErrorKind> {
-        let inverter_list = self.api.get_inverter_list().await?;
-        for inverter in inverter_list.inverter {
-            let crawled_inverter = CrawledInverter::fetch(&self.api, inverter.id).await?;
-            self.inverters.insert(inverter.id, crawled_inverter);
+        self.fetch_pending_dtus(None).await
+    }
+
+    /// What a newly fetched inverter is set up with.
+    fn setup(&self) -> InverterSetup<'_> {
+        InverterSetup {
+            journal_dir: self.journal_dir.as_deref(),
+            settings: &self.settings,
+            overrides: &self.overrides,
         }
-        Ok(())
     }
 
-    async fn get_inverter(&mut self, inverter_id: u8) -> Result<&mut CrawledInverter, ErrorKind> {
-        match self.inverters.entry(inverter_id) {
-            Entry::Vacant(entry) => {
-                log::info!("Initiating Inverter: {}", inverter_id);
-                Ok(entry.insert(CrawledInverter::fetch(&self.api, inverter_id).await?))
+    async fn fetch_dtu_inverters(
+        dtu: &str,
+        api: &Arc<dyn DtuBackend>,
+        setup: &InverterSetup<'_>,
+    ) -> Result<Vec<CrawledInverter>, ErrorKind> {
+        let mut inverters = Vec::new();
+        for inverter in api.get_inverter_list().await?.inverter {
+            inverters.push(Self::fetch_inverter(dtu, api, setup, inverter.id).await?);
+        }
+        Ok(inverters)
+    }
+
+    /// Fetch the inverters of the DTUs that are not initialized yet, at `now`
+    /// only those whose retry is due. Fails if no DTU is initialized.
+    async fn fetch_pending_dtus(&mut self, now: Option<DateTime<Local>>) -> Result<(), ErrorKind> {
+        let setup = &self.setup();
+        let fetched = join_all(
+            self.dtus
+                .iter()
+                .filter(|(_, crawled_dtu)| !crawled_dtu.initialized)
+                .filter(|(_, crawled_dtu)| match (now, crawled_dtu.retry_at) {
+                    (Some(now), Some(retry_at)) => retry_at <= now,
+                    _ => true,
+                })
+                .map(|(dtu, crawled_dtu)| async move {
+                    let inverters = Self::fetch_dtu_inverters(dtu, &crawled_dtu.api, setup).await;
+                    (dtu.clone(), inverters)
+                }),
+        )
+        .await;
+
+        let interval = Duration::from_secs(self.settings.interval);
+        let mut errors = Vec::new();
+        for (dtu, inverters) in fetched {
+            let Some(crawled_dtu) = self.dtus.get_mut(&dtu) else {
+                continue;
+            };
+            match inverters {
+                Ok(inverters) => {
+                    crawled_dtu.initialized = true;
+                    crawled_dtu.failures = 0;
+                    crawled_dtu.retry_at = None;
+                    for inverter in inverters {
+                        self.inverters.insert(inverter.key(), inverter);
+                    }
+                }
+                Err(e) => {
+                    crawled_dtu.failures += 1;
+                    let retry_in = backoff(interval, crawled_dtu.failures);
+                    crawled_dtu.retry_at = Some(crawled_dtu.api.now() + retry_in);
+                    log::error!(
+                        "Could not fetch the inverters of DTU {:?}, retrying in {:?}: {}",
+                        dtu,
+                        retry_in,
+                        e
+                    );
+                    errors.push(e);
+                }
             }
-            Entry::Occupied(entry) => Ok(entry.into_mut()),
+        }
+        for e in &errors {
+            self.record_error(e);
+        }
+        match (self.dtus.values().any(|dtu| dtu.initialized), errors.pop()) {
+            (false, Some(e)) => Err(e),
+            _ => Ok(()),
         }
     }
 
-    pub async fn crawl_inverter(&mut self, inverter_id: u8) -> Result<(), ErrorKind> {
-        let inverter = self.get_inverter(inverter_id).await?;
-        inverter.crawl().await
+    /// The earliest time an inverter is due or a DTU is retried, `None` if
+    /// nothing is scheduled.
+    pub fn next_due(&self) -> Option<DateTime<Local>> {
+        self.inverters
+            .values()
+            .filter_map(|inverter| inverter.next_crawl_at)
+            .chain(self.dtus.values().filter_map(|dtu| dtu.retry_at))
+            .min()
+    }
+
+    async fn get_inverter(
+        &mut self,
+        dtu: &str,
+        inverter_id: u8,
+    ) -> Result<&mut CrawledInverter, ErrorKind> {
+        let key = self
+            .inverters
+            .values()
+            .find(|inverter| inverter.dtu == dtu && inverter.id == inverter_id)
+            .map(CrawledInverter::key);
+        let key = match key {
+            Some(key) => key,
+            None => {
+                log::info!("Initiating Inverter: {}", inverter_id);
+                let api = &self
+                    .dtus
+                    .get(dtu)
+                    .ok_or(ErrorKind::InvalidArgument(format!(
+                        "no DTU named {:?}",
+                        dtu
+                    )))?
+                    .api;
+                let inverter = Self::fetch_inverter(dtu, api, &self.setup(), inverter_id).await?;
+                let key = inverter.key();
+                self.inverters.insert(key.clone(), inverter);
+                key
+            }
+        };
+        self.inverters
+            .get_mut(&key)
+            .ok_or(ErrorKind::InvalidArgument(key.to_string()))
+    }
+
+    /// Crawl the inverter with id `inverter_id` on the DTU named `dtu`.
+    pub async fn crawl_inverter(&mut self, dtu: &str, inverter_id: u8) -> Result<(), ErrorKind> {
+        let inverter = self.get_inverter(dtu, inverter_id).await?;
+        inverter
+            .crawl()
+            .await
+            .map_err(|e| crawl_failed(inverter, e))
+    }
+
+    /// Write the buffered rows of all inverters to the storage of their DTU.
+    pub async fn save(&mut self) -> Result<(), ErrorKind> {
+        for inverter in self.inverters.values_mut() {
+            if let Some(crawled_dtu) = self.dtus.get_mut(&inverter.dtu) {
+                inverter.save(crawled_dtu.storage.as_mut()).await?;
+            }
+        }
+        Ok(())
     }
 
+    /// Write the buffered rows of all inverters to csv files below
+    /// `folder_path`, in a folder per named DTU.
     pub async fn save_to_csv(&mut self, folder_path: &str) -> Result<(), ErrorKind> {
         for inverter in self.inverters.values_mut() {
-            inverter.save_to_csv(folder_path).await?;
+            let folder_path = dtu_folder(folder_path, &inverter.dtu);
+            inverter.save_to_csv(&folder_path).await?;
         }
         Ok(())
     }
 
     pub async fn crawl_all_due_inverters(
         &mut self,
-        sync_to_file: bool,
+        sync_to_storage: bool,
     ) -> Result<Option<DateTime<Local>>, ErrorKind> {
-        let out_dir = env::var("OUT_DIR").unwrap_or("./out".to_string());
-        let mut due_inverters = vec![];
-        let mut next_due: Option<DateTime<Local>> = None;
-        for (index, inverter) in &self.inverters {
-            if inverter.is_due() {
-                due_inverters.push(*index);
-            }
+        let result = self.crawl_due_inverters(sync_to_storage).await;
+        if let Err(e) = &result {
+            self.record_error(e);
         }
-        for inverter_id in due_inverters {
-            let inverter = self.get_inverter(inverter_id).await?;
-            inverter.crawl().await?;
+        result
+    }
+
+    /// Crawl the due inverters and return when the next one is due. Failed
+    /// inverters are backed off and the first of their errors is returned,
+    /// [`Crawler::next_due`] still tells when to crawl again.
+    async fn crawl_due_inverters(
+        &mut self,
+        sync_to_storage: bool,
+    ) -> Result<Option<DateTime<Local>>, ErrorKind> {
+        // one timestamp for all rows of the cycle, so they line up across inverters
+        let crawling_time = self.cycle_time();
+        // errors are logged and recorded, the other DTUs are crawled regardless
+        let _ = self.fetch_pending_dtus(Some(crawling_time)).await;
+
+        // `/api/live` is the same for all inverters of a DTU, so it is fetched
+        // once per DTU and cycle instead of once per inverter
+        let due_dtus: BTreeSet<&str> = self
+            .inverters
+            .values()
+            .filter(|inverter| inverter.is_due())
+            .map(|inverter| inverter.dtu.as_str())
+            .collect();
+        let dtus = &self.dtus;
+        let lives: HashMap<String, Result<Live, ErrorKind>> =
+            join_all(due_dtus.into_iter().map(|dtu| async move {
+                let live = match dtus.get(dtu) {
+                    Some(crawled_dtu) => crawled_dtu.api.get_live().await,
+                    None => Err(ErrorKind::InvalidArgument(format!(
+                        "no DTU named {:?}",
+                        dtu
+                    ))),
+                };
+                (dtu.to_string(), live)
+            }))
+            .await
+            .into_iter()
+            .collect();
+
+        let mut due_inverters: Vec<&mut CrawledInverter> = self
+            .inverters
+            .values_mut()
+            .filter(|inverter| inverter.is_due())
+            .collect();
+        let results: Vec<Result<(), ErrorKind>> =
+            stream::iter(due_inverters.iter_mut().map(|inverter| {
+                let live = lives.get(&inverter.dtu);
+                async move {
+                    match live {
+                        Some(Ok(live)) => inverter.crawl_with(live, crawling_time).await,
+                        Some(Err(e)) => Err(e.clone()),
+                        None => inverter.crawl().await,
+                    }
+                }
+            }))
+            .buffered(self.concurrency)
+            .collect()
+            .await;
 
-            if sync_to_file {
-                inverter.save_to_csv(&out_dir).await?;
+        let mut result = Ok(());
+        for (inverter, crawled) in due_inverters.into_iter().zip(results) {
+            if let Err(e) = crawled {
+                inverter.back_off(crawling_time);
+                let e = crawl_failed(inverter, e);
+                log::error!("{}", e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+                continue;
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics.record_crawl(inverter);
+            }
+            // a failing sink must not keep the rows from being stored
+            let selected = |sink: &&mut Box<dyn Sink>| match &inverter.sinks {
+                Some(names) => names.iter().any(|name| name == sink.name()),
+                None => true,
+            };
+            for sink in self
+                .sinks
+                .iter_mut()
+                .filter(|_| !inverter.is_stale)
+                .filter(selected)
+            {
+                if let Err(e) = sink.publish(inverter).await {
+                    log::error!("Error publishing inverter {}: {}", inverter.key(), e);
+                    if let Some(metrics) = &self.metrics {
+                        metrics.record_error(&e);
+                    }
+                }
             }
-            if let Some(next_crawl) = inverter.next_crawl_at {
-                next_due = Some(next_due.map_or(next_crawl, |v| v.min(next_crawl)));
+
+            // nor must a failing storage keep the other inverters from being handled
+            if sync_to_storage {
+                if let Some(crawled_dtu) = self.dtus.get_mut(&inverter.dtu) {
+                    if let Err(e) = inverter.save(crawled_dtu.storage.as_mut()).await {
+                        log::error!("Error saving inverter {}: {}", inverter.key(), e);
+                        // the returned error is recorded by the caller
+                        if result.is_ok() {
+                            result = Err(e);
+                        } else if let Some(metrics) = &self.metrics {
+                            metrics.record_error(&e);
+                        }
+                    }
+                }
             }
         }
 
-        Ok(next_due)
+        result.map(|_| self.next_due())
     }
 }
 
@@ -93,38 +509,351 @@ impl Crawler {
 mod test {
     use super::*;
 
-    use dotenv::dotenv;
-    use std::env;
+    use crate::{
+        api::test_util::{mock_dtu, TempDir},
+        Channel, Dataset, Fault, Index, Inverter, InverterList, InverterStatus, RetryPolicy,
+    };
 
-    static IS_OFFLINE: bool = true;
+    use async_trait::async_trait;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
 
     fn init() -> AhoyApi {
-        dotenv().ok();
-
-        let endpoint = env::var("INVERTER_ENDPOINT").unwrap();
-
-        let mut api = AhoyApi::new(endpoint);
-        api.set_offline_mode(IS_OFFLINE);
-        api
+        AhoyApi::offline()
     }
 
     #[tokio::test]
     async fn crawl_inverter() {
         let mut crawler = Crawler::from(init());
-        crawler.crawl_inverter(0).await.unwrap();
+        crawler.crawl_inverter("", 0).await.unwrap();
         println!("{:#?}", crawler.inverters);
     }
 
+    #[tokio::test]
+    async fn skip_stale_rows() {
+        let mut crawler = Crawler::from(init());
+        crawler.crawl_inverter("", 0).await.unwrap();
+        // the offline DTU never reports a new ts_last_success
+        crawler.crawl_inverter("", 0).await.unwrap();
+        let inverter = &crawler.inverters[&InverterKey::new("", "114184511809")];
+        assert!(inverter.is_stale);
+        assert_eq!(inverter.summary_dataset.len(), 1);
+        assert!(inverter.summary_dataset.rows()[0].measured_at.is_some());
+    }
+
     #[tokio::test]
     async fn crawl_inverter_and_save() {
         let mut crawler = Crawler::from(init());
-        crawler.crawl_inverter(0).await.unwrap();
-        crawler.crawl_inverter(0).await.unwrap();
+        crawler.crawl_inverter("", 0).await.unwrap();
+        crawler.crawl_inverter("", 0).await.unwrap();
         crawler.save_to_csv("./out").await.unwrap();
-        crawler.crawl_inverter(0).await.unwrap();
+        crawler.crawl_inverter("", 0).await.unwrap();
         crawler.save_to_csv("./out").await.unwrap();
-        crawler.crawl_inverter(0).await.unwrap();
+        crawler.crawl_inverter("", 0).await.unwrap();
         crawler.save_to_csv("./out").await.unwrap();
-        crawler.crawl_inverter(0).await.unwrap();
+        crawler.crawl_inverter("", 0).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn crawl_several_dtus() {
+        let temp_dir = TempDir::new("dtus");
+        let folder = temp_dir.path();
+        // both offline DTUs report the same inverter, which must not collide
+        let mut crawler = Crawler::from_dtus(vec![
+            DtuEndpoint::new("garage".to_string(), Arc::new(init())),
+            DtuEndpoint::new("roof".to_string(), Arc::new(init())),
+        ])
+        .with_storage(
+            "garage",
+            Box::new(CsvStorage::new(format!("{}/garage", folder))),
+        )
+        .with_storage(
+            "roof",
+            Box::new(CsvStorage::new(format!("{}/roof", folder))),
+        );
+        assert_eq!(crawler.dtu_names().collect::<Vec<_>>(), ["garage", "roof"]);
+
+        crawler.init().await.unwrap();
+        let mut keys: Vec<String> = crawler
+            .inverters
+            .keys()
+            .map(|key| key.to_string())
+            .collect();
+        keys.sort();
+        assert_eq!(keys, ["garage/114184511809", "roof/114184511809"]);
+
+        crawler.crawl_all_due_inverters(true).await.unwrap();
+        for dtu in ["garage", "roof"] {
+            let path = format!("{}/{}/PV Microinverte/summary.csv", folder, dtu);
+            assert!(std::path::Path::new(&path).exists(), "{} is missing", path);
+        }
+    }
+
+    /// Counts the requests to `/api/live`.
+    #[derive(Debug)]
+    struct CountingDtu {
+        api: AhoyApi,
+        live_requests: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl DtuBackend for CountingDtu {
+        async fn get_inverter_list(&self) -> Result<InverterList, ErrorKind> {
+            self.api.get_inverter_list().await
+        }
+
+        async fn get_live(&self) -> Result<Live, ErrorKind> {
+            self.live_requests.fetch_add(1, Ordering::SeqCst);
+            self.api.get_live().await
+        }
+
+        async fn get_inverter_status(
+            &self,
+            inverter: Inverter,
+        ) -> Result<InverterStatus, ErrorKind> {
+            self.api.get_inverter_status(inverter).await
+        }
+
+        async fn get_index(&self) -> Result<Index, ErrorKind> {
+            self.api.get_index().await
+        }
+    }
+
+    #[tokio::test]
+    async fn crawl_cycle() {
+        let dtus: Vec<Arc<CountingDtu>> = (0..3)
+            .map(|_| {
+                Arc::new(CountingDtu {
+                    api: init(),
+                    live_requests: AtomicUsize::new(0),
+                })
+            })
+            .collect();
+        let mut crawler = Crawler::from_dtus(
+            dtus.iter()
+                .enumerate()
+                .map(|(index, dtu)| DtuEndpoint::new(format!("dtu{}", index), dtu.clone()))
+                .collect(),
+        )
+        .with_concurrency(2);
+        crawler.init().await.unwrap();
+        let fetched: Vec<usize> = dtus
+            .iter()
+            .map(|dtu| dtu.live_requests.swap(0, Ordering::SeqCst))
+            .collect();
+
+        crawler.crawl_all_due_inverters(false).await.unwrap();
+        for dtu in &dtus {
+            assert_eq!(dtu.live_requests.load(Ordering::SeqCst), 1);
+        }
+        assert!(fetched.iter().all(|requests| *requests > 0));
+
+        let crawled_at: Vec<_> = crawler
+            .inverters
+            .values()
+            .flat_map(|inverter| {
+                inverter
+                    .datasets()
+                    .map(|(_, dataset)| dataset.rows()[0].crawled_at)
+            })
+            .collect();
+        assert_eq!(crawled_at.len(), 9);
+        assert!(crawled_at.iter().all(|time| *time == crawled_at[0]));
+    }
+
+    #[tokio::test]
+    async fn crawl_mock_dtu() {
+        let temp_dir = TempDir::new("mock");
+        let folder = temp_dir.path();
+        let (dtu, api) = mock_dtu(3, 4);
+        let mut crawler =
+            Crawler::from(api).with_storage("", Box::new(CsvStorage::new(folder.to_string())));
+
+        crawler.init().await.unwrap();
+        assert_eq!(crawler.inverters.len(), 3);
+        let live_requests = dtu.requests("/api/live");
+        crawler.crawl_all_due_inverters(true).await.unwrap();
+        assert_eq!(dtu.requests("/api/live"), live_requests + 1);
+        for id in 0..3 {
+            let path = format!("{}/Inverter {}/summary.csv", folder, id);
+            assert!(std::path::Path::new(&path).exists(), "{} is missing", path);
+            let inverter = &crawler.inverters[&InverterKey::new("", &format!("11618377100{}", id))];
+            assert_eq!(inverter.datasets().count(), 5);
+            assert!(!inverter.is_stale);
+        }
+    }
+
+    /// Fails every store, remembering the serials it was handed.
+    #[derive(Clone, Default)]
+    struct FailingStorage(Arc<std::sync::Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl StorageBackend for FailingStorage {
+        async fn store(
+            &mut self,
+            inverter: &Inverter,
+            _channel: &Channel,
+            _dataset: &Dataset,
+        ) -> Result<(), ErrorKind> {
+            self.0.lock().unwrap().push(inverter.serial.clone());
+            Err(ErrorKind::CouldNotOpenFile("full disk".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_storage() {
+        let (_dtu, api) = mock_dtu(2, 2);
+        let storage = FailingStorage::default();
+        let metrics = Metrics::new();
+        let mut crawler = Crawler::from(api)
+            .with_storage("", Box::new(storage.clone()))
+            .with_metrics(metrics.clone());
+        crawler.init().await.unwrap();
+
+        let err = crawler.crawl_all_due_inverters(true).await.unwrap_err();
+        assert!(matches!(err, ErrorKind::CouldNotOpenFile(_)), "{}", err);
+        let mut serials = storage.0.lock().unwrap().clone();
+        serials.sort();
+        assert_eq!(serials, ["116183771000", "116183771001"]);
+        let rendered = metrics.render();
+        assert!(
+            rendered.contains("ahoy_errors_total{kind=\"CouldNotOpenFile\"} 2\n"),
+            "{}",
+            rendered
+        );
+        assert!(crawler
+            .inverters
+            .values()
+            .all(|inverter| inverter.crawled_at.is_some()));
+    }
+
+    #[tokio::test]
+    async fn crawl_faulty_mock_dtu() {
+        let (dtu, api) = mock_dtu(2, 2);
+        let mut crawler = Crawler::from(api);
+        crawler.init().await.unwrap();
+        crawler.crawl_inverter("", 1).await.unwrap();
+
+        dtu.inject("/api/inverter/id/1", Fault::ServerError, 1);
+        let err = crawler.crawl_inverter("", 1).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("crawling inverter 116183771001 failed"));
+        assert!(matches!(
+            err.root(),
+            ErrorKind::HttpStatus { status: 500, .. }
+        ));
+
+        dtu.inject("/api/inverter/id/1", Fault::MalformedJson, 1);
+        let err = crawler.crawl_inverter("", 1).await.unwrap_err();
+        assert_eq!(err.root().name(), "ParsingError");
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        crawler.crawl_inverter("", 1).await.unwrap();
+        dtu.inject("/api/inverter/id/1", Fault::Stale, 1);
+        crawler.crawl_inverter("", 1).await.unwrap();
+        let inverter = &crawler.inverters[&InverterKey::new("", "116183771001")];
+        assert!(inverter.is_stale);
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        crawler.crawl_inverter("", 1).await.unwrap();
+        let inverter = &crawler.inverters[&InverterKey::new("", "116183771001")];
+        assert!(!inverter.is_stale);
+    }
+
+    #[tokio::test]
+    async fn failing_dtu_and_inverter_back_off() {
+        let (dtu, api) = mock_dtu(2, 2);
+        let unreachable =
+            AhoyApi::new("http://127.0.0.1:9".to_string()).with_retry_policy(RetryPolicy::none());
+        let mut crawler = Crawler::from_dtus(vec![
+            DtuEndpoint::new("garage".to_string(), Arc::new(unreachable)),
+            DtuEndpoint::new("roof".to_string(), Arc::new(api)),
+        ]);
+        crawler.init().await.unwrap();
+        assert_eq!(crawler.inverters.len(), 2);
+        let retry_at = crawler.dtus["garage"].retry_at.unwrap();
+        assert!(retry_at > Local::now() + chrono::Duration::seconds(50));
+
+        dtu.inject("/api/live", Fault::ServerError, 1);
+        let crawling_time = crawler.now();
+        assert!(crawler.crawl_all_due_inverters(false).await.is_err());
+        for inverter in crawler.inverters.values() {
+            assert_eq!(inverter.failures, 1);
+            assert!(inverter.next_crawl_at.unwrap() >= crawling_time + Duration::from_secs(60));
+        }
+        assert!(crawler.next_due().unwrap() > crawling_time);
+
+        // nothing is due, neither the inverters nor the unreachable DTU
+        let live_requests = dtu.requests("/api/live");
+        let next_due = crawler.crawl_all_due_inverters(false).await.unwrap();
+        assert_eq!(next_due, crawler.next_due());
+        assert_eq!(dtu.requests("/api/live"), live_requests);
+        assert_eq!(crawler.dtus["garage"].retry_at, Some(retry_at));
+    }
+
+    /// Records the serials of the inverters published to it.
+    struct RecordingSink(Arc<std::sync::Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl Sink for RecordingSink {
+        fn name(&self) -> &str {
+            "mqtt"
+        }
+
+        async fn publish(&mut self, inverter: &CrawledInverter) -> Result<(), ErrorKind> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(inverter.inverter().serial.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn per_inverter_overrides() {
+        let (_dtu, api) = mock_dtu(2, 2);
+        let published = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut crawler = Crawler::from(api)
+            .with_settings(CrawlerConfig {
+                adaptive: false,
+                ..CrawlerConfig::default()
+            })
+            .with_overrides(vec![InverterConfig {
+                serial: Some("116183771001".to_string()),
+                interval: Some(300),
+                fields: Some(vec!["P_AC".to_string(), "U_DC".to_string()]),
+                sinks: Some(Vec::new()),
+                ..InverterConfig::default()
+            }])
+            .with_sink(Box::new(RecordingSink(published.clone())));
+
+        crawler.init().await.unwrap();
+        crawler.crawl_all_due_inverters(false).await.unwrap();
+        assert_eq!(*published.lock().unwrap(), vec!["116183771000"]);
+
+        let interval = |inverter: &CrawledInverter| {
+            (inverter.next_crawl_at.unwrap() - inverter.crawled_at.unwrap()).num_seconds()
+        };
+        let all = &crawler.inverters[&InverterKey::new("", "116183771000")];
+        assert_eq!(interval(all), 60);
+        assert_eq!(all.summary_dataset.fields().len(), 12);
+
+        let selected = &crawler.inverters[&InverterKey::new("", "116183771001")];
+        assert_eq!(interval(selected), 300);
+        let names = |dataset: &Dataset| -> Vec<String> {
+            dataset
+                .fields()
+                .iter()
+                .map(|field| field.name.clone())
+                .collect()
+        };
+        assert_eq!(names(&selected.summary_dataset), vec!["P_AC"]);
+        assert_eq!(names(&selected.channel_datasets[1]), vec!["U_DC"]);
+        let row = &selected.summary_dataset.rows()[0];
+        assert_eq!(row.values.len(), 1);
+        assert!(row.values[0].is_some());
     }
 }
@@ -0,0 +1,33 @@
+use crate::Inverter;
+
+use serde::{Deserialize, Serialize};
+
+use std::fmt::{self, Display};
+
+/// Identifies one of the datasets of a crawled inverter.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    /// channel 0 of the DTU, the values of the inverter itself
+    Summary,
+    /// an input (string) of the inverter, starting at 0
+    Input(u8),
+}
+
+impl Channel {
+    /// The name the user gave the channel in the DTU, if any.
+    pub fn name<'a>(&self, inverter: &'a Inverter) -> Option<&'a str> {
+        match self {
+            Channel::Summary => None,
+            Channel::Input(index) => inverter.ch_name.get(*index as usize).map(String::as_str),
+        }
+    }
+}
+
+impl Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Channel::Summary => write!(f, "summary"),
+            Channel::Input(index) => write!(f, "{}", index),
+        }
+    }
+}
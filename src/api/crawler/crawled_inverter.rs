@@ -1,15 +1,21 @@
-use crate::{AhoyApi, Dataset, ErrorKind, Inverter, UnitValue};
+use crate::{
+    Channel, CrawlerConfig, CsvStorage, Dataset, DtuBackend, ErrorKind, Generic, Inverter,
+    InverterConfig, InverterKey, Live, SchedulePolicy, StorageBackend, SunTimes, UnitValue,
+};
 
-use chrono::{DateTime, Local};
+use super::schedule::backoff;
 
-use std::{collections::HashMap, env, time::Duration};
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+
+use std::{collections::HashMap, iter::once, sync::Arc, time::Duration};
 
 #[derive(Debug, Clone)]
 pub struct CrawledInverter {
-    api: AhoyApi,
+    api: Arc<dyn DtuBackend>,
     original_inverter: Inverter,
 
-    pub id: u8,       // InverterIndex.id or InverterStatus.id
+    pub dtu: String, // name of the DTU the inverter is connected to, empty for a single DTU
+    pub id: u8,      // InverterIndex.id or InverterStatus.id
     pub name: String, // InverterIndex.name or InverterStatus.name
 
     pub is_enabled: bool,   // InverterIndex.enabled or InverterStatus.enabled
@@ -19,6 +25,19 @@ pub struct CrawledInverter {
     pub crawled_at: Option<DateTime<Local>>,
     pub next_crawl_at: Option<DateTime<Local>>,
     pub crawling_interval: Option<Duration>,
+    pub failures: u32,                           // consecutive failed crawls
+    pub schedule: SchedulePolicy, // its day interval applies unless crawling_interval is set
+    pub sun_times: Option<SunTimes>, // Index.ts_sunrise, Index.ts_sunset, refreshed daily
+    pub sun_times_checked_on: Option<NaiveDate>, // day of the last attempt to refresh them
+
+    pub generic: Option<Generic>, // Live.generic of the last crawl
+
+    pub last_success: Option<u64>, // InverterStatus.ts_last_success of the last crawl
+    pub is_stale: bool,            // the last crawl returned no new measurement
+    pub keep_stale_rows: bool,     // store a row even if the inverter did not report
+
+    pub selected_fields: Option<Vec<String>>, // only these fields are stored, all if None
+    pub sinks: Option<Vec<String>>,           // names of the sinks it is published to, all if None
 
     pub channel_count: u8, // Inverter.channels
     // pub channel_fields: Vec<EmptyField>, // live.fld_names combined with Inverter.fld_units
@@ -32,15 +51,29 @@ pub struct CrawledInverter {
 }
 
 impl CrawledInverter {
-    pub async fn fetch(api: &AhoyApi, index: u8) -> Result<Self, ErrorKind> {
-        let inverter_index = &api.get_index().await?.inverter[index as usize];
-        let inverter = &api.get_inverter_list().await?.inverter[index as usize];
+    pub async fn fetch(api: Arc<dyn DtuBackend>, index: u8) -> Result<Self, ErrorKind> {
+        let not_found = || ErrorKind::InverterNotFound(format!("with id {}", index));
+        // ids are not positions, they have gaps once an inverter was deleted
+        let dtu_index = api.get_index().await?;
+        let inverter_index = dtu_index
+            .inverter
+            .iter()
+            .find(|inverter| inverter.id == index)
+            .ok_or_else(not_found)?;
+        let inverter_list = api.get_inverter_list().await?;
+        let inverter = inverter_list
+            .inverter
+            .iter()
+            .find(|inverter| inverter.id == index)
+            .ok_or_else(not_found)?;
         let live = &api.get_live().await?;
+        let checked_on = api.now().date_naive();
 
         Ok(CrawledInverter {
-            api: api.clone(),
+            api,
             original_inverter: inverter.clone(),
 
+            dtu: String::new(),
             id: inverter.id,
             name: inverter.name.clone(),
 
@@ -51,6 +84,19 @@ impl CrawledInverter {
             crawled_at: None,
             next_crawl_at: None,
             crawling_interval: None,
+            failures: 0,
+            schedule: SchedulePolicy::new(Duration::from_secs(60)),
+            sun_times: SunTimes::from_index(&dtu_index),
+            sun_times_checked_on: Some(checked_on),
+
+            generic: None,
+
+            last_success: None,
+            is_stale: false,
+            keep_stale_rows: false,
+
+            selected_fields: None,
+            sinks: None,
 
             channel_count: inverter.channels,
             channel_datasets: (0..inverter.channels)
@@ -60,48 +106,311 @@ impl CrawledInverter {
         })
     }
 
-    pub async fn save_to_csv(&mut self, folder_path: &str) -> Result<(), ErrorKind> {
+    /// Name the DTU the inverter is connected to.
+    pub fn with_dtu(mut self, dtu: &str) -> Self {
+        self.dtu = dtu.to_string();
+        self
+    }
+
+    /// Apply the crawler `settings` and the first of `overrides` matching the
+    /// inverter. Must be called before [`CrawledInverter::with_journal`], as
+    /// it drops the fields that are not selected.
+    pub fn configure(mut self, settings: &CrawlerConfig, overrides: &[InverterConfig]) -> Self {
+        let inverter_override = overrides.iter().find(|inverter_override| {
+            inverter_override.matches(&self.dtu, &self.original_inverter)
+        });
+        let interval = inverter_override
+            .and_then(|inverter_override| inverter_override.interval)
+            .unwrap_or(settings.interval);
+        self.schedule = settings.schedule(Duration::from_secs(interval));
+        self.keep_stale_rows = settings.keep_stale_rows;
+        self.selected_fields = inverter_override.and_then(|o| o.fields.clone());
+        self.sinks = inverter_override.and_then(|o| o.sinks.clone());
+
+        let (names, units) = self.selected(
+            self.summary_dataset
+                .fields()
+                .iter()
+                .map(|field| &field.name),
+            self.summary_dataset
+                .fields()
+                .iter()
+                .map(|field| &field.unit),
+        );
+        self.summary_dataset = Dataset::new(&names, &units);
+        let channel_fields = self.channel_datasets.first().map(|dataset| {
+            self.selected(
+                dataset.fields().iter().map(|field| &field.name),
+                dataset.fields().iter().map(|field| &field.unit),
+            )
+        });
+        if let Some((names, units)) = channel_fields {
+            for dataset in &mut self.channel_datasets {
+                *dataset = Dataset::new(&names, &units);
+            }
+        }
+        self
+    }
+
+    /// The names and units of the selected fields among `names`.
+    fn selected<'a>(
+        &self,
+        names: impl Iterator<Item = &'a String>,
+        units: impl Iterator<Item = &'a String>,
+    ) -> (Vec<String>, Vec<String>) {
+        names
+            .zip(units)
+            .filter(|(name, _)| {
+                self.selected_fields
+                    .iter()
+                    .all(|selected| selected.contains(name))
+            })
+            .map(|(name, unit)| (name.clone(), unit.clone()))
+            .unzip()
+    }
+
+    /// The key of the inverter among the inverters of all DTUs.
+    pub fn key(&self) -> InverterKey {
+        InverterKey::new(&self.dtu, &self.original_inverter.serial)
+    }
+
+    /// Journal the buffered rows of all datasets below `folder_path`, next to
+    /// the csv files they will be written to.
+    pub fn with_journal(mut self, folder_path: &str) -> Self {
+        let journal_path = |channel: &dyn ToString| {
+            format!(
+                "{}/{}/{}.journal",
+                folder_path,
+                self.name,
+                channel.to_string()
+            )
+        };
+        self.channel_datasets = self
+            .channel_datasets
+            .into_iter()
+            .enumerate()
+            .map(|(channel_index, dataset)| dataset.with_journal(journal_path(&channel_index)))
+            .collect();
+        self.summary_dataset = self.summary_dataset.with_journal(journal_path(&"summary"));
+        self
+    }
+
+    /// The inverter as reported by the DTU's inverter list.
+    pub fn inverter(&self) -> &Inverter {
+        &self.original_inverter
+    }
+
+    /// All datasets of the inverter, starting with the summary.
+    pub fn datasets(&self) -> impl Iterator<Item = (Channel, &Dataset)> {
+        once((Channel::Summary, &self.summary_dataset)).chain(
+            self.channel_datasets
+                .iter()
+                .enumerate()
+                .map(|(channel_index, dataset)| (Channel::Input(channel_index as u8), dataset)),
+        )
+    }
+
+    pub async fn save(&mut self, storage: &mut dyn StorageBackend) -> Result<(), ErrorKind> {
         for (channel_index, dataset) in self.channel_datasets.iter_mut().enumerate() {
-            dataset.save_to_csv(folder_path, &self.name, channel_index as u8)?;
+            dataset
+                .save_to(
+                    storage,
+                    &self.original_inverter,
+                    &Channel::Input(channel_index as u8),
+                )
+                .await?;
         }
         self.summary_dataset
-            .save_to_csv(folder_path, &self.name, "summary")?;
-        Ok(())
+            .save_to(storage, &self.original_inverter, &Channel::Summary)
+            .await
+    }
+
+    pub async fn save_to_csv(&mut self, folder_path: &str) -> Result<(), ErrorKind> {
+        self.save(&mut CsvStorage::new(folder_path.to_string()))
+            .await
     }
 
     pub fn is_due(&self) -> bool {
         match self.next_crawl_at {
-            Some(next_crawl_at) => next_crawl_at < Local::now(),
+            Some(next_crawl_at) => next_crawl_at < self.api.now(),
             None => true,
         }
     }
 
+    /// Fetch today's sunrise and sunset from the DTU, keeping the previous
+    /// times if that fails. Tried at most once per day, also while the DTU
+    /// reports none.
+    async fn refresh_sun_times(&mut self, now: DateTime<Local>) {
+        let today = now.date_naive();
+        let current = self.sun_times.iter().any(|sun| !sun.is_outdated(now));
+        if current || self.sun_times_checked_on == Some(today) {
+            return;
+        }
+        self.sun_times_checked_on = Some(today);
+        match self.api.get_index().await {
+            Ok(index) => {
+                if let Some(sun_times) = SunTimes::from_index(&index) {
+                    log::debug!(
+                        "Sunrise at {}, sunset at {}",
+                        sun_times.sunrise,
+                        sun_times.sunset
+                    );
+                    self.sun_times = Some(sun_times);
+                }
+            }
+            Err(e) => log::warn!("Could not refresh sunrise and sunset: {}", e),
+        }
+    }
+
+    /// Push the next crawl back after a failed one at `now`, see [`backoff`].
+    pub fn back_off(&mut self, now: DateTime<Local>) {
+        self.failures += 1;
+        let interval = self.crawling_interval.unwrap_or(self.schedule.day_interval);
+        self.next_crawl_at = Some(now + backoff(interval, self.failures));
+    }
+
     pub async fn crawl(&mut self) -> Result<(), ErrorKind> {
-        let default_interval = Duration::from_secs(
-            env::var("CRAWLING_INTERVAL")
-                .unwrap_or("60".to_string())
-                .parse::<u64>()
-                .unwrap_or(60),
-        );
+        let live = self.api.get_live().await?;
+        self.crawl_with(&live, self.api.cycle_time()).await
+    }
+
+    /// Crawl the inverter's values using `live`, fetched once for all
+    /// inverters of a crawling cycle, and store them with `crawling_time` so
+    /// the rows of the cycle line up.
+    pub async fn crawl_with(
+        &mut self,
+        live: &Live,
+        crawling_time: DateTime<Local>,
+    ) -> Result<(), ErrorKind> {
         log::info!("Crawling Inverter: {}", self.id);
-        let fields: Vec<HashMap<String, UnitValue<f32>>> = self
+        let status = self
             .api
-            .get_inverter_fields(self.original_inverter.clone(), None)
+            .get_inverter_status(self.original_inverter.clone())
             .await?;
+        let fields: Vec<HashMap<String, UnitValue<f32>>> = status.fields(
+            live,
+            self.channel_count as usize,
+            self.selected_fields.as_deref(),
+        );
+        let (names, units) = self.selected(live.ch0_fld_names.iter(), live.ch0_fld_units.iter());
+        if self.summary_dataset.migrate(&names, &units) {
+            log::warn!("Inverter {} reports different summary fields", self.id);
+        }
+        let (names, units) = self.selected(live.fld_names.iter(), live.fld_units.iter());
+        for dataset in &mut self.channel_datasets {
+            if dataset.migrate(&names, &units) {
+                log::warn!("Inverter {} reports different channel fields", self.id);
+            }
+        }
+        self.generic = Some(live.generic.clone());
+
+        // ts_last_success only advances when the DTU actually heard from the inverter
+        self.is_stale = self.last_success == Some(status.ts_last_success);
+        self.last_success = Some(status.ts_last_success);
+        let measured_at = match status.ts_last_success {
+            0 => None,
+            ts_last_success => Local.timestamp_opt(ts_last_success as i64, 0).single(),
+        };
 
-        let interval = self.crawling_interval.unwrap_or(default_interval);
+        let interval = self.crawling_interval.unwrap_or(self.schedule.day_interval);
 
-        let crawling_time = Local::now();
+        self.refresh_sun_times(crawling_time).await;
         self.crawled_at = Some(crawling_time);
-        self.next_crawl_at = Some(Local::now() + interval);
+        self.failures = 0;
+        let schedule = SchedulePolicy {
+            day_interval: interval,
+            ..self.schedule
+        };
+        self.next_crawl_at = Some(schedule.next_crawl(crawling_time, self.sun_times.as_ref()));
         self.crawling_interval = Some(interval);
 
-        self.summary_dataset.insert_row(&fields[0], &crawling_time);
+        if self.is_stale && !self.keep_stale_rows {
+            log::debug!(
+                "Inverter {} has not reported since {:?}, skipping row",
+                self.id,
+                measured_at
+            );
+            return Ok(());
+        }
+
+        self.summary_dataset
+            .insert_row(&fields[0], &crawling_time, measured_at);
 
         for channel_index in 1..=self.channel_count {
-            self.channel_datasets[channel_index as usize - 1]
-                .insert_row(&fields[channel_index as usize], &crawling_time)
+            self.channel_datasets[channel_index as usize - 1].insert_row(
+                &fields[channel_index as usize],
+                &crawling_time,
+                measured_at,
+            )
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{AhoyApi, Index, InverterList, InverterStatus};
+
+    use async_trait::async_trait;
+
+    /// The offline DTU after its first inverter was deleted: the remaining
+    /// one has id 2.
+    #[derive(Debug)]
+    struct RenumberedDtu(AhoyApi);
+
+    #[async_trait]
+    impl DtuBackend for RenumberedDtu {
+        async fn get_inverter_list(&self) -> Result<InverterList, ErrorKind> {
+            let mut list = self.0.get_inverter_list().await?;
+            list.inverter[0].id = 2;
+            Ok(list)
+        }
+
+        async fn get_live(&self) -> Result<Live, ErrorKind> {
+            self.0.get_live().await
+        }
+
+        async fn get_inverter_status(
+            &self,
+            inverter: Inverter,
+        ) -> Result<InverterStatus, ErrorKind> {
+            self.0
+                .get_inverter_status(Inverter { id: 0, ..inverter })
+                .await
+        }
+
+        async fn get_index(&self) -> Result<Index, ErrorKind> {
+            let mut index = self.0.get_index().await?;
+            index.inverter[0].id = 2;
+            Ok(index)
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_by_id() {
+        let api: Arc<dyn DtuBackend> = Arc::new(RenumberedDtu(AhoyApi::offline()));
+        assert!(CrawledInverter::fetch(api.clone(), 0).await.is_err());
+        let inverter = CrawledInverter::fetch(api, 2).await.unwrap();
+        assert_eq!(inverter.id, 2);
+        assert_eq!(inverter.name, "PV Microinverte");
+    }
+
+    #[tokio::test]
+    async fn sun_times_recover_once_a_day() {
+        let api: Arc<dyn DtuBackend> = Arc::new(AhoyApi::offline());
+        let mut inverter = CrawledInverter::fetch(api.clone(), 0).await.unwrap();
+        let live = api.get_live().await.unwrap();
+        let today = Local::now();
+
+        // the DTU had no location when the inverter was set up
+        inverter.sun_times = None;
+        inverter.crawl_with(&live, today).await.unwrap();
+        assert!(inverter.sun_times.is_none());
+
+        inverter.sun_times_checked_on = today.date_naive().pred_opt();
+        inverter.crawl_with(&live, today).await.unwrap();
+        assert!(inverter.sun_times.is_some());
+        assert_eq!(inverter.sun_times_checked_on, Some(today.date_naive()));
+    }
+}
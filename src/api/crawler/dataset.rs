@@ -1,90 +1,260 @@
 use super::utils::create_file_with_full_path;
-use crate::{ahoy::UnitValue, error_kind::ErrorKind, EmptyField};
+use crate::{
+    ahoy::UnitValue, error_kind::ErrorKind, Channel, EmptyField, Inverter, StorageBackend,
+};
 
 use chrono::{DateTime, Local};
-use csv::Writer;
 
 use serde::{Deserialize, Serialize};
 
-use std::{collections::HashMap, iter::once};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+};
+
+/// The values of one crawl, in the order of [`Dataset::fields`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Row {
+    pub values: Vec<Option<f32>>,
+    /// when the values were fetched from the DTU
+    pub crawled_at: DateTime<Local>,
+    /// when the DTU last heard from the inverter (`InverterStatus.ts_last_success`)
+    #[serde(default)]
+    pub measured_at: Option<DateTime<Local>>,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Dataset {
     fields: Vec<EmptyField>,
-    values: Vec<(Vec<Option<f32>>, DateTime<Local>)>,
+    values: Vec<Row>,
+    /// write-ahead journal of the rows in `values`, one json encoded row per line
+    #[serde(skip)]
+    journal_path: Option<String>,
 }
 
 impl Dataset {
+    /// A dataset with the fields `field_names`. Fields without a unit in
+    /// `field_units` get an empty one.
     pub fn new(field_names: &[String], field_units: &[String]) -> Self {
         let mut fields = Vec::new();
         for (index, fieldname) in field_names.iter().enumerate() {
             fields.push(EmptyField {
                 name: fieldname.clone(),
-                unit: field_units[index].clone(),
+                unit: field_units.get(index).cloned().unwrap_or_default(),
             });
         }
 
         Self {
             fields,
             values: Vec::new(),
+            journal_path: None,
+        }
+    }
+
+    /// Keep a journal of all buffered rows at `journal_path`, so they survive a
+    /// restart. Rows left in the journal by a previous run are loaded back into
+    /// the dataset.
+    pub fn with_journal(mut self, journal_path: String) -> Self {
+        if let Ok(file) = File::open(&journal_path) {
+            for line in BufReader::new(file).lines().map_while(Result::ok) {
+                match serde_json::from_str::<Row>(&line) {
+                    Ok(row) if row.values.len() == self.fields.len() => self.values.push(row),
+                    _ => log::warn!("skipping invalid journal entry in {}", journal_path),
+                }
+            }
+            if !self.values.is_empty() {
+                log::info!("replayed {} rows from {}", self.values.len(), journal_path);
+            }
+        }
+        self.journal_path = Some(journal_path);
+        self
+    }
+
+    pub fn fields(&self) -> &[EmptyField] {
+        &self.fields
+    }
+
+    pub fn rows(&self) -> &[Row] {
+        &self.values
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    fn append_to_journal(&self, row: &Row) -> Result<(), ErrorKind> {
+        let Some(journal_path) = &self.journal_path else {
+            return Ok(());
+        };
+        let mut file = create_file_with_full_path(journal_path.clone(), true, true)?;
+        let line = serde_json::to_string(row)
+            .map_err(|err| ErrorKind::CouldNotWriteToJournal(err.to_string()))?;
+        writeln!(file, "{}", line).map_err(|err| ErrorKind::CouldNotWriteToJournal(err.to_string()))
+    }
+
+    /// Replace the journal with the rows that are still buffered.
+    fn rewrite_journal(&self) -> Result<(), ErrorKind> {
+        let Some(journal_path) = &self.journal_path else {
+            return Ok(());
+        };
+        if self.values.is_empty() {
+            return match fs::remove_file(journal_path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    Err(ErrorKind::CouldNotWriteToJournal(err.to_string()))
+                }
+                _ => Ok(()),
+            };
         }
+        let mut content = String::new();
+        for row in &self.values {
+            content += &serde_json::to_string(row)
+                .map_err(|err| ErrorKind::CouldNotWriteToJournal(err.to_string()))?;
+            content.push('\n');
+        }
+        fs::write(journal_path, content)
+            .map_err(|err| ErrorKind::CouldNotWriteToJournal(err.to_string()))
     }
 
     pub fn insert_row(
         &mut self,
         data: &HashMap<String, UnitValue<f32>>,
         timestamp: &DateTime<Local>,
+        measured_at: Option<DateTime<Local>>,
     ) {
         let mut new_row = Vec::new();
         for key in &self.fields {
             new_row.push(data.get(&key.name).map(|entry| entry.value));
         }
-        self.values.push((new_row, *timestamp));
+        let row = Row {
+            values: new_row,
+            crawled_at: *timestamp,
+            measured_at,
+        };
+        if let Err(err) = self.append_to_journal(&row) {
+            log::error!("could not journal row: {}", err);
+        }
+        self.values.push(row);
     }
 
-    pub fn save_to_csv(
+    /// Switch to the fields the DTU currently reports, e.g. after a firmware
+    /// upgrade added or reordered fields. Buffered rows are remapped by field
+    /// name; values of fields that are no longer reported are dropped.
+    /// Returns whether the fields changed.
+    pub fn migrate(&mut self, field_names: &[String], field_units: &[String]) -> bool {
+        let migrated = Self::new(field_names, field_units);
+        if migrated.fields == self.fields {
+            return false;
+        }
+        let positions: Vec<Option<usize>> = migrated
+            .fields
+            .iter()
+            .map(|field| self.fields.iter().position(|old| old.name == field.name))
+            .collect();
+        for row in &mut self.values {
+            row.values = positions
+                .iter()
+                .map(|position| position.and_then(|position| row.values[position]))
+                .collect();
+        }
+        self.fields = migrated.fields;
+        if let Err(err) = self.rewrite_journal() {
+            log::error!("could not rewrite journal: {}", err);
+        }
+        true
+    }
+
+    /// Write all buffered rows to `storage` and drop them from the dataset
+    /// (and its journal) once they are stored.
+    pub async fn save_to(
         &mut self,
-        folder_path: &str,
-        inverter_name: &str,
-        channel_index: impl ToString,
+        storage: &mut dyn StorageBackend,
+        inverter: &Inverter,
+        channel: &Channel,
     ) -> Result<(), ErrorKind> {
-        let csv_path = format!(
-            "{}/{}/{}.csv",
-            folder_path,
-            inverter_name,
-            channel_index.to_string()
-        );
-        let file = create_file_with_full_path(csv_path, true, true)?;
-        let metadata = file
-            .metadata()
-            .map_err(|err| ErrorKind::CouldNotWriteToCsv(err.to_string()))?;
-
-        let mut writer = Writer::from_writer(file);
-
-        if metadata.len() == 0 {
-            writer
-                .write_record(
-                    once(&"timestamp".to_string())
-                        .chain(self.fields.iter().map(|field| &field.name)),
-                )
-                .map_err(|err| ErrorKind::CouldNotWriteToCsv(err.to_string()))?;
-        }
-        while let Some((row, datetime)) = self.values.first() {
-            writer
-                .write_record(
-                    once(datetime.format("%F %T").to_string()).chain(
-                        row.iter()
-                            .map(|value| match value {
-                                Some(value) => value.to_string(),
-                                None => "".to_string(),
-                            })
-                            .collect::<Vec<_>>(),
-                    ),
-                )
-                .map_err(|err| ErrorKind::CouldNotWriteToCsv(err.to_string()))?;
-            self.values.drain(..1);
+        if self.values.is_empty() {
+            return Ok(());
         }
+        storage.store(inverter, channel, self).await?;
+        self.values.clear();
+        self.rewrite_journal()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        api::test_util::{inverter, TempDir},
+        CsvStorage,
+    };
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn replay_journal() {
+        let temp_dir = TempDir::new("journal");
+        let folder = temp_dir.path();
+        let journal_path = format!("{}/inverter/0.journal", folder);
+        let names = ["P_DC".to_string(), "U_DC".to_string()];
+        let units = ["W".to_string(), "V".to_string()];
+
+        let mut dataset = Dataset::new(&names, &units).with_journal(journal_path.clone());
+        let row = HashMap::from([("P_DC".to_string(), UnitValue::new(12.5, "W".to_string()))]);
+        dataset.insert_row(&row, &Local::now(), None);
+        dataset.insert_row(&row, &Local::now(), None);
+
+        // simulate a restart
+        let mut dataset = Dataset::new(&names, &units).with_journal(journal_path.clone());
+        assert_eq!(dataset.len(), 2);
+        assert_eq!(dataset.values[0].values, vec![Some(12.5), None]);
+
+        let inverter = inverter("inverter", 1);
+        let mut storage = CsvStorage::new(folder.to_string());
+        dataset
+            .save_to(&mut storage, &inverter, &Channel::Input(0))
+            .await
+            .unwrap();
+        assert!(dataset.is_empty());
+        assert!(!Path::new(&journal_path).exists());
+    }
+
+    #[test]
+    fn migrate_fields() {
+        let mut dataset = Dataset::new(
+            &["P_DC".to_string(), "U_DC".to_string()],
+            &["W".to_string(), "V".to_string()],
+        );
+        let row = HashMap::from([
+            ("P_DC".to_string(), UnitValue::new(12.5, "W".to_string())),
+            ("U_DC".to_string(), UnitValue::new(30.0, "V".to_string())),
+        ]);
+        dataset.insert_row(&row, &Local::now(), None);
+
+        assert!(!dataset.migrate(
+            &["P_DC".to_string(), "U_DC".to_string()],
+            &["W".to_string(), "V".to_string()]
+        ));
+        assert!(dataset.migrate(
+            &["U_DC".to_string(), "I_DC".to_string()],
+            &["V".to_string(), "A".to_string()]
+        ));
+        assert_eq!(dataset.fields()[1].name, "I_DC");
+        assert_eq!(dataset.rows()[0].values, vec![Some(30.0), None]);
+    }
 
-        Ok(())
+    #[test]
+    fn missing_units() {
+        let mut dataset = Dataset::new(&["P_DC".to_string()], &[]);
+        assert_eq!(dataset.fields()[0].unit, "");
+        assert!(dataset.migrate(
+            &["P_DC".to_string(), "U_DC".to_string()],
+            &["W".to_string()]
+        ));
+        assert_eq!(dataset.fields()[0].unit, "W");
+        assert_eq!(dataset.fields()[1].unit, "");
     }
 }
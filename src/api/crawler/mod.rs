@@ -1,12 +1,18 @@
 mod ahoy_crawler;
+mod channel;
 mod crawled_inverter;
 mod dataset;
 mod empty_field;
+mod schedule;
 mod utils;
 
-pub use ahoy_crawler::Crawler;
+pub(crate) use ahoy_crawler::dtu_folder;
 pub use ahoy_crawler::Crawler as AhoyCrawler;
+pub use ahoy_crawler::{Crawler, InverterKey, DEFAULT_CONCURRENCY};
+pub use channel::Channel;
 pub use crawled_inverter::CrawledInverter;
-pub use dataset::Dataset;
+pub use dataset::{Dataset, Row};
 pub use empty_field::EmptyField;
-pub use utils::entrypoint;
+pub use schedule::{SchedulePolicy, SunTimes};
+pub(crate) use utils::create_file_with_full_path;
+pub use utils::{crawl_entrypoint, entrypoint};
@@ -0,0 +1,161 @@
+use crate::Index;
+
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+
+use std::time::Duration;
+
+/// Sunrise and sunset as reported by the DTU in `Index`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SunTimes {
+    pub sunrise: DateTime<Local>,
+    pub sunset: DateTime<Local>,
+    /// the DTU talks to the inverters from `sunrise - offset` until `sunset + offset`
+    pub offset: Duration,
+    /// the DTU does not talk to the inverters at night
+    pub night_communication_disabled: bool,
+    /// the day these times were fetched on
+    pub fetched_on: NaiveDate,
+}
+
+impl SunTimes {
+    /// Returns `None` if the DTU has no location configured and therefore
+    /// reports no sunrise/sunset.
+    pub fn from_index(index: &Index) -> Option<Self> {
+        if index.ts_sunrise == 0 || index.ts_sunset == 0 {
+            return None;
+        }
+        let sunrise = Local.timestamp_opt(index.ts_sunrise as i64, 0).single()?;
+        let sunset = Local.timestamp_opt(index.ts_sunset as i64, 0).single()?;
+        Some(Self {
+            sunrise,
+            sunset,
+            // a negative offset only shortens the DTU's day, crawling a little longer is harmless
+            offset: Duration::from_secs(index.ts_offset.max(0) as u64),
+            night_communication_disabled: index.dis_night_comm,
+            // the DTU's clock, so a replayed index is not outdated right away
+            fetched_on: Local
+                .timestamp_opt(index.ts_now as i64, 0)
+                .single()
+                .filter(|_| index.ts_now > 0)
+                .unwrap_or_else(Local::now)
+                .date_naive(),
+        })
+    }
+
+    pub fn is_outdated(&self, now: DateTime<Local>) -> bool {
+        self.fetched_on != now.date_naive()
+    }
+}
+
+/// The longest a failing DTU or inverter is left alone, unless its interval is longer.
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// How long to wait after `failures` consecutive failures: `interval`,
+/// doubled with every further failure.
+pub(crate) fn backoff(interval: Duration, failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+    interval
+        .saturating_mul(factor)
+        .min(MAX_BACKOFF.max(interval))
+}
+
+/// Decides when an inverter is crawled next, depending on the time of day.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SchedulePolicy {
+    /// interval between sunrise and sunset
+    pub day_interval: Duration,
+    /// interval at night, `None` pauses crawling until shortly before sunrise
+    pub night_interval: Option<Duration>,
+    /// how long before sunrise (minus the DTU's offset) crawling resumes
+    pub sunrise_lead: Duration,
+    /// ignore sunrise/sunset and always use `day_interval`
+    pub fixed: bool,
+}
+
+impl SchedulePolicy {
+    pub fn new(day_interval: Duration) -> Self {
+        Self {
+            day_interval,
+            night_interval: Some(Duration::from_secs(15 * 60)),
+            sunrise_lead: Duration::from_secs(10 * 60),
+            fixed: false,
+        }
+    }
+
+    pub fn next_crawl(&self, now: DateTime<Local>, sun: Option<&SunTimes>) -> DateTime<Local> {
+        let during_day = now + self.day_interval;
+        let Some(sun) = sun.filter(|_| !self.fixed) else {
+            return during_day;
+        };
+
+        let mut start = sun.sunrise - sun.offset - self.sunrise_lead;
+        let mut end = sun.sunset + sun.offset;
+        // the reported times may belong to a previous day
+        while end <= now {
+            start += chrono::Duration::days(1);
+            end += chrono::Duration::days(1);
+        }
+
+        if now >= start {
+            return during_day;
+        }
+        match self.night_interval {
+            Some(night_interval) if !sun.night_communication_disabled => {
+                (now + night_interval).min(start)
+            }
+            _ => start,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2024, 6, 1, hour, minute, 0)
+            .single()
+            .unwrap()
+    }
+
+    fn sun(night_communication_disabled: bool) -> SunTimes {
+        SunTimes {
+            sunrise: at(5, 30),
+            sunset: at(21, 0),
+            offset: Duration::ZERO,
+            night_communication_disabled,
+            fetched_on: at(0, 0).date_naive(),
+        }
+    }
+
+    #[test]
+    fn day_and_night() {
+        let policy = SchedulePolicy::new(Duration::from_secs(30));
+        let sun = sun(false);
+
+        assert_eq!(
+            policy.next_crawl(at(12, 0), Some(&sun)),
+            at(12, 0) + Duration::from_secs(30)
+        );
+        assert_eq!(policy.next_crawl(at(22, 0), Some(&sun)), at(22, 15));
+        // never sleep past the start of the day
+        assert_eq!(policy.next_crawl(at(5, 10), Some(&sun)), at(5, 20));
+        assert_eq!(
+            policy.next_crawl(at(12, 0), None),
+            at(12, 0) + Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn pause_without_night_communication() {
+        let policy = SchedulePolicy::new(Duration::from_secs(30));
+        let sun = sun(true);
+
+        assert_eq!(
+            policy.next_crawl(at(22, 0), Some(&sun)),
+            at(5, 20) + chrono::Duration::days(1)
+        );
+        assert_eq!(policy.next_crawl(at(3, 0), Some(&sun)), at(5, 20));
+    }
+}
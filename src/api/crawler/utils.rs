@@ -1,4 +1,6 @@
-use crate::{AhoyApi, Crawler, ErrorKind};
+#[cfg(test)]
+use crate::AhoyApi;
+use crate::{CliOptions, Config, DtuEndpoint, ErrorKind, Metrics};
 
 use chrono::Local;
 
@@ -6,11 +8,14 @@ use dotenv::dotenv;
 use env_logger::{Builder, Target};
 use log::{debug, error, info, warn, LevelFilter};
 
-use tokio::time::{sleep_until, Instant};
+use tokio::{
+    signal,
+    time::{sleep, sleep_until, Instant},
+};
 
 use std::{
-    env,
     fs::{self, File, OpenOptions},
+    future::{pending, Future},
     io::Write,
     path::Path,
     time::Duration,
@@ -38,31 +43,90 @@ pub(crate) fn create_file_with_full_path(
         .write(write)
         .append(append)
         .open(&file_path)
-        .map_err(|e| {
-            env_logger::init();
-            error!("Error opening log file: {:?}, logging to stdout instead", e);
-            ErrorKind::CouldNotOpenFile(file_path.clone())
-        })
+        .map_err(|_| ErrorKind::CouldNotOpenFile(file_path.clone()))
 }
 
 #[cfg(not(test))]
 pub async fn entrypoint() -> Result<(), ErrorKind> {
-    _entrypoint(false).await
+    _entrypoint(&CliOptions::default(), false).await
 }
 #[cfg(test)]
 pub async fn entrypoint(offline: bool) -> Result<(), ErrorKind> {
-    _entrypoint(offline).await
+    _entrypoint(&CliOptions::default(), offline).await
 }
 
-async fn _entrypoint(_offline: bool) -> Result<(), ErrorKind> {
-    dotenv().ok();
+/// Run the `crawl` subcommand: `crawl [--config <path>] [--endpoint <url>]`.
+pub async fn crawl_entrypoint(args: &[String]) -> Result<(), ErrorKind> {
+    _entrypoint(&CliOptions::parse(args, false)?, false).await
+}
+
+/// Resolves once the process is asked to stop, either by SIGINT (ctrl+c) or,
+/// on unix, by SIGTERM as sent by `docker stop`. The signal handlers are
+/// registered immediately, so signals arriving before the future is polled
+/// are not lost.
+#[cfg(unix)]
+fn shutdown_signal() -> impl Future<Output = ()> {
+    use signal::unix::{signal, SignalKind};
+
+    let interrupt = signal(SignalKind::interrupt());
+    let terminate = signal(SignalKind::terminate());
+    async move {
+        match (interrupt, terminate) {
+            (Ok(mut interrupt), Ok(mut terminate)) => {
+                tokio::select! {
+                    _ = interrupt.recv() => {},
+                    _ = terminate.recv() => {},
+                }
+            }
+            (interrupt, terminate) => {
+                error!(
+                    "Could not listen for shutdown signals: {:?} {:?}",
+                    interrupt.err(),
+                    terminate.err()
+                );
+                pending::<()>().await;
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> impl Future<Output = ()> {
+    async {
+        if let Err(err) = signal::ctrl_c().await {
+            error!("Could not listen for ctrl+c: {:?}", err);
+            pending::<()>().await;
+        }
+    }
+}
+
+/// The configured DTUs to crawl.
+#[cfg(not(test))]
+fn backends(config: &Config, _offline: bool) -> Result<Vec<DtuEndpoint>, ErrorKind> {
+    config.connect_dtus()
+}
 
-    match env::var("LOGGING_TARGET") {
-        Ok(target) => {
+/// Tests crawl the Ahoy api, optionally in offline mode.
+#[cfg(test)]
+fn backends(_config: &Config, offline: bool) -> Result<Vec<DtuEndpoint>, ErrorKind> {
+    let mut api = AhoyApi::offline();
+    api.set_offline_mode(offline);
+    Ok(vec![DtuEndpoint::new(
+        String::new(),
+        std::sync::Arc::new(api),
+    )])
+}
+
+/// Log to `target`, `stdout` or the path of a log file, or to stdout if none is configured.
+fn init_logging(target: Option<&str>) {
+    match target {
+        Some(target) => {
             info!("Logging to {}", target);
             if target == "stdout" {
                 env_logger::init();
-            } else if let Ok(target_file) = create_file_with_full_path(target, true, true) {
+            } else if let Ok(target_file) =
+                create_file_with_full_path(target.to_string(), true, true)
+            {
                 let boxed = Box::new(target_file);
                 Builder::new()
                     .target(Target::Pipe(boxed))
@@ -84,64 +148,113 @@ async fn _entrypoint(_offline: bool) -> Result<(), ErrorKind> {
                 error!("Error opening log file, logging to stdout instead");
             }
         }
-        Err(_) => {
+        None => {
             env_logger::init();
             error!("No LOGGING_TARGET specified, logging to stdout");
         }
     }
-    // if LOGGING_TARGET == "stdout" -> log to stdout
+}
+
+async fn _entrypoint(options: &CliOptions, _offline: bool) -> Result<(), ErrorKind> {
+    dotenv().ok();
+
+    let config = match options.load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            env_logger::init();
+            error!("Invalid configuration, see `config check`: {}", e);
+            return Err(e);
+        }
+    };
+    init_logging(config.logging_target.as_deref());
 
     info!("Starting crawler");
 
-    match AhoyApi::from_env() {
-        // the mut is used in tests to allow offline mode
-        #[allow(unused_mut)]
-        Ok(mut api) => {
+    match backends(&config, _offline) {
+        Ok(dtus) => {
             info!("API configured");
 
-            #[cfg(test)]
-            api.set_offline_mode(_offline);
+            let default_interval = Duration::from_secs(config.crawler.interval);
 
-            let default_interval = Duration::from_secs(
-                env::var("CRAWLING_INTERVAL")
-                    .unwrap_or("60".to_string())
-                    .parse::<u64>()
-                    .unwrap_or(60),
-            );
+            let mut crawler = match config.crawler(dtus) {
+                Ok(crawler) => crawler,
+                Err(e) => {
+                    error!("Error configuring the crawler: {}", e);
+                    return Err(e);
+                }
+            };
 
-            let mut crawler = Crawler::from(api);
+            if let Some(address) = config.metrics_address {
+                let metrics = Metrics::new();
+                crawler = crawler.with_metrics(metrics.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = metrics.serve(address).await {
+                        error!("Error serving metrics: {}", e);
+                    }
+                });
+            }
+            let mut shutdown = Box::pin(shutdown_signal());
 
-            loop {
-                if crawler.init().await.is_ok() {
-                    break;
-                } else {
-                    error!("Error initializing crawler");
-                    tokio::time::sleep(default_interval).await;
+            while let Err(e) = crawler.init().await {
+                error!("Error initializing crawler: {}", e);
+                tokio::select! {
+                    _ = sleep(default_interval) => {},
+                    _ = &mut shutdown => {
+                        info!("Shutting down before the crawler was initialized");
+                        return Ok(());
+                    },
                 }
             }
 
             let mut next_sync: u8 = 4;
             info!("Crawler initialized");
             loop {
-                match crawler.crawl_all_due_inverters(next_sync == 0).await {
+                let wake_up_at = match crawler.crawl_all_due_inverters(next_sync == 0).await {
                     Ok(Some(closest_due)) => {
-                        if let Ok(sleep_duration) = (closest_due - Local::now()).to_std() {
+                        if let Ok(sleep_duration) = (closest_due - crawler.now()).to_std() {
                             debug!(
                                 "Successfully crawled all due inverters, sleeping {:?}",
                                 sleep_duration
                             );
 
-                            sleep_until(Instant::now() + sleep_duration).await;
+                            Instant::now() + sleep_duration
                         } else {
-                            warn!("negative duration! sleeping for 1 minute");
-                            tokio::time::sleep(default_interval).await;
+                            // crawling took longer than the interval, or a replayed
+                            // DTU's clock already reached the next captured crawl
+                            debug!("Inverters are already due again");
+                            Instant::now()
                         }
                     }
                     Ok(None) => {
                         warn!("No next due inverters found, sleeping for 1 minute");
-                        tokio::time::sleep(default_interval).await;
+                        Instant::now() + default_interval
+                    }
+                    Err(e) if matches!(e.root(), ErrorKind::CaptureExhausted(_)) => {
+                        info!("Replayed the whole capture, saving buffered rows");
+                        return crawler.save().await;
+                    }
+                    Err(e) => {
+                        error!("Error: {}", e);
+                        // failed inverters are backed off, so this does not poll them right away
+                        match crawler.next_due().map(|due| (due - crawler.now()).to_std()) {
+                            Some(Ok(sleep_duration)) => Instant::now() + sleep_duration,
+                            Some(Err(_)) => Instant::now(),
+                            None => Instant::now() + default_interval,
+                        }
                     }
-                    Err(e) => error!("Error: {:?}", e),
+                };
+                tokio::select! {
+                    _ = sleep_until(wake_up_at) => {},
+                    _ = &mut shutdown => {
+                        info!("Shutting down, saving buffered rows");
+                        return match crawler.save().await {
+                            Ok(()) => Ok(()),
+                            Err(e) => {
+                                error!("Error saving buffered rows: {}", e);
+                                Err(e)
+                            }
+                        };
+                    },
                 }
                 if next_sync == 0 {
                     next_sync = 4;
@@ -151,7 +264,7 @@ async fn _entrypoint(_offline: bool) -> Result<(), ErrorKind> {
             }
         }
         Err(e) => {
-            error!("Error generating Api: {:?}", e);
+            error!("Error generating Api: {}", e);
             Err(e)
         }
     }
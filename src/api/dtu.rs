@@ -0,0 +1,315 @@
+use crate::{
+    config::{env_var, from_str, override_from_env},
+    dtu_folder, AhoyApi, Capture, ErrorKind, HttpConfig, Index, Inverter, InverterList,
+    InverterStatus, Live, OpenDtuApi, ReplayDtu,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+
+use std::{env, fmt::Debug, str::FromStr, sync::Arc};
+
+/// What the crawler needs from a DTU. Every firmware maps its responses into
+/// the models of the Ahoy api, so datasets look the same regardless of the
+/// DTU they were crawled from.
+#[async_trait]
+pub trait DtuBackend: Debug + Send + Sync {
+    /// All inverters configured in the DTU.
+    async fn get_inverter_list(&self) -> Result<InverterList, ErrorKind>;
+
+    /// The field names and units of the summary and the inputs, and
+    /// information about the DTU itself.
+    async fn get_live(&self) -> Result<Live, ErrorKind>;
+
+    /// The current values of `inverter`, in the order of the fields of [`Live`].
+    async fn get_inverter_status(&self, inverter: Inverter) -> Result<InverterStatus, ErrorKind>;
+
+    /// The state of the DTU and its inverters.
+    async fn get_index(&self) -> Result<Index, ErrorKind>;
+
+    /// The time crawls of this DTU are scheduled and stored with. Only a
+    /// replayed DTU runs on a different clock.
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    /// The timestamp of a crawling cycle, read once when the cycle starts
+    /// and stored with all of its rows.
+    fn cycle_time(&self) -> DateTime<Local> {
+        self.now()
+    }
+}
+
+#[async_trait]
+impl DtuBackend for AhoyApi {
+    async fn get_inverter_list(&self) -> Result<InverterList, ErrorKind> {
+        AhoyApi::get_inverter_list(self).await
+    }
+
+    async fn get_live(&self) -> Result<Live, ErrorKind> {
+        AhoyApi::get_live(self).await
+    }
+
+    async fn get_inverter_status(&self, inverter: Inverter) -> Result<InverterStatus, ErrorKind> {
+        AhoyApi::get_inverter_status(self, inverter).await
+    }
+
+    async fn get_index(&self) -> Result<Index, ErrorKind> {
+        AhoyApi::get_index(self).await
+    }
+
+    fn cycle_time(&self) -> DateTime<Local> {
+        AhoyApi::cycle_time(self)
+    }
+}
+
+/// A DTU managed by the crawler. Rows of its inverters are stored below
+/// `{OUT_DIR}/{name}`, or directly in `OUT_DIR` if the name is empty.
+#[derive(Debug, Clone)]
+pub struct DtuEndpoint {
+    pub name: String,
+    pub api: Arc<dyn DtuBackend>,
+}
+
+impl DtuEndpoint {
+    pub fn new(name: String, api: Arc<dyn DtuBackend>) -> Self {
+        Self { name, api }
+    }
+}
+
+/// The firmware of a DTU, or a captured session of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DtuType {
+    #[default]
+    Ahoy,
+    OpenDtu,
+    /// the endpoint is a capture file, see [`ReplayDtu`]
+    Replay,
+}
+
+impl FromStr for DtuType {
+    type Err = ErrorKind;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "ahoy" => Ok(DtuType::Ahoy),
+            "opendtu" => Ok(DtuType::OpenDtu),
+            "replay" => Ok(DtuType::Replay),
+            _ => Err(ErrorKind::InvalidArgument(format!(
+                "unknown DTU type {:?}, expected ahoy, opendtu or replay",
+                value
+            ))),
+        }
+    }
+}
+
+/// A DTU to crawl, a `[[dtu]]` section of the configuration file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DtuConfig {
+    /// the folder its rows are stored in, may be empty for a single DTU
+    pub name: String,
+    /// e.g. `http://192.168.1.20`, or the path of the capture file to replay
+    pub endpoint: String,
+    #[serde(rename = "type", deserialize_with = "from_str")]
+    pub dtu_type: DtuType,
+    /// only needed if the web interface is protected
+    pub password: Option<String>,
+    /// capture every response of an Ahoy DTU to `{capture_dir}/{name}/capture.jsonl`
+    pub capture_dir: Option<String>,
+    /// replay a capture this many times faster, `inf` without waiting
+    pub replay_speed: f64,
+}
+
+impl Default for DtuConfig {
+    fn default() -> Self {
+        Self::new(String::new(), String::new())
+    }
+}
+
+impl DtuConfig {
+    pub fn new(name: String, endpoint: String) -> Self {
+        Self {
+            name,
+            endpoint,
+            dtu_type: DtuType::default(),
+            password: None,
+            capture_dir: None,
+            replay_speed: 1.0,
+        }
+    }
+
+    /// Without `[[dtu]]` sections, configure the DTUs with `DTU_ENDPOINTS`
+    /// (see [`parse_endpoints`]) or `INVERTER_ENDPOINT`, and `DTU_TYPE`
+    /// (`ahoy`, `opendtu` or `replay`), `INVERTER_PASSWORD`, `CAPTURE_DIR` and
+    /// `REPLAY_SPEED`. These variables are rejected if `dtus` is configured,
+    /// as they cannot tell which DTU they belong to.
+    pub fn apply_env(dtus: &mut Vec<DtuConfig>) -> Result<(), ErrorKind> {
+        let set: Vec<&str> = DTU_ENV_VARS
+            .iter()
+            .copied()
+            .filter(|name| env::var_os(name).is_some())
+            .collect();
+        check_env_conflict(dtus, &set)?;
+        if let Some(endpoints) = env_var::<String>("DTU_ENDPOINTS")? {
+            *dtus = parse_endpoints(&endpoints)?
+                .into_iter()
+                .map(|(name, endpoint)| DtuConfig::new(name, endpoint))
+                .collect();
+        } else if let Some(endpoint) = env_var("INVERTER_ENDPOINT")? {
+            *dtus = vec![DtuConfig::new(String::new(), endpoint)];
+        }
+        for dtu in dtus.iter_mut() {
+            override_from_env(&mut dtu.dtu_type, "DTU_TYPE")?;
+            if let Some(password) = env_var::<String>("INVERTER_PASSWORD")? {
+                dtu.password = Some(password).filter(|password| !password.is_empty());
+            }
+            if let Some(capture_dir) = env_var("CAPTURE_DIR")? {
+                dtu.capture_dir = Some(capture_dir);
+            }
+            override_from_env(&mut dtu.replay_speed, "REPLAY_SPEED")?;
+        }
+        Ok(())
+    }
+
+    /// Crawl only the DTU at `endpoint`. A single configured DTU keeps its
+    /// other settings, several are replaced by an unnamed one.
+    pub fn use_endpoint(dtus: &mut Vec<DtuConfig>, endpoint: String) {
+        match dtus.as_mut_slice() {
+            [dtu] => dtu.endpoint = endpoint,
+            _ => *dtus = vec![DtuConfig::new(String::new(), endpoint)],
+        }
+    }
+
+    /// Create the backend of the DTU, whose requests use the timeouts and
+    /// retries of `http`.
+    pub fn connect(&self, http: &HttpConfig) -> Result<Arc<dyn DtuBackend>, ErrorKind> {
+        let endpoint = self.endpoint.clone();
+        let password = self
+            .password
+            .clone()
+            .filter(|password| !password.is_empty());
+        match self.dtu_type {
+            DtuType::Ahoy => {
+                let mut api = AhoyApi::new(endpoint)
+                    .with_client(http.client())
+                    .with_retry_policy(http.retry_policy());
+                if let Some(capture_dir) = &self.capture_dir {
+                    let path = format!("{}/capture.jsonl", dtu_folder(capture_dir, &self.name));
+                    api = api.with_capture(Capture::create(&path)?);
+                }
+                Ok(Arc::new(match password {
+                    Some(password) => api.with_password(password),
+                    None => api,
+                }))
+            }
+            DtuType::OpenDtu => {
+                let api = OpenDtuApi::new(endpoint)
+                    .with_client(http.client())
+                    .with_retry_policy(http.retry_policy());
+                Ok(Arc::new(match password {
+                    Some(password) => api.with_password(password),
+                    None => api,
+                }))
+            }
+            DtuType::Replay => Ok(Arc::new(
+                ReplayDtu::open(&endpoint)?.with_speed(self.replay_speed),
+            )),
+        }
+    }
+}
+
+/// The environment variables that configure DTUs.
+const DTU_ENV_VARS: &[&str] = &[
+    "DTU_ENDPOINTS",
+    "INVERTER_ENDPOINT",
+    "DTU_TYPE",
+    "INVERTER_PASSWORD",
+    "CAPTURE_DIR",
+    "REPLAY_SPEED",
+];
+
+/// Fail if the DTU variables `set` in the environment would override the
+/// `[[dtu]]` sections in `dtus`.
+fn check_env_conflict(dtus: &[DtuConfig], set: &[&str]) -> Result<(), ErrorKind> {
+    match dtus.is_empty() || set.is_empty() {
+        true => Ok(()),
+        false => Err(ErrorKind::InvalidConfig(format!(
+            "{} cannot be combined with [[dtu]] sections, configure the DTUs in the file",
+            set.join(", ")
+        ))),
+    }
+}
+
+/// Why `name` cannot name a DTU, `None` if it can. Names end up in paths.
+pub(crate) fn name_problem(name: &str) -> Option<String> {
+    match name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        true => Some(format!(
+            "{:?} cannot name a DTU, names must not be empty, contain slashes or start with a dot",
+            name
+        )),
+        false => None,
+    }
+}
+
+/// Parse `name=endpoint` pairs separated by commas, e.g.
+/// `garage=http://192.168.1.20,roof=http://192.168.1.21`. Names must be
+/// unique and usable as a directory name.
+fn parse_endpoints(value: &str) -> Result<Vec<(String, String)>, ErrorKind> {
+    let invalid = |problem: String| ErrorKind::InvalidConfig(format!("DTU_ENDPOINTS: {}", problem));
+    let mut endpoints: Vec<(String, String)> = Vec::new();
+    for pair in value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let (name, endpoint) = pair
+            .split_once('=')
+            .ok_or_else(|| invalid(format!("{:?} is not name=endpoint", pair)))?;
+        let (name, endpoint) = (name.trim(), endpoint.trim());
+        if let Some(problem) = name_problem(name) {
+            return Err(invalid(problem));
+        }
+        if endpoint.is_empty() {
+            return Err(invalid(format!("{} has no endpoint", name)));
+        }
+        if endpoints.iter().any(|(other, _)| other == name) {
+            return Err(invalid(format!("{} is listed twice", name)));
+        }
+        endpoints.push((name.to_string(), endpoint.to_string()));
+    }
+    match endpoints.is_empty() {
+        true => Err(invalid("no DTU listed".to_string())),
+        false => Ok(endpoints),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn endpoints() {
+        assert_eq!(
+            parse_endpoints("garage=http://192.168.1.20, roof=http://dtu.local/").unwrap(),
+            vec![
+                ("garage".to_string(), "http://192.168.1.20".to_string()),
+                ("roof".to_string(), "http://dtu.local/".to_string()),
+            ]
+        );
+        assert!(parse_endpoints("garage=http://a,garage=http://b").is_err());
+        assert!(parse_endpoints("http://192.168.1.20").is_err());
+        assert!(parse_endpoints("../roof=http://a").is_err());
+
+        let configured = [DtuConfig::new("roof".to_string(), "http://a".to_string())];
+        assert!(check_env_conflict(&configured, &[]).is_ok());
+        assert!(check_env_conflict(&[], &["INVERTER_ENDPOINT", "DTU_TYPE"]).is_ok());
+        let err = check_env_conflict(&configured, &["INVERTER_ENDPOINT", "DTU_TYPE"]);
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .contains("INVERTER_ENDPOINT, DTU_TYPE cannot be combined"));
+        assert!(parse_endpoints("").is_err());
+    }
+}
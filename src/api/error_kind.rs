@@ -1,12 +1,262 @@
 use serde::{Deserialize, Serialize};
+
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+/// Characters of a response body kept in a [`ErrorKind::ParsingError`].
+const SNIPPET_LENGTH: usize = 200;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ErrorKind {
     EnvVarError,
-    NetworkError,
-    ParsingError,
+    /// the request to `url` did not complete in time
+    Timeout {
+        url: String,
+    },
+    /// `url` could not be reached
+    RequestFailed {
+        url: String,
+        cause: String,
+    },
+    /// `url` answered with an unsuccessful status
+    HttpStatus {
+        url: String,
+        status: u16,
+    },
+    /// the response of `path` could not be deserialized
+    ParsingError {
+        path: String,
+        /// position of the error in the body, 1-based as reported by serde
+        line: usize,
+        column: usize,
+        message: String,
+        /// the start of the body, or the part around `column` for single line bodies
+        snippet: String,
+    },
+    /// the DTU did not report the inverter with the given serial or id
+    InverterNotFound(String),
+    InvalidCommand(String),
+    /// the DTU is password protected and no (valid) session exists
+    PasswordRequired,
+    /// the DTU rejected the configured password
+    AuthenticationFailed,
     CouldNotCreateFolder(String),
     CouldNotCreateFile(String),
     CouldNotOpenFile(String),
+    CouldNotReadCsv(String),
+    InvalidArgument(String),
+    /// the configuration file or an environment variable overriding it is
+    /// invalid, one problem per line
+    InvalidConfig(String),
 
     CouldNotWriteToCsv(String),
+    CouldNotWriteToJournal(String),
+    CouldNotWriteToDatabase(String),
+    CouldNotWriteToParquet(String),
+    CouldNotServeMetrics(String),
+    CouldNotServeMockDtu(String),
+    CouldNotPublish(String),
+    CouldNotApplyRetention(String),
+
+    /// a replayed session has no more responses to `path`
+    CaptureExhausted(String),
+
+    /// crawling the inverter (`dtu/serial`) failed with `source`
+    CrawlFailed {
+        inverter: String,
+        source: Box<ErrorKind>,
+    },
+}
+
+/// Up to [`SNIPPET_LENGTH`] characters of `body` around `column` on `line`.
+fn snippet(body: &str, line: usize, column: usize) -> String {
+    let text = body.lines().nth(line.saturating_sub(1)).unwrap_or(body);
+    let start = column.saturating_sub(SNIPPET_LENGTH / 2);
+    let snippet: String = text.chars().skip(start).take(SNIPPET_LENGTH).collect();
+    let mut result = String::new();
+    if start > 0 {
+        result.push('…');
+    }
+    result.push_str(&snippet);
+    if text.chars().count() > start + SNIPPET_LENGTH {
+        result.push('…');
+    }
+    result
+}
+
+impl ErrorKind {
+    /// The error for a `body` returned by `path` that serde could not deserialize.
+    pub fn parsing(path: &str, body: &str, err: serde_json::Error) -> Self {
+        ErrorKind::ParsingError {
+            path: path.to_string(),
+            line: err.line(),
+            column: err.column(),
+            message: err.to_string(),
+            snippet: snippet(body, err.line(), err.column()),
+        }
+    }
+
+    /// The name of the variant, without its payload.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::EnvVarError => "EnvVarError",
+            ErrorKind::Timeout { .. } => "Timeout",
+            ErrorKind::RequestFailed { .. } => "RequestFailed",
+            ErrorKind::HttpStatus { .. } => "HttpStatus",
+            ErrorKind::ParsingError { .. } => "ParsingError",
+            ErrorKind::InverterNotFound(_) => "InverterNotFound",
+            ErrorKind::InvalidCommand(_) => "InvalidCommand",
+            ErrorKind::PasswordRequired => "PasswordRequired",
+            ErrorKind::AuthenticationFailed => "AuthenticationFailed",
+            ErrorKind::CouldNotCreateFolder(_) => "CouldNotCreateFolder",
+            ErrorKind::CouldNotCreateFile(_) => "CouldNotCreateFile",
+            ErrorKind::CouldNotOpenFile(_) => "CouldNotOpenFile",
+            ErrorKind::CouldNotReadCsv(_) => "CouldNotReadCsv",
+            ErrorKind::InvalidArgument(_) => "InvalidArgument",
+            ErrorKind::InvalidConfig(_) => "InvalidConfig",
+            ErrorKind::CouldNotWriteToCsv(_) => "CouldNotWriteToCsv",
+            ErrorKind::CouldNotWriteToJournal(_) => "CouldNotWriteToJournal",
+            ErrorKind::CouldNotWriteToDatabase(_) => "CouldNotWriteToDatabase",
+            ErrorKind::CouldNotWriteToParquet(_) => "CouldNotWriteToParquet",
+            ErrorKind::CouldNotServeMetrics(_) => "CouldNotServeMetrics",
+            ErrorKind::CouldNotServeMockDtu(_) => "CouldNotServeMockDtu",
+            ErrorKind::CouldNotPublish(_) => "CouldNotPublish",
+            ErrorKind::CouldNotApplyRetention(_) => "CouldNotApplyRetention",
+            ErrorKind::CaptureExhausted(_) => "CaptureExhausted",
+            ErrorKind::CrawlFailed { .. } => "CrawlFailed",
+        }
+    }
+
+    /// The error that caused this one, unwrapping the context added while crawling.
+    pub fn root(&self) -> &ErrorKind {
+        match self {
+            ErrorKind::CrawlFailed { source, .. } => source.root(),
+            error => error,
+        }
+    }
+
+    /// Whether sending the same request again might succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ErrorKind::Timeout { .. } | ErrorKind::RequestFailed { .. } => true,
+            ErrorKind::HttpStatus { status, .. } => *status >= 500 || *status == 429,
+            _ => false,
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::EnvVarError => write!(f, "missing or invalid environment variable"),
+            ErrorKind::Timeout { url } => write!(f, "request to {} timed out", url),
+            ErrorKind::RequestFailed { url, cause } => {
+                write!(f, "request to {} failed: {}", url, cause)
+            }
+            ErrorKind::HttpStatus { url, status } => {
+                write!(f, "{} answered with status {}", url, status)
+            }
+            ErrorKind::ParsingError {
+                path,
+                message,
+                snippet,
+                ..
+            } => write!(
+                f,
+                "could not parse the response of {}: {} in {:?}",
+                path, message, snippet
+            ),
+            ErrorKind::InverterNotFound(inverter) => {
+                write!(f, "the DTU does not know inverter {}", inverter)
+            }
+            ErrorKind::InvalidCommand(reason) => write!(f, "invalid command: {}", reason),
+            ErrorKind::PasswordRequired => write!(f, "the DTU requires a password"),
+            ErrorKind::AuthenticationFailed => write!(f, "the DTU rejected the password"),
+            ErrorKind::CouldNotCreateFolder(path) => write!(f, "could not create folder {}", path),
+            ErrorKind::CouldNotCreateFile(path) => write!(f, "could not create file {}", path),
+            ErrorKind::CouldNotOpenFile(path) => write!(f, "could not open file {}", path),
+            ErrorKind::CouldNotReadCsv(reason) => write!(f, "could not read csv: {}", reason),
+            ErrorKind::InvalidArgument(reason) => write!(f, "invalid argument: {}", reason),
+            ErrorKind::InvalidConfig(problems) => write!(f, "invalid configuration: {}", problems),
+            ErrorKind::CouldNotWriteToCsv(reason) => write!(f, "could not write csv: {}", reason),
+            ErrorKind::CouldNotWriteToJournal(reason) => {
+                write!(f, "could not write journal: {}", reason)
+            }
+            ErrorKind::CouldNotWriteToDatabase(reason) => {
+                write!(f, "could not write to the database: {}", reason)
+            }
+            ErrorKind::CouldNotWriteToParquet(reason) => {
+                write!(f, "could not write parquet: {}", reason)
+            }
+            ErrorKind::CouldNotServeMetrics(reason) => {
+                write!(f, "could not serve metrics: {}", reason)
+            }
+            ErrorKind::CouldNotServeMockDtu(reason) => {
+                write!(f, "could not serve the mock DTU: {}", reason)
+            }
+            ErrorKind::CouldNotPublish(reason) => write!(f, "could not publish: {}", reason),
+            ErrorKind::CouldNotApplyRetention(reason) => {
+                write!(f, "could not apply retention: {}", reason)
+            }
+            ErrorKind::CaptureExhausted(path) => {
+                write!(f, "the capture has no more responses to {}", path)
+            }
+            ErrorKind::CrawlFailed { inverter, source } => {
+                write!(f, "crawling inverter {} failed: {}", inverter, source)
+            }
+        }
+    }
+}
+
+impl Error for ErrorKind {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ErrorKind::CrawlFailed { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parsing_error() {
+        let body = format!(
+            "{{\"ch\":[[1,2],[3,\"x\"]],\"pad\":\"{}\"}}",
+            "a".repeat(300)
+        );
+        let err = serde_json::from_str::<serde_json::Value>(&body[..40]).unwrap_err();
+        let error = ErrorKind::parsing("/api/inverter/id/0", &body[..40], err);
+        let ErrorKind::ParsingError {
+            path,
+            line,
+            column,
+            snippet,
+            ..
+        } = &error
+        else {
+            panic!("{:?}", error);
+        };
+        assert_eq!(path, "/api/inverter/id/0");
+        assert_eq!((*line, *column), (1, 40));
+        assert_eq!(snippet, &body[..40]);
+        assert!(error
+            .to_string()
+            .starts_with("could not parse the response of /api/inverter/id/0: EOF"));
+
+        let long = super::snippet(&body, 1, 200);
+        assert!(long.starts_with('…') && long.ends_with('…'));
+        assert_eq!(long.chars().count(), SNIPPET_LENGTH + 2);
+
+        let crawl_failed = ErrorKind::CrawlFailed {
+            inverter: "roof/114184511809".to_string(),
+            source: Box::new(error),
+        };
+        assert_eq!(crawl_failed.root().name(), "ParsingError");
+        assert!(crawl_failed.source().is_some());
+    }
 }
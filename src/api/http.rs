@@ -0,0 +1,258 @@
+use crate::{config::override_from_env, ErrorKind};
+
+use reqwest::{Client, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A client that gives up connecting after `connect_timeout` and on the whole
+/// request after `timeout`. Clones share the same connection pool.
+pub fn http_client(connect_timeout: Duration, timeout: Duration) -> Client {
+    Client::builder()
+        .connect_timeout(connect_timeout)
+        .timeout(timeout)
+        .build()
+        .unwrap_or_default()
+}
+
+/// Timeouts and retries of the requests to a DTU, the `[http]` section of the
+/// configuration file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// seconds
+    pub connect_timeout: u64,
+    /// seconds
+    pub request_timeout: u64,
+    /// attempts after the first one, 0 disables retrying
+    pub retries: u32,
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        let retry = RetryPolicy::default();
+        Self {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT.as_secs(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT.as_secs(),
+            retries: retry.max_retries,
+            retry_base_delay_ms: retry.base_delay.as_millis() as u64,
+            retry_max_delay_ms: retry.max_delay.as_millis() as u64,
+        }
+    }
+}
+
+impl HttpConfig {
+    /// Override the settings with `CONNECT_TIMEOUT`, `REQUEST_TIMEOUT`,
+    /// `REQUEST_RETRIES`, `RETRY_BASE_DELAY_MS` and `RETRY_MAX_DELAY_MS`.
+    pub fn apply_env(&mut self) -> Result<(), ErrorKind> {
+        override_from_env(&mut self.connect_timeout, "CONNECT_TIMEOUT")?;
+        override_from_env(&mut self.request_timeout, "REQUEST_TIMEOUT")?;
+        override_from_env(&mut self.retries, "REQUEST_RETRIES")?;
+        override_from_env(&mut self.retry_base_delay_ms, "RETRY_BASE_DELAY_MS")?;
+        override_from_env(&mut self.retry_max_delay_ms, "RETRY_MAX_DELAY_MS")
+    }
+
+    /// What is wrong with the settings, see [`Config::problems`](crate::Config::problems).
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.connect_timeout == 0 || self.request_timeout == 0 {
+            problems.push("http timeouts must be at least 1 second".to_string());
+        }
+        if self.retry_base_delay_ms > self.retry_max_delay_ms {
+            problems.push(format!(
+                "http.retry_base_delay_ms ({}) is larger than http.retry_max_delay_ms ({})",
+                self.retry_base_delay_ms, self.retry_max_delay_ms
+            ));
+        }
+        problems
+    }
+
+    pub fn client(&self) -> Client {
+        http_client(
+            Duration::from_secs(self.connect_timeout),
+            Duration::from_secs(self.request_timeout),
+        )
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.retries,
+            base_delay: Duration::from_millis(self.retry_base_delay_ms),
+            max_delay: Duration::from_millis(self.retry_max_delay_ms),
+        }
+    }
+}
+
+/// Map a failed request to `url` to the matching error.
+pub(crate) fn request_error(url: &str, err: reqwest::Error) -> ErrorKind {
+    if err.is_timeout() {
+        ErrorKind::Timeout {
+            url: url.to_string(),
+        }
+    } else if let Some(status) = err.status() {
+        ErrorKind::HttpStatus {
+            url: url.to_string(),
+            status: status.as_u16(),
+        }
+    } else {
+        ErrorKind::RequestFailed {
+            url: url.to_string(),
+            cause: err.to_string(),
+        }
+    }
+}
+
+/// An error for an unsuccessful `status`, `None` for a success.
+pub(crate) fn status_error(url: &str, status: StatusCode) -> Option<ErrorKind> {
+    match status.is_success() {
+        true => None,
+        false => Some(ErrorKind::HttpStatus {
+            url: url.to_string(),
+            status: status.as_u16(),
+        }),
+    }
+}
+
+/// Deserialize the `body` returned by `path`.
+pub(crate) fn parse_response<T: DeserializeOwned>(path: &str, body: &str) -> Result<T, ErrorKind> {
+    serde_json::from_str(body).map_err(|err| ErrorKind::parsing(path, body, err))
+}
+
+/// How often and how long to wait before a failed request is sent again. The
+/// delay doubles with every attempt, up to `max_delay`, and is jittered so
+/// several crawlers do not hammer a recovering DTU in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// attempts after the first one, 0 disables retrying
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// The delay before retry number `attempt` (starting at 0): a random
+    /// duration between half and all of `base_delay * 2^attempt`, capped at
+    /// `max_delay`.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        // every RandomState is seeded randomly, which is all the randomness needed here
+        let random = RandomState::new().build_hasher().finish();
+        let jitter = backoff / 2;
+        let jitter_millis = jitter.as_millis() as u64;
+        let offset = match jitter_millis {
+            0 => Duration::ZERO,
+            millis => Duration::from_millis(random % (millis + 1)),
+        };
+        backoff - jitter + offset
+    }
+
+    /// Run `request` until it succeeds, fails with an error that is not worth
+    /// retrying or the retries are used up.
+    pub async fn run<T, F, Fut>(&self, mut request: F) -> Result<T, ErrorKind>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, ErrorKind>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match request().await {
+                Err(err) if err.is_retryable() && attempt < self.max_retries => {
+                    let delay = self.delay(attempt);
+                    log::warn!("{}, retrying in {:?}", err, delay);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+        };
+        for attempt in 0..5 {
+            let full = Duration::from_millis(100 * 2u64.pow(attempt)).min(policy.max_delay);
+            let delay = policy.delay(attempt);
+            assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_until_success() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        };
+        let attempts = AtomicU32::new(0);
+        let result = policy
+            .run(|| async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(ErrorKind::HttpStatus {
+                        url: "http://dtu/api/live".to_string(),
+                        status: 503,
+                    }),
+                    _ => Ok(()),
+                }
+            })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // a client error will not go away by asking again
+        attempts.store(0, Ordering::SeqCst);
+        let result: Result<(), _> = policy
+            .run(|| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(ErrorKind::HttpStatus {
+                    url: "http://dtu/api/live".to_string(),
+                    status: 404,
+                })
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
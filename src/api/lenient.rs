@@ -0,0 +1,136 @@
+// Deserializers for values whose type differs between firmware versions,
+// e.g. `"interval": "30"` in one version and `"interval": 30` in the next.
+// A `null` becomes the default of the type.
+
+use serde::{
+    de::{DeserializeOwned, Error},
+    Deserialize, Deserializer,
+};
+use serde_json::Value;
+
+use std::{fmt::Display, str::FromStr};
+
+fn number_from<T, E>(value: Value) -> Result<T, E>
+where
+    T: DeserializeOwned + FromStr + Default,
+    T::Err: Display,
+    E: Error,
+{
+    match value {
+        Value::Null => Ok(T::default()),
+        Value::String(text) if text.trim().is_empty() => Ok(T::default()),
+        Value::String(text) => text.trim().parse().map_err(E::custom),
+        Value::Bool(value) => T::deserialize(Value::from(value as u8)).map_err(E::custom),
+        value => T::deserialize(value).map_err(E::custom),
+    }
+}
+
+/// A number that may also be sent as a string or a bool.
+pub(crate) fn number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + FromStr + Default,
+    T::Err: Display,
+{
+    number_from(Value::deserialize(deserializer)?)
+}
+
+/// A list of numbers that may also be sent as strings.
+pub(crate) fn numbers<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + FromStr + Default,
+    T::Err: Display,
+{
+    match Value::deserialize(deserializer)? {
+        Value::Array(values) => values.into_iter().map(number_from).collect(),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// A list of numbers where `null` marks a missing value.
+pub(crate) fn optional_numbers<'de, D, T>(deserializer: D) -> Result<Vec<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + FromStr + Default,
+    T::Err: Display,
+{
+    match Value::deserialize(deserializer)? {
+        Value::Array(values) => values
+            .into_iter()
+            .map(|value| match value {
+                Value::Null => Ok(None),
+                value => number_from(value).map(Some),
+            })
+            .collect(),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Rows of measurements. Values that are `null` or not a number at all become
+/// `NaN`, so they are left out like values the DTU did not report.
+pub(crate) fn measurements<'de, D>(deserializer: D) -> Result<Vec<Vec<f32>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Value::Array(rows) = Value::deserialize(deserializer)? else {
+        return Ok(Vec::new());
+    };
+    Ok(rows
+        .into_iter()
+        .map(|row| match row {
+            Value::Array(values) => values
+                .into_iter()
+                .map(|value| match value {
+                    Value::Number(number) => number.as_f64().map_or(f32::NAN, |v| v as f32),
+                    Value::String(text) => text.trim().parse().unwrap_or(f32::NAN),
+                    _ => f32::NAN,
+                })
+                .collect(),
+            _ => Vec::new(),
+        })
+        .collect())
+}
+
+/// A string that may also be sent as a number or a bool.
+pub(crate) fn text<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Null => String::new(),
+        Value::String(text) => text,
+        value => value.to_string(),
+    })
+}
+
+/// A serial number, sent as a string or a number. It identifies the
+/// inverter, so unlike other values it must not be missing or empty.
+pub(crate) fn serial<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Value::deserialize(deserializer)? {
+        Value::String(text) if !text.trim().is_empty() => Ok(text),
+        Value::Number(number) => Ok(number.to_string()),
+        other => Err(D::Error::custom(format!("invalid serial {}", other))),
+    }
+}
+
+/// A bool that may also be sent as `0`/`1` or as a string.
+pub(crate) fn boolean<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Value::deserialize(deserializer)? {
+        Value::Null => Ok(false),
+        Value::Bool(value) => Ok(value),
+        Value::Number(number) => Ok(number.as_f64().is_some_and(|value| value != 0.0)),
+        Value::String(text) => match text.trim() {
+            "true" | "1" => Ok(true),
+            "false" | "0" | "" => Ok(false),
+            other => Err(D::Error::custom(format!("invalid bool {:?}", other))),
+        },
+        other => Err(D::Error::custom(format!("invalid bool {}", other))),
+    }
+}
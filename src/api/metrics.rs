@@ -0,0 +1,274 @@
+use crate::{Channel, CrawledInverter, ErrorKind, Generic};
+
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
+#[derive(Debug, Clone)]
+struct FieldValue {
+    channel: Channel,
+    channel_name: String,
+    field: String,
+    unit: String,
+    value: f32,
+}
+
+#[derive(Debug, Clone)]
+struct InverterMetrics {
+    dtu: String,
+    name: String,
+    serial: String,
+    last_crawl: Option<i64>,
+    values: Vec<FieldValue>,
+}
+
+#[derive(Debug, Default)]
+struct MetricsState {
+    inverters: BTreeMap<(String, u8), InverterMetrics>,
+    errors: BTreeMap<&'static str, u64>,
+    /// by DTU name
+    generic: BTreeMap<String, Generic>,
+}
+
+/// The latest crawled values and crawler statistics, rendered in the
+/// prometheus text format. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    state: Arc<RwLock<MetricsState>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the values of `inverter` with the latest row of each of its datasets.
+    pub fn record_crawl(&self, inverter: &CrawledInverter) {
+        let mut values = Vec::new();
+        for (channel, dataset) in inverter.datasets() {
+            let Some(row) = dataset.rows().last() else {
+                continue;
+            };
+            for (field, value) in dataset.fields().iter().zip(&row.values) {
+                if let Some(value) = value {
+                    values.push(FieldValue {
+                        channel,
+                        channel_name: channel.name(inverter.inverter()).unwrap_or("").to_string(),
+                        field: field.name.clone(),
+                        unit: field.unit.clone(),
+                        value: *value,
+                    });
+                }
+            }
+        }
+
+        let mut state = self.state.write().unwrap();
+        state.inverters.insert(
+            (inverter.dtu.clone(), inverter.id),
+            InverterMetrics {
+                dtu: inverter.dtu.clone(),
+                name: inverter.name.clone(),
+                serial: inverter.inverter().serial.clone(),
+                last_crawl: inverter.crawled_at.map(|crawled_at| crawled_at.timestamp()),
+                values,
+            },
+        );
+        if let Some(generic) = &inverter.generic {
+            state.generic.insert(inverter.dtu.clone(), generic.clone());
+        }
+    }
+
+    pub fn record_error(&self, error: &ErrorKind) {
+        *self
+            .state
+            .write()
+            .unwrap()
+            .errors
+            .entry(error.root().name())
+            .or_default() += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let state = self.state.read().unwrap();
+        let mut out = String::new();
+
+        out += "# HELP ahoy_field_value Latest value of a field reported by the DTU\n";
+        out += "# TYPE ahoy_field_value gauge\n";
+        for ((_, id), inverter) in &state.inverters {
+            for value in &inverter.values {
+                let _ = writeln!(
+                    out,
+                    "ahoy_field_value{{{}inverter_id=\"{}\",inverter=\"{}\",serial=\"{}\",channel=\"{}\",channel_name=\"{}\",field=\"{}\",unit=\"{}\"}} {}",
+                    dtu_label(&inverter.dtu),
+                    id,
+                    escape(&inverter.name),
+                    escape(&inverter.serial),
+                    value.channel,
+                    escape(&value.channel_name),
+                    escape(&value.field),
+                    escape(&value.unit),
+                    value.value
+                );
+            }
+        }
+
+        out += "# HELP ahoy_last_crawl_timestamp_seconds Time of the last successful crawl\n";
+        out += "# TYPE ahoy_last_crawl_timestamp_seconds gauge\n";
+        for ((_, id), inverter) in &state.inverters {
+            if let Some(last_crawl) = inverter.last_crawl {
+                let _ = writeln!(
+                    out,
+                    "ahoy_last_crawl_timestamp_seconds{{{}inverter_id=\"{}\",inverter=\"{}\",serial=\"{}\"}} {}",
+                    dtu_label(&inverter.dtu),
+                    id,
+                    escape(&inverter.name),
+                    escape(&inverter.serial),
+                    last_crawl
+                );
+            }
+        }
+
+        out += "# HELP ahoy_errors_total Errors while crawling, by kind\n";
+        out += "# TYPE ahoy_errors_total counter\n";
+        for (kind, count) in &state.errors {
+            let _ = writeln!(out, "ahoy_errors_total{{kind=\"{}\"}} {}", kind, count);
+        }
+
+        if !state.generic.is_empty() {
+            out += "# HELP ahoy_wifi_rssi_dbm Wifi signal strength of the DTU\n";
+            out += "# TYPE ahoy_wifi_rssi_dbm gauge\n";
+            for (dtu, generic) in &state.generic {
+                let _ = writeln!(
+                    out,
+                    "ahoy_wifi_rssi_dbm{} {}",
+                    dtu_labels(dtu),
+                    generic.wifi_rssi
+                );
+            }
+            out += "# HELP ahoy_dtu_uptime_seconds Uptime of the DTU\n";
+            out += "# TYPE ahoy_dtu_uptime_seconds gauge\n";
+            for (dtu, generic) in &state.generic {
+                let _ = writeln!(
+                    out,
+                    "ahoy_dtu_uptime_seconds{} {}",
+                    dtu_labels(dtu),
+                    generic.ts_uptime
+                );
+            }
+            out += "# HELP ahoy_dtu_info Version information of the DTU\n";
+            out += "# TYPE ahoy_dtu_info gauge\n";
+            for (dtu, generic) in &state.generic {
+                let _ = writeln!(
+                    out,
+                    "ahoy_dtu_info{{{}version=\"{}\",build=\"{}\",esp_type=\"{}\"}} 1",
+                    dtu_label(dtu),
+                    escape(&generic.version),
+                    escape(&generic.build),
+                    escape(&generic.esp_type)
+                );
+            }
+        }
+
+        out
+    }
+
+    /// Serve the metrics on `GET /metrics` until the server fails.
+    pub async fn serve(self, address: SocketAddr) -> Result<(), ErrorKind> {
+        let make_service = make_service_fn(move |_| {
+            let metrics = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let metrics = metrics.clone();
+                    async move { Ok::<_, Infallible>(metrics.respond(request)) }
+                }))
+            }
+        });
+        log::info!("serving metrics on http://{}/metrics", address);
+        Server::try_bind(&address)
+            .map_err(|err| ErrorKind::CouldNotServeMetrics(err.to_string()))?
+            .serve(make_service)
+            .await
+            .map_err(|err| ErrorKind::CouldNotServeMetrics(err.to_string()))
+    }
+
+    fn respond(&self, request: Request<Body>) -> Response<Body> {
+        let mut response = Response::default();
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/metrics") => {
+                response
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+                *response.body_mut() = Body::from(self.render());
+            }
+            _ => *response.status_mut() = StatusCode::NOT_FOUND,
+        }
+        response
+    }
+}
+
+/// The `dtu` label followed by a comma, empty for an unnamed DTU so a single
+/// DTU is rendered without it.
+fn dtu_label(dtu: &str) -> String {
+    match dtu.is_empty() {
+        true => String::new(),
+        false => format!("dtu=\"{}\",", escape(dtu)),
+    }
+}
+
+/// The `dtu` label as the only label of a metric.
+fn dtu_labels(dtu: &str) -> String {
+    match dtu.is_empty() {
+        true => String::new(),
+        false => format!("{{dtu=\"{}\"}}", escape(dtu)),
+    }
+}
+
+/// Escape a prometheus label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::test_util::crawled_inverter;
+
+    #[tokio::test]
+    async fn render_crawled_inverter() {
+        let inverter = crawled_inverter().await;
+
+        let metrics = Metrics::new();
+        metrics.record_crawl(&inverter);
+        metrics.record_error(&ErrorKind::CrawlFailed {
+            inverter: "114184511809".to_string(),
+            source: Box::new(ErrorKind::Timeout {
+                url: "http://dtu/api/live".to_string(),
+            }),
+        });
+        let rendered = metrics.render();
+
+        assert!(rendered.contains("ahoy_field_value{inverter_id=\"0\",inverter=\"PV Microinverte\",serial=\"114184511809\",channel=\"1\",channel_name=\"B\",field=\"YieldDay\",unit=\"Wh\"} 19\n"));
+        assert!(rendered.contains("ahoy_errors_total{kind=\"Timeout\"} 1\n"));
+        assert!(rendered.contains("ahoy_wifi_rssi_dbm -68\n"));
+
+        metrics.record_crawl(&inverter.with_dtu("roof"));
+        let rendered = metrics.render();
+        assert!(
+            rendered.contains("ahoy_last_crawl_timestamp_seconds{dtu=\"roof\",inverter_id=\"0\",")
+        );
+        assert!(rendered.contains("ahoy_wifi_rssi_dbm{dtu=\"roof\"} -68\n"));
+    }
+}
@@ -0,0 +1,594 @@
+use crate::{config::env_var, ErrorKind, Generic, PvPlant};
+
+use chrono::{DateTime, Local, TimeZone};
+use hyper::{
+    body::to_bytes,
+    header::CONTENT_TYPE,
+    server::conn::{AddrIncoming, AddrStream},
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use serde_json::{json, Value};
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    convert::Infallible,
+    hash::{BuildHasher, Hasher},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Something that goes wrong while the mock DTU answers a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Fault {
+    /// answer only after the timeout delay, longer than a client waits
+    Timeout,
+    /// answer with `500 Internal Server Error`
+    ServerError,
+    /// answer with a truncated body
+    MalformedJson,
+    /// answer with the body of the previous request to the same path, as a
+    /// DTU does that lost the connection to its inverters
+    Stale,
+}
+
+impl FromStr for Fault {
+    type Err = ErrorKind;
+
+    fn from_str(fault: &str) -> Result<Self, Self::Err> {
+        match fault {
+            "timeout" => Ok(Fault::Timeout),
+            "error" => Ok(Fault::ServerError),
+            "malformed" => Ok(Fault::MalformedJson),
+            "stale" => Ok(Fault::Stale),
+            _ => Err(ErrorKind::InvalidArgument(format!(
+                "unknown fault {}",
+                fault
+            ))),
+        }
+    }
+}
+
+/// A fault that hits the next `remaining` requests to paths starting with `path`.
+#[derive(Debug)]
+struct ScheduledFault {
+    path: String,
+    fault: Fault,
+    remaining: usize,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    scheduled: Vec<ScheduledFault>,
+    last_bodies: HashMap<String, String>,
+    requests: HashMap<String, usize>,
+    /// the address and token of the client that logged in last
+    session: Option<(IpAddr, String)>,
+    logins: usize,
+}
+
+/// A simulated Ahoy DTU that serves `/api/index`, `/api/live`,
+/// `/api/inverter/list` and `/api/inverter/id/{n}` over HTTP. The inverters
+/// of a [`PvPlant`] produce power between sunrise and sunset of a simulated
+/// clock, which can run faster than real time, and the DTU polls them every
+/// `interval` seconds.
+#[derive(Debug, Clone)]
+pub struct MockDtu {
+    plant: PvPlant,
+    interval: u64,
+    speed: f64,
+    start: DateTime<Local>,
+    started: Instant,
+    timeout_delay: Duration,
+    fault_rates: Vec<(Fault, f64)>,
+    password: Option<String>,
+    state: Arc<Mutex<MockState>>,
+}
+
+fn random_unit() -> f64 {
+    RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64
+}
+
+impl MockDtu {
+    /// A DTU with `inverters` inverters of `channels` inputs of 400 W each, in
+    /// the local time zone at 48° north. Its clock starts now and runs in real time.
+    pub fn new(inverters: usize, channels: usize) -> Self {
+        Self::with_plant(PvPlant::in_local_time_zone(48.0).with_inverters(inverters, channels, 400))
+    }
+
+    /// A DTU with the inverters of `plant`, whose yield starts on its start day.
+    pub fn with_plant(plant: PvPlant) -> Self {
+        Self {
+            plant,
+            interval: 30,
+            speed: 1.0,
+            start: Local::now(),
+            started: Instant::now(),
+            timeout_delay: Duration::from_secs(60),
+            fault_rates: Vec::new(),
+            password: None,
+            state: Arc::new(Mutex::new(MockState::default())),
+        }
+    }
+
+    /// Read the DTU from `MOCK_INVERTERS` (default 2), `MOCK_CHANNELS`
+    /// (default 2), `MOCK_LATITUDE` and `MOCK_LONGITUDE` (degrees, defaults to
+    /// 48° north in the local time zone), `MOCK_CLOUDINESS` (0 to 1, default
+    /// 0.3), `MOCK_INTERVAL` (seconds between polls, default 30), `MOCK_SPEED`
+    /// (simulated seconds per second, default 1) and `MOCK_FAULTS` (e.g.
+    /// `timeout=0.01,error=0.05,malformed=0.01,stale=0.1`, the share of
+    /// requests that fail that way).
+    pub fn from_env() -> Result<Self, ErrorKind> {
+        let local = PvPlant::in_local_time_zone(48.0);
+        let plant = PvPlant::new(
+            env_var("MOCK_LATITUDE")?.unwrap_or(local.latitude),
+            env_var("MOCK_LONGITUDE")?.unwrap_or(local.longitude),
+        )
+        .with_inverters(
+            env_var("MOCK_INVERTERS")?.unwrap_or(2),
+            env_var("MOCK_CHANNELS")?.unwrap_or(2),
+            400,
+        )
+        .with_cloudiness(env_var("MOCK_CLOUDINESS")?.unwrap_or(0.3));
+        let mut dtu = Self::with_plant(plant)
+            .with_interval(env_var("MOCK_INTERVAL")?.unwrap_or(30))
+            .with_speed(env_var("MOCK_SPEED")?.unwrap_or(1.0));
+        let faults: String = env_var("MOCK_FAULTS")?.unwrap_or_default();
+        for fault in faults.split(',').filter(|fault| !fault.is_empty()) {
+            let invalid = || ErrorKind::InvalidConfig(format!("MOCK_FAULTS: invalid {:?}", fault));
+            let (name, rate) = fault.split_once('=').ok_or_else(invalid)?;
+            let rate = rate.parse().map_err(|_| invalid())?;
+            dtu = dtu.with_fault_rate(name.parse()?, rate);
+        }
+        Ok(dtu)
+    }
+
+    /// The peak power of every input in W.
+    pub fn with_max_power(mut self, max_power: u16) -> Self {
+        self.plant = self.plant.with_max_power(max_power);
+        self
+    }
+
+    /// Seconds between two polls of the inverters, at least 1.
+    pub fn with_interval(mut self, interval: u64) -> Self {
+        self.interval = interval.max(1);
+        self
+    }
+
+    /// Simulated seconds per real second.
+    pub fn with_speed(mut self, speed: f64) -> Self {
+        self.speed = speed.max(0.0);
+        self
+    }
+
+    /// Start the simulated clock at `start` instead of now.
+    pub fn starting_at(mut self, start: DateTime<Local>) -> Self {
+        self.plant = self.plant.starting_on(start.date_naive());
+        self.start = start;
+        self.started = Instant::now();
+        self
+    }
+
+    /// How long a request hit by [`Fault::Timeout`] waits before it is answered.
+    pub fn with_timeout_delay(mut self, delay: Duration) -> Self {
+        self.timeout_delay = delay;
+        self
+    }
+
+    /// Fail the given share (0 to 1) of all requests with `fault`.
+    pub fn with_fault_rate(mut self, fault: Fault, rate: f64) -> Self {
+        self.fault_rates.push((fault, rate.clamp(0.0, 1.0)));
+        self
+    }
+
+    /// Protect the api with `password`. Like Ahoy, a login unlocks the GET
+    /// endpoints for the address it came from, while commands need its token.
+    pub fn with_password(mut self, password: &str) -> Self {
+        self.password = Some(password.to_string());
+        self
+    }
+
+    /// End the session of the client that logged in, as a restarted DTU does.
+    pub fn expire_session(&self) {
+        self.lock().session = None;
+    }
+
+    /// Fail the next `times` requests to paths starting with `path` with `fault`.
+    pub fn inject(&self, path: &str, fault: Fault, times: usize) {
+        self.lock().scheduled.push(ScheduledFault {
+            path: path.to_string(),
+            fault,
+            remaining: times,
+        });
+    }
+
+    /// How often `path` has been requested.
+    pub fn requests(&self, path: &str) -> usize {
+        self.lock().requests.get(path).copied().unwrap_or(0)
+    }
+
+    /// The current time of the simulated clock.
+    pub fn now(&self) -> DateTime<Local> {
+        let elapsed = self.started.elapsed().as_secs_f64() * self.speed;
+        self.start + chrono::Duration::milliseconds((elapsed * 1000.0) as i64)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        // a panicking test must not take the other requests down with it
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The time the DTU last polled its inverters. It does not poll them at
+    /// night, so from sunset to sunrise this is the last sunset.
+    fn last_poll(&self, now: DateTime<Local>) -> DateTime<Local> {
+        let timestamp = now.timestamp();
+        let polled = timestamp - timestamp.rem_euclid(self.interval as i64);
+        let polled = Local.timestamp_opt(polled, 0).single().unwrap_or(now);
+        let today = now.date_naive();
+        // polar days and nights have no sunset to stop at
+        let Some((sunrise, sunset)) = self.plant.sunrise_sunset(today) else {
+            return polled;
+        };
+        if now < sunrise {
+            return self
+                .plant
+                .sunrise_sunset(today.pred_opt().unwrap_or(today))
+                .map_or(polled, |(_, sunset)| sunset);
+        }
+        polled.min(sunset)
+    }
+
+    fn generic(&self, now: DateTime<Local>) -> Generic {
+        Generic {
+            wifi_rssi: -64,
+            ts_uptime: (now - self.start).num_seconds().max(0) as u64 + 3600,
+            ts_now: now.timestamp().max(0) as u64,
+            version: "0.8.83".to_string(),
+            build: "mock".to_string(),
+            menu_mask: 61,
+            esp_type: "ESP32".to_string(),
+            ..Generic::default()
+        }
+    }
+
+    fn inverter_list(&self) -> Value {
+        json!(self.plant.inverter_list(self.interval))
+    }
+
+    fn inverter(&self, id: usize, now: DateTime<Local>) -> Option<Value> {
+        Some(json!(self.plant.status(id, self.last_poll(now))?))
+    }
+
+    fn live(&self, now: DateTime<Local>) -> Value {
+        json!(self.plant.live(self.generic(now), self.interval as u32))
+    }
+
+    fn index(&self, now: DateTime<Local>) -> Value {
+        let polled = self.last_poll(now);
+        let available = self.plant.sun_position(now).elevation > 0.0;
+        let inverters: Vec<Value> = (0..self.plant.inverters.len())
+            .filter_map(|id| {
+                let status = self.plant.status(id, polled)?;
+                Some(json!({
+                    "enabled": true,
+                    "id": id,
+                    "name": status.name,
+                    "version": status.version,
+                    "is_avail": available,
+                    "is_producing": status.status > 0,
+                    "ts_last_success": status.ts_last_success
+                }))
+            })
+            .collect();
+        // a DTU without a location reports neither sunrise nor sunset
+        let (sunrise, sunset) = self
+            .plant
+            .sunrise_sunset(now.date_naive())
+            .map_or((0, 0), |(sunrise, sunset)| {
+                (sunrise.timestamp(), sunset.timestamp())
+            });
+        json!({
+            "generic": self.generic(now),
+            "ts_now": now.timestamp(),
+            "ts_sunrise": sunrise,
+            "ts_sunset": sunset,
+            "ts_offset": 0,
+            "disNightComm": true,
+            "inverter": inverters,
+            "warnings": [],
+            "infos": []
+        })
+    }
+
+    /// The body the DTU answers `path` with, `None` for unknown paths.
+    fn body(&self, path: &str) -> Option<String> {
+        let now = self.now();
+        let body = match path {
+            "/api/index" => self.index(now),
+            "/api/live" => self.live(now),
+            "/api/inverter/list" => self.inverter_list(),
+            _ => {
+                let id = path.strip_prefix("/api/inverter/id/")?.parse().ok()?;
+                self.inverter(id, now)?
+            }
+        };
+        Some(body.to_string())
+    }
+
+    /// The fault hitting the next request to `path`, scheduled ones first.
+    fn next_fault(&self, state: &mut MockState, path: &str) -> Option<Fault> {
+        let scheduled = state
+            .scheduled
+            .iter_mut()
+            .find(|scheduled| scheduled.remaining > 0 && path.starts_with(&scheduled.path));
+        if let Some(scheduled) = scheduled {
+            scheduled.remaining -= 1;
+            return Some(scheduled.fault);
+        }
+        self.fault_rates
+            .iter()
+            .find(|(_, rate)| random_unit() < *rate)
+            .map(|(fault, _)| *fault)
+    }
+
+    /// Answer a command posted to `/api/ctrl` by the client at `remote`.
+    async fn control(&self, request: Request<Body>, remote: IpAddr) -> Value {
+        let body = to_bytes(request.into_body()).await.unwrap_or_default();
+        let command: Value = serde_json::from_slice(&body).unwrap_or_default();
+        let mut state = self.lock();
+        if command["cmd"] == "auth" {
+            if self.password.is_some() && command["val"] != json!(self.password) {
+                return json!({"success": false, "error": "ERR_AUTH"});
+            }
+            state.logins += 1;
+            let token = format!("{:08X}", state.logins);
+            state.session = Some((remote, token.clone()));
+            return json!({"success": true, "token": token});
+        }
+        let authorized = match &state.session {
+            Some((_, token)) => command["token"] == json!(token),
+            None => false,
+        };
+        match self.password.is_none() || authorized {
+            true => json!({"success": true}),
+            false => json!({"success": false, "error": "ERR_PROTECTED"}),
+        }
+    }
+
+    /// Whether the client at `remote` has to log in before reading the api.
+    fn is_protected(&self, remote: IpAddr) -> bool {
+        self.password.is_some()
+            && !matches!(self.lock().session, Some((address, _)) if address == remote)
+    }
+
+    async fn respond(&self, request: Request<Body>, remote: IpAddr) -> Response<Body> {
+        let path = request.uri().path().to_string();
+        if path == "/api/ctrl" {
+            *self.lock().requests.entry(path).or_default() += 1;
+            return Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(self.control(request, remote).await.to_string()))
+                .unwrap_or_default();
+        }
+        if self.is_protected(remote) {
+            *self.lock().requests.entry(path).or_default() += 1;
+            return Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from("{\"error\":\"ERR_PROTECTED\"}"))
+                .unwrap_or_default();
+        }
+        let fault = {
+            let mut state = self.lock();
+            *state.requests.entry(path.clone()).or_default() += 1;
+            self.next_fault(&mut state, &path)
+        };
+        log::debug!("mock DTU answering {} with {:?}", path, fault);
+
+        if fault == Some(Fault::Timeout) {
+            tokio::time::sleep(self.timeout_delay).await;
+        }
+        let Some(mut body) = self.body(&path) else {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap_or_default();
+        };
+        match fault {
+            Some(Fault::ServerError) => {
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from("Internal Server Error"))
+                    .unwrap_or_default();
+            }
+            Some(Fault::MalformedJson) => {
+                body = body.chars().take(body.chars().count() / 2).collect();
+            }
+            Some(Fault::Stale) => {
+                if let Some(last_body) = self.lock().last_bodies.get(&path) {
+                    body = last_body.clone();
+                }
+            }
+            Some(Fault::Timeout) | None => {
+                self.lock().last_bodies.insert(path, body.clone());
+            }
+        }
+        Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap_or_default()
+    }
+
+    async fn run(self, incoming: AddrIncoming) -> Result<(), ErrorKind> {
+        let make_service = make_service_fn(move |connection: &AddrStream| {
+            let dtu = self.clone();
+            let remote = connection.remote_addr().ip();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let dtu = dtu.clone();
+                    async move { Ok::<_, Infallible>(dtu.respond(request, remote).await) }
+                }))
+            }
+        });
+        Server::builder(incoming)
+            .serve(make_service)
+            .await
+            .map_err(|err| ErrorKind::CouldNotServeMockDtu(err.to_string()))
+    }
+
+    /// Serve the DTU on `address` until the server fails.
+    pub async fn serve(self, address: SocketAddr) -> Result<(), ErrorKind> {
+        let incoming = AddrIncoming::bind(&address)
+            .map_err(|err| ErrorKind::CouldNotServeMockDtu(err.to_string()))?;
+        log::info!("serving a mock DTU on http://{}", address);
+        self.run(incoming).await
+    }
+
+    /// Serve the DTU on a free local port in the background and return the
+    /// endpoint to crawl, e.g. `http://127.0.0.1:43117`.
+    pub fn spawn(&self) -> Result<String, ErrorKind> {
+        let incoming = AddrIncoming::bind(&([127, 0, 0, 1], 0).into())
+            .map_err(|err| ErrorKind::CouldNotServeMockDtu(err.to_string()))?;
+        let endpoint = format!("http://{}", incoming.local_addr());
+        let dtu = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = dtu.run(incoming).await {
+                log::error!("{}", e);
+            }
+        });
+        Ok(endpoint)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{http_client, AhoyApi, RetryPolicy};
+
+    use chrono::NaiveDate;
+
+    fn ch(status: &Value, channel: usize, field: usize) -> f64 {
+        status["ch"][channel][field].as_f64().unwrap()
+    }
+
+    fn at_hour(date: NaiveDate, hour: u32) -> DateTime<Local> {
+        Local
+            .from_local_datetime(&date.and_hms_opt(hour, 0, 0).unwrap())
+            .earliest()
+            .unwrap()
+    }
+
+    #[test]
+    fn simulated_day() {
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let dtu = MockDtu::new(1, 2).starting_at(at_hour(date, 0));
+        let status = |hour| dtu.inverter(0, at_hour(date, 0) + chrono::Duration::hours(hour));
+
+        let night = status(1).unwrap();
+        assert_eq!(ch(&night, 0, 2), 0.0);
+        let noon = status(13).unwrap();
+        assert!(ch(&noon, 0, 2) > 400.0);
+        assert_eq!(ch(&noon, 1, 6), 400.0);
+        // the DTU stops polling at sunset
+        let evening = status(23).unwrap();
+        let (_, sunset) = dtu.plant.sunrise_sunset(date).unwrap();
+        assert_eq!(evening["ts_last_success"], sunset.timestamp());
+        assert_eq!(ch(&evening, 0, 2), 0.0);
+        assert!(status(7).is_some() && dtu.inverter(1, at_hour(date, 7)).is_none());
+
+        // the yield of the day starts over, the total keeps growing
+        let next_morning = status(24 + 8).unwrap();
+        assert!(ch(&next_morning, 0, 7) < ch(&evening, 0, 7));
+        assert!(ch(&next_morning, 0, 6) > ch(&evening, 0, 6));
+        assert!(ch(&evening, 0, 6) > ch(&noon, 0, 6));
+    }
+
+    #[tokio::test]
+    async fn serve_and_inject_faults() {
+        let dtu = MockDtu::new(2, 4)
+            .starting_at(at_hour(Local::now().date_naive(), 12))
+            .with_interval(1)
+            .with_speed(100.0)
+            .with_timeout_delay(Duration::from_secs(2));
+        let api = AhoyApi::new(dtu.spawn().unwrap())
+            .with_client(http_client(
+                Duration::from_millis(200),
+                Duration::from_millis(200),
+            ))
+            .with_retry_policy(RetryPolicy::none());
+
+        let list = api.get_inverter_list().await.unwrap();
+        assert_eq!(list.inverter.len(), 2);
+        assert_eq!(list.inverter[1].channels, 4);
+        let live = api.get_live().await.unwrap();
+        let inverter = list.inverter[1].clone();
+        let status = api.get_inverter_status(inverter.clone()).await.unwrap();
+        assert_eq!(status.fields(&live, 4, None).len(), 5);
+        assert!(api.get_index().await.unwrap().inverter[0].is_producing);
+
+        dtu.inject("/api/live", Fault::ServerError, 1);
+        let res = api.get_live().await;
+        assert!(
+            matches!(res, Err(ErrorKind::HttpStatus { status: 500, .. })),
+            "{:?}",
+            res
+        );
+        dtu.inject("/api/inverter/list", Fault::MalformedJson, 1);
+        let res = api.get_inverter_list().await;
+        assert!(
+            matches!(res, Err(ErrorKind::ParsingError { .. })),
+            "{:?}",
+            res
+        );
+        dtu.inject("/api/index", Fault::Timeout, 1);
+        let res = api.get_index().await;
+        assert!(matches!(res, Err(ErrorKind::Timeout { .. })), "{:?}", res);
+
+        // time moves on, but a stale DTU keeps reporting the last poll
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        dtu.inject("/api/inverter/id/", Fault::Stale, 1);
+        let stale = api.get_inverter_status(inverter.clone()).await.unwrap();
+        assert_eq!(stale.ts_last_success, status.ts_last_success);
+        let fresh = api.get_inverter_status(inverter).await.unwrap();
+        assert!(fresh.ts_last_success > status.ts_last_success);
+
+        // retried requests get through once the faults are used up
+        let api = api.with_retry_policy(RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        });
+        dtu.inject("/api/live", Fault::ServerError, 2);
+        api.get_live().await.unwrap();
+        assert_eq!(dtu.requests("/api/live"), 5);
+    }
+
+    #[tokio::test]
+    async fn relogin_after_expired_session() {
+        let dtu = MockDtu::new(1, 2).with_password("secret");
+        let endpoint = dtu.spawn().unwrap();
+
+        let api = AhoyApi::new(endpoint.clone());
+        assert!(matches!(
+            api.get_live().await,
+            Err(ErrorKind::PasswordRequired)
+        ));
+
+        let api = AhoyApi::new(endpoint).with_password("secret".to_string());
+        api.get_live().await.unwrap();
+        assert_eq!(dtu.requests("/api/ctrl"), 1);
+        api.get_inverter_list().await.unwrap();
+        assert_eq!(dtu.requests("/api/ctrl"), 1);
+
+        // a restarted DTU forgets the session, the next GET logs in again
+        dtu.expire_session();
+        let list = api.get_inverter_list().await.unwrap();
+        assert_eq!(list.inverter.len(), 1);
+        assert_eq!(dtu.requests("/api/ctrl"), 2);
+        assert_eq!(dtu.requests("/api/inverter/list"), 3);
+    }
+}
@@ -1,6 +1,50 @@
 pub mod ahoy;
+pub mod capture;
+pub mod cli;
+pub mod config;
+pub mod control;
 pub mod crawler;
+pub mod dtu;
 pub mod error_kind;
+pub mod http;
+mod lenient;
+pub mod metrics;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock_dtu;
+pub mod opendtu;
+pub mod replay;
+pub mod report;
+#[cfg(any(test, feature = "mock"))]
+pub mod simulator;
+pub mod sink;
+pub mod storage;
+#[cfg(test)]
+pub(crate) mod test_util;
 
 pub use ahoy::AhoyApi as Ahoy;
+pub use capture::{read_capture, Capture, CapturedResponse, CYCLE_MARKER};
+pub use cli::{
+    dtu_status, list_entrypoint, list_inverters, once_entrypoint, render_snapshot, snapshot,
+    status_entrypoint, CliOptions, USAGE,
+};
+pub use config::{
+    config_entrypoint, Config, CrawlerConfig, InverterConfig, DEFAULT_CONFIG_FILE, DEFAULT_OUT_DIR,
+};
+pub(crate) use control::CtrlResponse;
+pub use control::{CommandOutcome, InverterCommand, PowerLimit};
+pub use dtu::{DtuBackend, DtuConfig, DtuEndpoint, DtuType};
 pub use error_kind::ErrorKind;
+pub use http::{http_client, HttpConfig, RetryPolicy};
+pub use metrics::Metrics;
+#[cfg(any(test, feature = "mock"))]
+pub use mock_dtu::{Fault, MockDtu};
+pub use opendtu::OpenDtuApi;
+pub use replay::ReplayDtu;
+pub use report::{
+    build_report, render_report, report_entrypoint, OutputFormat, ReportPeriod, ReportRow,
+};
+#[cfg(any(test, feature = "mock"))]
+pub use simulator::{
+    simulate_entrypoint, sun_position, sunrise_sunset, InputReading, InverterReading, PvPlant,
+    PvString, SimulatedInverter, SunPosition, DEFAULT_SIMULATION_DIR,
+};
@@ -0,0 +1,568 @@
+use crate::http::{
+    parse_response, request_error, status_error, DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT,
+};
+use crate::{
+    http_client, DtuBackend, ErrorKind, Extra, Generic, Index, Inverter, InverterIndex,
+    InverterList, InverterStatus, Live, RetryPolicy,
+};
+
+use async_trait::async_trait;
+use chrono::Local;
+use futures::future::try_join_all;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::from_str;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    iter::once,
+    sync::{Arc, Mutex},
+};
+
+/// Fields of the summary (channel 0) as `(group, OpenDTU name, Ahoy name, unit)`.
+const SUMMARY_FIELDS: &[(&str, &str, &str, &str)] = &[
+    ("AC", "Voltage", "U_AC", "V"),
+    ("AC", "Current", "I_AC", "A"),
+    ("AC", "Power", "P_AC", "W"),
+    ("AC", "Frequency", "F_AC", "Hz"),
+    ("AC", "PowerFactor", "PF_AC", ""),
+    ("INV", "Temperature", "Temp", "°C"),
+    ("INV", "YieldTotal", "YieldTotal", "kWh"),
+    ("INV", "YieldDay", "YieldDay", "Wh"),
+    ("INV", "Power DC", "P_DC", "W"),
+    ("INV", "Efficiency", "Efficiency", "%"),
+    ("AC", "ReactivePower", "Q_AC", "var"),
+];
+
+/// Fields of each input as `(OpenDTU name, Ahoy name, unit)`.
+const INPUT_FIELDS: &[(&str, &str, &str)] = &[
+    ("Voltage", "U_DC", "V"),
+    ("Current", "I_DC", "A"),
+    ("Power", "P_DC", "W"),
+    ("YieldDay", "YieldDay", "Wh"),
+    ("YieldTotal", "YieldTotal", "kWh"),
+    ("Irradiation", "Irradiation", "%"),
+];
+
+/// `(ts_last_success, data_age, polled at in ms)` of an inverter's last poll.
+type LastPoll = (u64, u64, i64);
+
+/// Talks to an OpenDTU and maps its live data into the models of the Ahoy api.
+#[derive(Debug, Clone)]
+pub struct OpenDtuApi {
+    endpoint: String,
+    /// password of the `admin` user, only needed if the live data is protected
+    password: Option<String>,
+    client: Client,
+    retry: RetryPolicy,
+    /// the last poll of each inverter, by serial
+    last_success: Arc<Mutex<HashMap<String, LastPoll>>>,
+    #[cfg(test)]
+    offline_mode: bool,
+}
+
+impl OpenDtuApi {
+    #[cfg(not(test))]
+    pub fn new(endpoint: String) -> Self {
+        Self {
+            endpoint,
+            password: None,
+            client: http_client(DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT),
+            retry: RetryPolicy::default(),
+            last_success: Arc::default(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn new(endpoint: String) -> Self {
+        Self {
+            endpoint,
+            password: None,
+            client: http_client(DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT),
+            retry: RetryPolicy::default(),
+            last_success: Arc::default(),
+            offline_mode: false,
+        }
+    }
+
+    pub fn with_password(mut self, password: String) -> Self {
+        self.password = Some(password);
+        self
+    }
+
+    /// Send requests with `client`, e.g. one with different timeouts.
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Retry failed GET requests according to `retry`.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn set_offline_mode(&mut self, value: bool) {
+        self.offline_mode = value;
+    }
+
+    /// The time the DTU last heard from `inverter`, derived from its
+    /// `data_age` at `now_millis`. The age only has whole seconds, so the
+    /// time of the previous poll is kept while the age grew by at least the
+    /// time in between, which keeps repeated polls of one measurement equal.
+    fn ts_last_success(&self, inverter: &LiveInverter, now_millis: i64) -> u64 {
+        let derived = ((now_millis / 1000).max(0) as u64).saturating_sub(inverter.data_age);
+        let mut last_success = self.last_success.lock().unwrap();
+        let ts_last_success = match last_success.get(&inverter.serial) {
+            Some(&(ts_last_success, data_age, polled_at))
+                if (inverter.data_age + 1) * 1000
+                    >= data_age * 1000 + (now_millis - polled_at).max(0) as u64 =>
+            {
+                ts_last_success
+            }
+            _ => derived,
+        };
+        last_success.insert(
+            inverter.serial.clone(),
+            (ts_last_success, inverter.data_age, now_millis),
+        );
+        ts_last_success
+    }
+
+    async fn _request(&self, path: String) -> Result<String, ErrorKind> {
+        let url = format!("{}{}", self.endpoint, path);
+        self.retry.run(|| self.get_once(&url)).await
+    }
+
+    async fn get_once(&self, url: &str) -> Result<String, ErrorKind> {
+        log::info!("requesting {}", url);
+        let mut request = self.client.get(url);
+        if let Some(password) = &self.password {
+            request = request.basic_auth("admin", Some(password));
+        }
+        let res = request
+            .send()
+            .await
+            .map_err(|err| request_error(url, err))?;
+        if res.status() == StatusCode::UNAUTHORIZED {
+            return Err(match self.password {
+                Some(_) => ErrorKind::AuthenticationFailed,
+                None => ErrorKind::PasswordRequired,
+            });
+        }
+        if let Some(err) = status_error(url, res.status()) {
+            return Err(err);
+        }
+        res.text().await.map_err(|err| request_error(url, err))
+    }
+
+    #[cfg(test)]
+    async fn request(&self, path: String) -> Result<String, ErrorKind> {
+        if self.offline_mode {
+            Ok(match path.as_str() {
+                "/api/livedata/status" => "{\"inverters\":[{\"serial\":\"114184511809\",\"name\":\"Roof\",\"order\":0,\"data_age\":4,\"poll_enabled\":true,\"reachable\":true,\"producing\":true,\"limit_relative\":100,\"limit_absolute\":600,\"events\":2}],\"total\":{\"Power\":{\"v\":120.5,\"u\":\"W\",\"d\":0},\"YieldDay\":{\"v\":440,\"u\":\"Wh\",\"d\":0},\"YieldTotal\":{\"v\":298.886,\"u\":\"kWh\",\"d\":3}},\"hints\":{\"time_sync\":false,\"radio_problem\":false,\"default_password\":true}}",
+                "/api/livedata/status?inv=114184511809" => "{\"inverters\":[{\"serial\":\"114184511809\",\"name\":\"Roof\",\"order\":0,\"data_age\":4,\"poll_enabled\":true,\"reachable\":true,\"producing\":true,\"limit_relative\":100,\"limit_absolute\":600,\"events\":2,\"AC\":{\"0\":{\"Power\":{\"v\":120.5,\"u\":\"W\",\"d\":1},\"Voltage\":{\"v\":231.2,\"u\":\"V\",\"d\":1},\"Current\":{\"v\":0.52,\"u\":\"A\",\"d\":2},\"Frequency\":{\"v\":50.01,\"u\":\"Hz\",\"d\":2},\"PowerFactor\":{\"v\":0.99,\"u\":\"\",\"d\":3},\"ReactivePower\":{\"v\":1.2,\"u\":\"var\",\"d\":1}}},\"DC\":{\"0\":{\"name\":{\"u\":\"East\"},\"Power\":{\"v\":60.1,\"u\":\"W\",\"d\":1},\"Voltage\":{\"v\":32.1,\"u\":\"V\",\"d\":1},\"Current\":{\"v\":1.87,\"u\":\"A\",\"d\":2},\"YieldDay\":{\"v\":210,\"u\":\"Wh\",\"d\":0},\"YieldTotal\":{\"v\":150.381,\"u\":\"kWh\",\"d\":3},\"Irradiation\":{\"v\":15.0,\"u\":\"%\",\"d\":3,\"max\":400}},\"1\":{\"name\":{\"u\":\"West\"},\"Power\":{\"v\":64.2,\"u\":\"W\",\"d\":1},\"Voltage\":{\"v\":33.0,\"u\":\"V\",\"d\":1},\"Current\":{\"v\":1.95,\"u\":\"A\",\"d\":2},\"YieldDay\":{\"v\":230,\"u\":\"Wh\",\"d\":0},\"YieldTotal\":{\"v\":148.505,\"u\":\"kWh\",\"d\":3},\"Irradiation\":{\"v\":16.05,\"u\":\"%\",\"d\":3,\"max\":400}}},\"INV\":{\"0\":{\"Temperature\":{\"v\":35.4,\"u\":\"°C\",\"d\":1},\"Power DC\":{\"v\":124.3,\"u\":\"W\",\"d\":1},\"YieldDay\":{\"v\":440,\"u\":\"Wh\",\"d\":0},\"YieldTotal\":{\"v\":298.886,\"u\":\"kWh\",\"d\":3},\"Efficiency\":{\"v\":96.94,\"u\":\"%\",\"d\":3}}}}],\"total\":{\"Power\":{\"v\":120.5,\"u\":\"W\",\"d\":0},\"YieldDay\":{\"v\":440,\"u\":\"Wh\",\"d\":0},\"YieldTotal\":{\"v\":298.886,\"u\":\"kWh\",\"d\":3}},\"hints\":{\"time_sync\":false,\"radio_problem\":false,\"default_password\":true}}",
+                "/api/system/status" => "{\"hostname\":\"OpenDTU\",\"sdkversion\":\"v4.4.6\",\"chipmodel\":\"ESP32-D0WDQ6\",\"uptime\":86400,\"git_hash\":\"v24.2.12\",\"pioenv\":\"generic_esp32\"}",
+                "/api/network/status" => "{\"sta_status\":true,\"sta_ssid\":\"home\",\"sta_rssi\":-61}",
+                _ => "",
+            }
+            .to_string())
+        } else {
+            self._request(path).await
+        }
+    }
+
+    #[cfg(not(test))]
+    async fn request(&self, path: String) -> Result<String, ErrorKind> {
+        self._request(path).await
+    }
+
+    /// The live data of all inverters, or the full details of one.
+    pub async fn get_livedata(&self, serial: Option<&str>) -> Result<LiveData, ErrorKind> {
+        let path = match serial {
+            Some(serial) => format!("/api/livedata/status?inv={}", serial),
+            None => "/api/livedata/status".to_string(),
+        };
+        let res = self.request(path.clone()).await?;
+        parse_response(&path, &res)
+    }
+
+    pub async fn get_system_status(&self) -> Result<SystemStatus, ErrorKind> {
+        let path = "/api/system/status";
+        let res = self.request(path.to_string()).await?;
+        parse_response(path, &res)
+    }
+
+    /// Information about the DTU in the shape of Ahoy's `generic` block.
+    pub async fn get_generic(&self) -> Result<Generic, ErrorKind> {
+        let system = self.get_system_status().await?;
+        // the signal strength is nice to have, but not worth failing a crawl for
+        let network = match self.request("/api/network/status".to_string()).await {
+            Ok(res) => from_str::<NetworkStatus>(&res).unwrap_or_default(),
+            Err(_) => NetworkStatus::default(),
+        };
+        Ok(Generic {
+            wifi_rssi: network.sta_rssi,
+            ts_uptime: system.uptime,
+            ts_now: Local::now().timestamp() as u64,
+            version: system.git_hash.clone(),
+            build: system.git_hash,
+            menu_prot: false,
+            menu_mask: 0,
+            menu_prot_en: false,
+            esp_type: system.chipmodel,
+            extra: Extra::new(),
+        })
+    }
+}
+
+/// A value of `/api/livedata/status`, e.g. `{"v": 120.5, "u": "W", "d": 1}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LiveValue {
+    #[serde(default)]
+    pub v: Option<f32>,
+    #[serde(default)]
+    pub u: String,
+    #[serde(default)]
+    pub max: Option<f32>,
+}
+
+/// The values of one channel of a group, by name.
+pub type LiveChannel = BTreeMap<String, LiveValue>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LiveInverter {
+    pub serial: String,
+    pub name: String,
+    #[serde(default)]
+    pub order: u8,
+    /// seconds since the DTU last heard from the inverter
+    #[serde(default)]
+    pub data_age: u64,
+    #[serde(default)]
+    pub poll_enabled: bool,
+    #[serde(default)]
+    pub reachable: bool,
+    #[serde(default)]
+    pub producing: bool,
+    #[serde(default)]
+    pub limit_relative: f32,
+    #[serde(default)]
+    pub events: i32,
+    #[serde(rename = "AC", default)]
+    pub ac: BTreeMap<String, LiveChannel>,
+    #[serde(rename = "DC", default)]
+    pub dc: BTreeMap<String, LiveChannel>,
+    #[serde(rename = "INV", default)]
+    pub inv: BTreeMap<String, LiveChannel>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Hints {
+    #[serde(default)]
+    pub time_sync: bool,
+    #[serde(default)]
+    pub radio_problem: bool,
+    #[serde(default)]
+    pub default_password: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LiveData {
+    pub inverters: Vec<LiveInverter>,
+    #[serde(default)]
+    pub hints: Hints,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SystemStatus {
+    #[serde(default)]
+    pub uptime: u64,
+    #[serde(default)]
+    pub git_hash: String,
+    #[serde(default)]
+    pub chipmodel: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct NetworkStatus {
+    #[serde(default)]
+    sta_rssi: i32,
+}
+
+impl LiveInverter {
+    /// The inputs ordered by their index; the keys are strings, so `"10"`
+    /// would otherwise sort before `"2"`.
+    fn inputs(&self) -> Vec<&LiveChannel> {
+        let mut inputs: Vec<(u8, &LiveChannel)> = self
+            .dc
+            .iter()
+            .filter_map(|(index, channel)| Some((index.parse().ok()?, channel)))
+            .collect();
+        inputs.sort_by_key(|(index, _)| *index);
+        inputs.into_iter().map(|(_, channel)| channel).collect()
+    }
+
+    fn value(&self, group: &str, name: &str) -> Option<&LiveValue> {
+        let group = match group {
+            "AC" => &self.ac,
+            "INV" => &self.inv,
+            _ => &self.dc,
+        };
+        group.get("0")?.get(name)
+    }
+
+    fn to_inverter(&self, id: u8) -> Inverter {
+        let inputs = self.inputs();
+        Inverter {
+            enabled: self.poll_enabled,
+            id,
+            name: self.name.clone(),
+            serial: self.serial.clone(),
+            channels: inputs.len() as u8,
+            version: String::new(),
+            ch_yield_cor: vec![0.0; inputs.len()],
+            ch_name: inputs
+                .iter()
+                .map(|input| {
+                    input
+                        .get("name")
+                        .map(|name| name.u.clone())
+                        .unwrap_or_default()
+                })
+                .collect(),
+            ch_max_pwr: inputs
+                .iter()
+                .map(|input| {
+                    input
+                        .get("Irradiation")
+                        .and_then(|irradiation| irradiation.max)
+                        .map(|max| max as u16)
+                })
+                .collect(),
+            extra: Extra::new(),
+        }
+    }
+
+    /// The values in the order of [`SUMMARY_FIELDS`] and [`INPUT_FIELDS`].
+    /// Values the DTU did not report are `NaN`.
+    fn to_status(&self, id: u8, ts_last_success: u64) -> InverterStatus {
+        let inverter = self.to_inverter(id);
+        let summary: Vec<f32> = SUMMARY_FIELDS
+            .iter()
+            .map(|(group, name, _, _)| {
+                self.value(group, name)
+                    .and_then(|value| value.v)
+                    .unwrap_or(f32::NAN)
+            })
+            .collect();
+        let inputs = self.inputs().into_iter().map(|input| {
+            INPUT_FIELDS
+                .iter()
+                .map(|(name, _, _)| {
+                    input
+                        .get(*name)
+                        .and_then(|value| value.v)
+                        .unwrap_or(f32::NAN)
+                })
+                .collect::<Vec<f32>>()
+        });
+        InverterStatus {
+            id,
+            enabled: self.poll_enabled,
+            name: self.name.clone(),
+            serial: self.serial.clone(),
+            version: String::new(),
+            power_limit_read: self.limit_relative as u32,
+            power_limit_ack: true,
+            ts_last_success,
+            generation: 0,
+            status: self.producing as u8,
+            alarm_cnt: self.events.max(0) as u32,
+            ch: once(summary).chain(inputs).collect(),
+            ch_name: once("AC".to_string()).chain(inverter.ch_name).collect(),
+            ch_max_pwr: once(None).chain(inverter.ch_max_pwr).collect(),
+            extra: Extra::new(),
+        }
+    }
+}
+
+impl LiveData {
+    /// The inverters in the order configured in the DTU; their position is
+    /// used as id.
+    fn ordered(mut self) -> Vec<LiveInverter> {
+        self.inverters.sort_by_key(|inverter| inverter.order);
+        self.inverters
+    }
+}
+
+#[async_trait]
+impl DtuBackend for OpenDtuApi {
+    async fn get_inverter_list(&self) -> Result<InverterList, ErrorKind> {
+        // the inputs are only listed in the details of each inverter
+        let summaries = self.get_livedata(None).await?.ordered();
+        let inverters: Vec<Inverter> = try_join_all(summaries.iter().enumerate().map(
+            |(id, summary)| async move {
+                self.get_livedata(Some(&summary.serial))
+                    .await?
+                    .inverters
+                    .iter()
+                    .find(|live| live.serial == summary.serial)
+                    .map(|live| live.to_inverter(id as u8))
+                    .ok_or(ErrorKind::InverterNotFound(summary.serial.clone()))
+            },
+        ))
+        .await?;
+        Ok(InverterList {
+            max_num_inverters: inverters.len() as u8,
+            inverter: inverters,
+            interval: String::new(),
+            retries: String::new(),
+            rst_mid: false,
+            rst_n_avail: false,
+            rst_com_stop: false,
+            strt_wtht_tm: false,
+            yld_eff: 1.0,
+            extra: Extra::new(),
+        })
+    }
+
+    async fn get_live(&self) -> Result<Live, ErrorKind> {
+        let inverters = self.get_livedata(None).await?.inverters.len();
+        Ok(Live {
+            generic: self.get_generic().await?,
+            refresh: 0,
+            ch0_fld_units: SUMMARY_FIELDS
+                .iter()
+                .map(|(_, _, _, unit)| unit.to_string())
+                .collect(),
+            ch0_fld_names: SUMMARY_FIELDS
+                .iter()
+                .map(|(_, _, name, _)| name.to_string())
+                .collect(),
+            fld_units: INPUT_FIELDS
+                .iter()
+                .map(|(_, _, unit)| unit.to_string())
+                .collect(),
+            fld_names: INPUT_FIELDS
+                .iter()
+                .map(|(_, name, _)| name.to_string())
+                .collect(),
+            iv: vec![true; inverters],
+            extra: Extra::new(),
+        })
+    }
+
+    async fn get_inverter_status(&self, inverter: Inverter) -> Result<InverterStatus, ErrorKind> {
+        self.get_livedata(Some(&inverter.serial))
+            .await?
+            .inverters
+            .iter()
+            .find(|live| live.serial == inverter.serial)
+            .map(|live| {
+                let ts_last_success = self.ts_last_success(live, Local::now().timestamp_millis());
+                live.to_status(inverter.id, ts_last_success)
+            })
+            .ok_or(ErrorKind::InverterNotFound(inverter.serial))
+    }
+
+    async fn get_index(&self) -> Result<Index, ErrorKind> {
+        let livedata = self.get_livedata(None).await?;
+        let generic = self.get_generic().await?;
+        let mut warnings = Vec::new();
+        if livedata.hints.time_sync {
+            warnings.push("time is not synchronized".to_string());
+        }
+        if livedata.hints.radio_problem {
+            warnings.push("radio problem".to_string());
+        }
+        if livedata.hints.default_password {
+            warnings.push("default password is in use".to_string());
+        }
+        Ok(Index {
+            ts_now: generic.ts_now,
+            generic,
+            // OpenDTU does not report sunrise and sunset, crawling falls back to a fixed interval
+            ts_sunrise: 0,
+            ts_sunset: 0,
+            ts_offset: 0,
+            dis_night_comm: false,
+            inverter: livedata
+                .ordered()
+                .iter()
+                .enumerate()
+                .map(|(id, inverter)| InverterIndex {
+                    enabled: inverter.poll_enabled,
+                    id: id as u8,
+                    name: inverter.name.clone(),
+                    version: String::new(),
+                    is_avail: inverter.reachable,
+                    is_producing: inverter.producing,
+                    ts_last_success: self
+                        .ts_last_success(inverter, Local::now().timestamp_millis()),
+                    extra: Extra::new(),
+                })
+                .collect(),
+            warnings,
+            infos: Vec::new(),
+            extra: Extra::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CrawledInverter, UnitValue};
+
+    use std::{collections::HashMap, sync::Arc};
+
+    fn init() -> OpenDtuApi {
+        let mut api = OpenDtuApi::new("http://opendtu".to_string());
+        api.set_offline_mode(true);
+        api
+    }
+
+    #[tokio::test]
+    async fn map_livedata() {
+        let api = init();
+        let inverter = api.get_inverter_list().await.unwrap().inverter[0].clone();
+        assert_eq!(inverter.channels, 2);
+        assert_eq!(inverter.ch_name, ["East", "West"]);
+
+        let live = DtuBackend::get_live(&api).await.unwrap();
+        assert_eq!(live.generic.wifi_rssi, -61);
+        let status = DtuBackend::get_inverter_status(&api, inverter)
+            .await
+            .unwrap();
+        let fields: Vec<HashMap<String, UnitValue<f32>>> = status.fields(&live, 2, None);
+        assert_eq!(fields[0]["P_AC"].value, 120.5);
+        assert_eq!(fields[0]["Temp"].unit, "°C");
+        assert_eq!(fields[2]["YieldDay"].value, 230.0);
+
+        let index = DtuBackend::get_index(&api).await.unwrap();
+        assert!(index.inverter[0].is_producing);
+        assert_eq!(index.warnings, ["default password is in use"]);
+    }
+
+    #[tokio::test]
+    async fn stale_while_data_age_grows() {
+        let api = init();
+        let mut inverter = api.get_livedata(None).await.unwrap().inverters.remove(0);
+        assert_eq!(api.ts_last_success(&inverter, 1_000_900), 996);
+        // 10.2s later the truncated age would move the derived time by a second
+        inverter.data_age = 14;
+        assert_eq!(api.ts_last_success(&inverter, 1_011_100), 996);
+        inverter.data_age = 3;
+        assert_eq!(api.ts_last_success(&inverter, 1_021_000), 1018);
+    }
+
+    #[tokio::test]
+    async fn crawl_opendtu() {
+        let mut inverter = CrawledInverter::fetch(Arc::new(init()), 0).await.unwrap();
+        inverter.crawl().await.unwrap();
+        assert_eq!(inverter.summary_dataset.rows()[0].values[2], Some(120.5));
+        assert!(inverter.sun_times.is_none());
+    }
+}
@@ -0,0 +1,278 @@
+use crate::{
+    http::parse_response, read_capture, CapturedResponse, DtuBackend, ErrorKind, Index, Inverter,
+    InverterList, InverterStatus, Live, CYCLE_MARKER,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use serde::de::DeserializeOwned;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::{sleep_until, Instant};
+
+#[derive(Debug, Default)]
+struct ReplayState {
+    /// the responses not served yet, by path
+    pending: HashMap<String, VecDeque<CapturedResponse>>,
+    /// when the first response was served
+    started: Option<Instant>,
+}
+
+/// Serves a session captured with a [`Capture`](crate::Capture) back to the
+/// crawler. Every path is answered with its captured responses in order, and
+/// the clock of the replay is the time of the next captured request or
+/// cycle, so a crawler with the settings of the captured one produces the
+/// same datasets.
+#[derive(Debug)]
+pub struct ReplayDtu {
+    first_timestamp: Option<DateTime<Local>>,
+    last_timestamp: Option<DateTime<Local>>,
+    speed: f64,
+    state: Mutex<ReplayState>,
+}
+
+impl ReplayDtu {
+    /// Replay `responses` at their original speed.
+    pub fn new(responses: Vec<CapturedResponse>) -> Self {
+        let first_timestamp = responses.iter().map(|response| response.timestamp).min();
+        let last_timestamp = responses.iter().map(|response| response.timestamp).max();
+        let mut pending: HashMap<String, VecDeque<CapturedResponse>> = HashMap::new();
+        for response in responses {
+            pending
+                .entry(response.path.clone())
+                .or_default()
+                .push_back(response);
+        }
+        Self {
+            first_timestamp,
+            last_timestamp,
+            speed: 1.0,
+            state: Mutex::new(ReplayState {
+                pending,
+                started: None,
+            }),
+        }
+    }
+
+    /// Replay the capture file at `path`.
+    pub fn open(path: &str) -> Result<Self, ErrorKind> {
+        Ok(Self::new(read_capture(path)?))
+    }
+
+    /// Serve the responses `speed` times faster than they were captured,
+    /// `f64::INFINITY` serves them without waiting.
+    pub fn with_speed(mut self, speed: f64) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// How many captured responses have not been served yet.
+    pub fn remaining(&self) -> usize {
+        self.lock().pending.values().map(VecDeque::len).sum()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ReplayState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Take the next response to `path` and wait until it is due.
+    async fn next_response(&self, path: &str) -> Result<CapturedResponse, ErrorKind> {
+        let (response, started) = {
+            let mut state = self.lock();
+            let response = state
+                .pending
+                .get_mut(path)
+                .and_then(VecDeque::pop_front)
+                .ok_or(ErrorKind::CaptureExhausted(path.to_string()))?;
+            (response, *state.started.get_or_insert_with(Instant::now))
+        };
+        let offset = self
+            .first_timestamp
+            .and_then(|first| (response.timestamp - first).to_std().ok())
+            .unwrap_or_default();
+        if self.speed.is_finite() && self.speed > 0.0 {
+            sleep_until(started + Duration::from_secs_f64(offset.as_secs_f64() / self.speed)).await;
+        }
+        Ok(response)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, ErrorKind> {
+        let response = self.next_response(path).await?;
+        match (response.body, response.error) {
+            (_, Some(error)) => Err(error),
+            (Some(body), None) => parse_response(path, &body),
+            (None, None) => parse_response(path, ""),
+        }
+    }
+}
+
+#[async_trait]
+impl DtuBackend for ReplayDtu {
+    async fn get_inverter_list(&self) -> Result<InverterList, ErrorKind> {
+        self.get("/api/inverter/list").await
+    }
+
+    async fn get_live(&self) -> Result<Live, ErrorKind> {
+        self.get("/api/live").await
+    }
+
+    async fn get_inverter_status(&self, inverter: Inverter) -> Result<InverterStatus, ErrorKind> {
+        self.get(&format!("/api/inverter/id/{}", inverter.id)).await
+    }
+
+    async fn get_index(&self) -> Result<Index, ErrorKind> {
+        self.get("/api/index").await
+    }
+
+    /// The time of the next captured request, or of the last one once all
+    /// responses have been served.
+    fn now(&self) -> DateTime<Local> {
+        self.lock()
+            .pending
+            .values()
+            .filter_map(|responses| responses.front())
+            .map(|response| response.timestamp)
+            .min()
+            .or(self.last_timestamp)
+            .unwrap_or_else(Local::now)
+    }
+
+    /// The timestamp the captured crawler started its next cycle with, the
+    /// clock of the replay for captures without them.
+    fn cycle_time(&self) -> DateTime<Local> {
+        let marker = self
+            .lock()
+            .pending
+            .get_mut(CYCLE_MARKER)
+            .and_then(VecDeque::pop_front);
+        match marker {
+            Some(marker) => marker.timestamp,
+            None => self.now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        api::test_util::{mock_dtu, TempDir},
+        Capture, Crawler, Row,
+    };
+
+    use std::sync::Arc;
+
+    fn response(timestamp: DateTime<Local>, path: &str, body: &str) -> CapturedResponse {
+        CapturedResponse {
+            timestamp,
+            path: path.to_string(),
+            body: Some(body.to_string()),
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_in_order() {
+        let start = Local::now() - chrono::Duration::hours(1);
+        let live = "{\"refresh\":30}";
+        let replay = ReplayDtu::new(vec![
+            response(start, "/api/live", live),
+            CapturedResponse {
+                timestamp: start + chrono::Duration::milliseconds(100),
+                path: "/api/index".to_string(),
+                body: None,
+                error: Some(ErrorKind::Timeout {
+                    url: "http://dtu/api/index".to_string(),
+                }),
+            },
+            response(
+                start + chrono::Duration::milliseconds(200),
+                "/api/live",
+                "{",
+            ),
+        ])
+        .with_speed(10.0);
+
+        assert_eq!(replay.now(), start);
+        let begin = Instant::now();
+        assert_eq!(replay.get_live().await.unwrap().refresh, 30);
+        assert_eq!(replay.now(), start + chrono::Duration::milliseconds(100));
+        let res = replay.get_index().await;
+        assert!(matches!(res, Err(ErrorKind::Timeout { .. })), "{:?}", res);
+        let res = replay.get_live().await;
+        assert!(
+            matches!(res, Err(ErrorKind::ParsingError { .. })),
+            "{:?}",
+            res
+        );
+        assert!(begin.elapsed() >= Duration::from_millis(20));
+        assert_eq!(replay.remaining(), 0);
+
+        let res = replay.get_live().await;
+        assert!(
+            matches!(res, Err(ErrorKind::CaptureExhausted(_))),
+            "{:?}",
+            res
+        );
+        assert_eq!(replay.now(), start + chrono::Duration::milliseconds(200));
+    }
+
+    /// The rows of all inverters, sorted by inverter and channel.
+    fn rows(crawler: &Crawler) -> Vec<Vec<Row>> {
+        let mut keys: Vec<_> = crawler.inverters.keys().collect();
+        keys.sort();
+        keys.into_iter()
+            .flat_map(|key| {
+                crawler.inverters[key]
+                    .datasets()
+                    .map(|(_, dataset)| dataset.rows().to_vec())
+            })
+            .collect()
+    }
+
+    async fn crawl_session(crawler: &mut Crawler, pause: Duration) {
+        crawler.init().await.unwrap();
+        crawler.crawl_all_due_inverters(false).await.unwrap();
+        for _ in 0..2 {
+            tokio::time::sleep(pause).await;
+            for id in 0..2 {
+                crawler.crawl_inverter("", id).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn capture_and_replay() {
+        let temp_dir = TempDir::new("capture");
+        let path = &temp_dir.join("capture.jsonl");
+        let (_dtu, api) = mock_dtu(2, 2);
+        let api = api.with_capture(Capture::create(path).unwrap());
+        let mut captured = Crawler::from(api);
+        crawl_session(&mut captured, Duration::from_millis(20)).await;
+
+        let mut replayed = Crawler::from_backend(Arc::new(
+            ReplayDtu::open(path).unwrap().with_speed(f64::INFINITY),
+        ));
+        crawl_session(&mut replayed, Duration::ZERO).await;
+
+        let (captured, replayed) = (rows(&captured), rows(&replayed));
+        assert_eq!(captured.len(), 6);
+        assert_eq!(captured.len(), replayed.len());
+        for (captured, replayed) in captured.iter().zip(&replayed) {
+            assert_eq!(captured.len(), 3);
+            assert_eq!(captured.len(), replayed.len());
+            for (captured, replayed) in captured.iter().zip(replayed) {
+                assert_eq!(captured.values, replayed.values);
+                assert_eq!(captured.measured_at, replayed.measured_at);
+                assert_eq!(captured.crawled_at, replayed.crawled_at);
+            }
+        }
+    }
+}
@@ -0,0 +1,552 @@
+use crate::{api::cli::out_dir, ErrorKind};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Gaps between two rows longer than this are not counted as production time.
+const MAX_PRODUCTION_GAP: i64 = 15 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReportPeriod {
+    Day,
+    Month,
+    Year,
+}
+
+impl ReportPeriod {
+    fn key(&self, time: &NaiveDateTime) -> String {
+        match self {
+            ReportPeriod::Day => time.format("%Y-%m-%d").to_string(),
+            ReportPeriod::Month => time.format("%Y-%m").to_string(),
+            ReportPeriod::Year => time.format("%Y").to_string(),
+        }
+    }
+}
+
+impl FromStr for ReportPeriod {
+    type Err = ErrorKind;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "day" | "daily" => Ok(ReportPeriod::Day),
+            "month" | "monthly" => Ok(ReportPeriod::Month),
+            "year" | "yearly" => Ok(ReportPeriod::Year),
+            _ => Err(ErrorKind::InvalidArgument(format!(
+                "unknown period '{}', expected day, month or year",
+                value
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Csv,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = ErrorKind;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "table" => Ok(OutputFormat::Table),
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(ErrorKind::InvalidArgument(format!(
+                "unknown format '{}', expected table, csv or json",
+                value
+            ))),
+        }
+    }
+}
+
+/// Energy and power statistics of one channel of an inverter for one period.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReportRow {
+    /// empty unless the DTUs are named
+    pub dtu: String,
+    pub inverter: String,
+    pub channel: String,
+    pub period: String,
+    /// sum of the daily maxima of `YieldDay`
+    pub energy_kwh: Option<f64>,
+    /// difference between the first and last `YieldTotal`
+    pub yield_total_delta_kwh: Option<f64>,
+    pub peak_p_ac_w: Option<f64>,
+    pub peak_p_ac_at: Option<NaiveDateTime>,
+    pub peak_p_dc_w: Option<f64>,
+    pub peak_p_dc_at: Option<NaiveDateTime>,
+    /// average `Efficiency` while producing
+    pub avg_efficiency: Option<f64>,
+    pub max_temp: Option<f64>,
+    pub production_hours: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Sample {
+    time: NaiveDateTime,
+    yield_day: Option<f64>,
+    yield_total: Option<f64>,
+    p_ac: Option<f64>,
+    p_dc: Option<f64>,
+    efficiency: Option<f64>,
+    temp: Option<f64>,
+}
+
+impl Sample {
+    fn power(&self) -> Option<f64> {
+        self.p_ac.or(self.p_dc)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Accumulator {
+    daily_yield: BTreeMap<NaiveDate, f64>,
+    yield_total: Option<(f64, f64)>,
+    peak_p_ac: Option<(f64, NaiveDateTime)>,
+    peak_p_dc: Option<(f64, NaiveDateTime)>,
+    efficiency: (f64, u32),
+    max_temp: Option<f64>,
+    production_seconds: i64,
+}
+
+fn max_with_time(
+    current: Option<(f64, NaiveDateTime)>,
+    value: Option<f64>,
+    time: NaiveDateTime,
+) -> Option<(f64, NaiveDateTime)> {
+    match (current, value) {
+        (Some((peak, _)), Some(value)) if value > peak => Some((value, time)),
+        (None, Some(value)) => Some((value, time)),
+        (current, _) => current,
+    }
+}
+
+impl Accumulator {
+    fn add(&mut self, sample: &Sample, production_seconds: i64) {
+        if let Some(yield_day) = sample.yield_day {
+            let daily = self.daily_yield.entry(sample.time.date()).or_insert(0.0);
+            *daily = daily.max(yield_day);
+        }
+        if let Some(yield_total) = sample.yield_total {
+            self.yield_total = Some(match self.yield_total {
+                Some((min, max)) => (min.min(yield_total), max.max(yield_total)),
+                None => (yield_total, yield_total),
+            });
+        }
+        self.peak_p_ac = max_with_time(self.peak_p_ac, sample.p_ac, sample.time);
+        self.peak_p_dc = max_with_time(self.peak_p_dc, sample.p_dc, sample.time);
+        if let (Some(efficiency), Some(power)) = (sample.efficiency, sample.power()) {
+            if power > 0.0 && efficiency > 0.0 {
+                self.efficiency.0 += efficiency;
+                self.efficiency.1 += 1;
+            }
+        }
+        if let Some(temp) = sample.temp {
+            self.max_temp = Some(self.max_temp.map_or(temp, |max| max.max(temp)));
+        }
+        self.production_seconds += production_seconds;
+    }
+
+    fn finish(self, dtu: &str, inverter: &str, channel: &str, period: String) -> ReportRow {
+        ReportRow {
+            dtu: dtu.to_string(),
+            inverter: inverter.to_string(),
+            channel: channel.to_string(),
+            period,
+            // YieldDay is reported in Wh
+            energy_kwh: (!self.daily_yield.is_empty())
+                .then(|| self.daily_yield.values().sum::<f64>() / 1000.0),
+            yield_total_delta_kwh: self.yield_total.map(|(min, max)| max - min),
+            peak_p_ac_w: self.peak_p_ac.map(|(value, _)| value),
+            peak_p_ac_at: self.peak_p_ac.map(|(_, time)| time),
+            peak_p_dc_w: self.peak_p_dc.map(|(value, _)| value),
+            peak_p_dc_at: self.peak_p_dc.map(|(_, time)| time),
+            avg_efficiency: (self.efficiency.1 > 0)
+                .then(|| self.efficiency.0 / self.efficiency.1 as f64),
+            max_temp: self.max_temp,
+            production_hours: self.production_seconds as f64 / 3600.0,
+        }
+    }
+}
+
+/// Open a csv file of the crawler, which may have been compressed by the
+/// retention policy.
+pub(crate) fn open_csv(path: &Path) -> Result<csv::Reader<Box<dyn Read>>, ErrorKind> {
+    let file =
+        File::open(path).map_err(|_| ErrorKind::CouldNotOpenFile(path.display().to_string()))?;
+    let file: Box<dyn Read> = match path.extension().is_some_and(|extension| extension == "gz") {
+        true => Box::new(GzDecoder::new(file)),
+        false => Box::new(file),
+    };
+    Ok(csv::Reader::from_reader(file))
+}
+
+fn read_samples(path: &Path) -> Result<Vec<Sample>, ErrorKind> {
+    let read_error = |err: csv::Error| ErrorKind::CouldNotReadCsv(format!("{:?}: {}", path, err));
+    let mut reader = open_csv(path)?;
+    let headers = reader.headers().map_err(read_error)?.clone();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let timestamp = column("timestamp").ok_or(ErrorKind::CouldNotReadCsv(format!(
+        "{:?}: no timestamp column",
+        path
+    )))?;
+    let (yield_day, yield_total, p_ac, p_dc, efficiency, temp) = (
+        column("YieldDay"),
+        column("YieldTotal"),
+        column("P_AC"),
+        column("P_DC"),
+        column("Efficiency"),
+        column("Temp"),
+    );
+
+    let mut samples = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(read_error)?;
+        let value = |index: Option<usize>| index.and_then(|index| record.get(index)?.parse().ok());
+        let Some(time) = record
+            .get(timestamp)
+            .and_then(|time| NaiveDateTime::parse_from_str(time, "%F %T").ok())
+        else {
+            continue;
+        };
+        samples.push(Sample {
+            time,
+            yield_day: value(yield_day),
+            yield_total: value(yield_total),
+            p_ac: value(p_ac),
+            p_dc: value(p_dc),
+            efficiency: value(efficiency),
+            temp: value(temp),
+        });
+    }
+    Ok(samples)
+}
+
+/// The channel a csv file belongs to, `None` if it is no csv file.
+fn channel_of(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_string_lossy();
+    let stem = name
+        .strip_suffix(".csv.gz")
+        .or_else(|| name.strip_suffix(".csv"))?;
+    // `{channel}.v{n}.csv` continues `{channel}.csv` after the fields changed
+    Some(match stem.split_once(".v") {
+        Some((channel, version)) if version.parse::<u32>().is_ok() => channel.to_string(),
+        _ => stem.to_string(),
+    })
+}
+
+/// The csv files of a channel by `(dtu, inverter, channel)`.
+pub(crate) type ChannelFiles = BTreeMap<(String, String, String), Vec<PathBuf>>;
+
+/// Whether the directory `name` is a partition of the csv storage.
+fn is_partition(name: &str) -> bool {
+    NaiveDate::parse_from_str(name, "%Y-%m-%d").is_ok()
+        || NaiveDate::parse_from_str(&format!("{}-01", name), "%Y-%m-%d").is_ok()
+        || ["year=", "month=", "day="]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
+/// Find the csv files of all channels below `folder_path`, grouped by DTU,
+/// inverter and channel. Files are stored in `{inverter}/` or, for named
+/// DTUs, in `{dtu}/{inverter}/`, optionally followed by partition
+/// directories. The DTU is empty for the first layout.
+pub(crate) fn find_channel_files(folder_path: &str) -> Result<ChannelFiles, ErrorKind> {
+    let read_dir = |path: &Path| {
+        fs::read_dir(path).map_err(|_| ErrorKind::CouldNotOpenFile(path.display().to_string()))
+    };
+    let mut files = ChannelFiles::new();
+    let mut directories = vec![(PathBuf::from(folder_path), Vec::new())];
+    while let Some((directory, names)) = directories.pop() {
+        for entry in read_dir(&directory)?.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                let mut names = names.clone();
+                names.push(entry.file_name().to_string_lossy().to_string());
+                directories.push((path, names));
+                continue;
+            }
+            let Some(channel) = channel_of(&path) else {
+                continue;
+            };
+            let owners = match names.iter().position(|name| is_partition(name)) {
+                Some(partition) => &names[..partition],
+                None => &names[..],
+            };
+            let (dtu, inverter) = match owners {
+                [inverter] => (String::new(), inverter.clone()),
+                [dtu, inverter] => (dtu.clone(), inverter.clone()),
+                _ => continue,
+            };
+            files
+                .entry((dtu, inverter, channel))
+                .or_default()
+                .push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Build the report for all inverters and channels stored below `folder_path`.
+pub fn build_report(folder_path: &str, period: ReportPeriod) -> Result<Vec<ReportRow>, ErrorKind> {
+    let mut rows = Vec::new();
+    for ((dtu, inverter, channel), paths) in find_channel_files(folder_path)? {
+        let mut samples = Vec::new();
+        for path in paths {
+            samples.extend(read_samples(&path)?);
+        }
+        samples.sort_by_key(|sample| sample.time);
+
+        let mut periods: BTreeMap<String, Accumulator> = BTreeMap::new();
+        for (index, sample) in samples.iter().enumerate() {
+            let production_seconds = match (sample.power(), samples.get(index + 1)) {
+                (Some(power), Some(next)) if power > 0.0 => (next.time - sample.time)
+                    .num_seconds()
+                    .min(MAX_PRODUCTION_GAP),
+                _ => 0,
+            };
+            periods
+                .entry(period.key(&sample.time))
+                .or_default()
+                .add(sample, production_seconds);
+        }
+        rows.extend(
+            periods
+                .into_iter()
+                .map(|(key, accumulator)| accumulator.finish(&dtu, &inverter, &channel, key)),
+        );
+    }
+    Ok(rows)
+}
+
+fn format_value(value: Option<f64>, precision: usize) -> String {
+    value
+        .map(|value| format!("{:.*}", precision, value))
+        .unwrap_or_default()
+}
+
+fn format_time(value: Option<NaiveDateTime>) -> String {
+    value
+        .map(|value| value.format("%F %T").to_string())
+        .unwrap_or_default()
+}
+
+pub fn render_report(rows: &[ReportRow], format: OutputFormat) -> Result<String, ErrorKind> {
+    match format {
+        OutputFormat::Json => serde_json::to_string_pretty(rows)
+            .map_err(|err| ErrorKind::InvalidArgument(err.to_string())),
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for row in rows {
+                writer
+                    .serialize(row)
+                    .map_err(|err| ErrorKind::CouldNotWriteToCsv(err.to_string()))?;
+            }
+            let bytes = writer
+                .into_inner()
+                .map_err(|err| ErrorKind::CouldNotWriteToCsv(err.to_string()))?;
+            Ok(String::from_utf8_lossy(&bytes).to_string())
+        }
+        OutputFormat::Table => {
+            let with_dtu = rows.iter().any(|row| !row.dtu.is_empty());
+            let header = [
+                "dtu",
+                "inverter",
+                "channel",
+                "period",
+                "kWh",
+                "Δ total",
+                "peak P_AC",
+                "at",
+                "peak P_DC",
+                "at",
+                "eff. %",
+                "max °C",
+                "hours",
+            ];
+            let skip = usize::from(!with_dtu);
+            let mut table: Vec<Vec<String>> = vec![header
+                .iter()
+                .skip(skip)
+                .map(|column| column.to_string())
+                .collect()];
+            for row in rows {
+                table.push(vec![
+                    row.dtu.clone(),
+                    row.inverter.clone(),
+                    row.channel.clone(),
+                    row.period.clone(),
+                    format_value(row.energy_kwh, 3),
+                    format_value(row.yield_total_delta_kwh, 3),
+                    format_value(row.peak_p_ac_w, 1),
+                    format_time(row.peak_p_ac_at),
+                    format_value(row.peak_p_dc_w, 1),
+                    format_time(row.peak_p_dc_at),
+                    format_value(row.avg_efficiency, 1),
+                    format_value(row.max_temp, 1),
+                    format!("{:.2}", row.production_hours),
+                ]);
+                table.last_mut().unwrap().drain(..skip);
+            }
+            Ok(render_table(&table))
+        }
+    }
+}
+
+/// Left-align the cells of `table` in columns separated by two spaces.
+pub(crate) fn render_table(table: &[Vec<String>]) -> String {
+    let columns = table.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| {
+            table
+                .iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let mut out = String::new();
+    for row in table {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        out += line.join("  ").trim_end();
+        out.push('\n');
+    }
+    out
+}
+
+/// Run the `report` subcommand: `report [--dir <path>] [--period day|month|year]
+/// [--format table|csv|json] [--config <path>]`. The directory defaults to the
+/// configured `out_dir`.
+pub fn report_entrypoint(args: &[String]) -> Result<(), ErrorKind> {
+    let mut folder_path = None;
+    let mut config = None;
+    let mut period = ReportPeriod::Day;
+    let mut format = OutputFormat::Table;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or(ErrorKind::InvalidArgument(format!("{} needs a value", arg)))
+        };
+        match arg.as_str() {
+            "--dir" => folder_path = Some(value()?.clone()),
+            "--config" => config = Some(value()?.clone()),
+            "--period" => period = value()?.parse()?,
+            "--format" => format = value()?.parse()?,
+            _ => {
+                return Err(ErrorKind::InvalidArgument(format!(
+                    "unknown argument '{}'",
+                    arg
+                )))
+            }
+        }
+    }
+
+    let folder_path = out_dir(folder_path, config.as_deref())?;
+    let rows = build_report(&folder_path, period)?;
+    print!("{}", render_report(&rows, format)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::test_util::TempDir;
+
+    #[test]
+    fn daily_and_monthly_report() {
+        let temp_dir = TempDir::new("report");
+        let folder = Path::new(temp_dir.path());
+        fs::create_dir_all(folder.join("roof")).unwrap();
+        fs::write(
+            folder.join("roof/summary.csv"),
+            "timestamp,measured_at,P_AC,Temp,YieldTotal,YieldDay,P_DC,Efficiency\n\
+            2024-06-01 12:00:00,2024-06-01 12:00:00,100,30,10.0,500,104,96\n\
+            2024-06-01 12:10:00,2024-06-01 12:10:00,300,35,10.2,700,310,97\n\
+            2024-06-01 12:20:00,2024-06-01 12:20:00,0,31,10.3,800,0,0\n\
+            2024-06-02 12:00:00,2024-06-02 12:00:00,200,28,10.5,200,206,97\n",
+        )
+        .unwrap();
+        let folder_path = folder.to_str().unwrap();
+
+        let rows = build_report(folder_path, ReportPeriod::Day).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].period, "2024-06-01");
+        assert_eq!(rows[0].energy_kwh, Some(0.8));
+        assert_eq!(rows[0].peak_p_ac_w, Some(300.0));
+        assert_eq!(rows[0].max_temp, Some(35.0));
+        assert_eq!(rows[0].avg_efficiency, Some(96.5));
+        assert!((rows[0].production_hours - 20.0 / 60.0).abs() < 1e-9);
+
+        let rows = build_report(folder_path, ReportPeriod::Month).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].energy_kwh, Some(1.0));
+        assert!((rows[0].yield_total_delta_kwh.unwrap() - 0.5).abs() < 1e-9);
+
+        let table = render_report(&rows, OutputFormat::Table).unwrap();
+        assert!(table
+            .lines()
+            .nth(1)
+            .unwrap()
+            .starts_with("roof      summary  2024-06"));
+    }
+
+    #[test]
+    fn named_dtus() {
+        let temp_dir = TempDir::new("report-dtus");
+        let folder = Path::new(temp_dir.path());
+        for (dtu, inverter, yield_day) in [
+            ("garage", "InvA", 500),
+            ("garage", "InvB", 600),
+            ("roof", "InvA", 700),
+            ("roof", "InvB", 800),
+        ] {
+            let directory = folder.join(dtu).join(inverter).join("2024-06-01");
+            fs::create_dir_all(&directory).unwrap();
+            fs::write(
+                directory.join("summary.csv"),
+                format!(
+                    "timestamp,measured_at,P_AC,YieldDay\n2024-06-01 12:00:00,,100,{}\n",
+                    yield_day
+                ),
+            )
+            .unwrap();
+        }
+
+        let rows = build_report(folder.to_str().unwrap(), ReportPeriod::Day).unwrap();
+        let energy: Vec<(&str, &str, Option<f64>)> = rows
+            .iter()
+            .map(|row| (row.dtu.as_str(), row.inverter.as_str(), row.energy_kwh))
+            .collect();
+        assert_eq!(
+            energy,
+            [
+                ("garage", "InvA", Some(0.5)),
+                ("garage", "InvB", Some(0.6)),
+                ("roof", "InvA", Some(0.7)),
+                ("roof", "InvB", Some(0.8)),
+            ]
+        );
+        let table = render_report(&rows, OutputFormat::Table).unwrap();
+        assert!(table.lines().nth(1).unwrap().starts_with("garage  InvA"));
+    }
+}
@@ -0,0 +1,975 @@
+use crate::{
+    Channel, Config, Dataset, ErrorKind, Generic, Inverter, InverterList, InverterStatus, Live,
+};
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveTime, TimeZone, Timelike, Utc};
+
+use std::{
+    collections::HashMap,
+    f64::consts::PI,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+/// Where `simulate` writes its rows unless `--out` is given, away from the
+/// crawled rows in `out_dir`.
+pub const DEFAULT_SIMULATION_DIR: &str = "./simulated";
+
+/// Fields of channel 0 and of the inputs, in the order Ahoy reports them.
+pub(crate) const SUMMARY_FIELDS: [(&str, &str); 12] = [
+    ("U_AC", "V"),
+    ("I_AC", "A"),
+    ("P_AC", "W"),
+    ("F_AC", "Hz"),
+    ("PF_AC", ""),
+    ("Temp", "°C"),
+    ("YieldTotal", "kWh"),
+    ("YieldDay", "Wh"),
+    ("P_DC", "W"),
+    ("Efficiency", "%"),
+    ("Q_AC", "var"),
+    ("MaxPower", "W"),
+];
+pub(crate) const INPUT_FIELDS: [(&str, &str); 7] = [
+    ("U_DC", "V"),
+    ("I_DC", "A"),
+    ("P_DC", "W"),
+    ("YieldDay", "Wh"),
+    ("YieldTotal", "kWh"),
+    ("Irradiation", "%"),
+    ("MaxPower", "W"),
+];
+
+/// Seconds between the points the yield of a day is integrated over.
+const YIELD_STEP: i64 = 300;
+/// Minutes between two independent cloud conditions, interpolated in between.
+const CLOUD_MINUTES: i64 = 15;
+/// Cell temperature in °C at 800 W/m² and 20 °C air (nominal operating cell temperature).
+const NOCT: f64 = 45.0;
+/// Relative power change per °C cell temperature above 25 °C.
+const TEMPERATURE_COEFFICIENT: f64 = -0.0037;
+
+/// A string of panels connected to one input of an inverter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PvString {
+    pub name: String,
+    /// peak power in W, reported as `ch_max_pwr`
+    pub max_power: u16,
+    /// degrees from horizontal
+    pub tilt: f64,
+    /// degrees clockwise from north, 180 faces south
+    pub azimuth: f64,
+}
+
+impl PvString {
+    /// A string tilted by 30° facing south.
+    pub fn new(name: &str, max_power: u16) -> Self {
+        Self {
+            name: name.to_string(),
+            max_power,
+            tilt: 30.0,
+            azimuth: 180.0,
+        }
+    }
+
+    pub fn with_orientation(mut self, tilt: f64, azimuth: f64) -> Self {
+        self.tilt = tilt;
+        self.azimuth = azimuth;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatedInverter {
+    pub name: String,
+    pub serial: String,
+    pub strings: Vec<PvString>,
+    /// kWh produced before the start day of the plant
+    pub initial_yield: f64,
+}
+
+impl SimulatedInverter {
+    pub fn new(name: &str, serial: &str, strings: Vec<PvString>) -> Self {
+        Self {
+            name: name.to_string(),
+            serial: serial.to_string(),
+            strings,
+            initial_yield: 1000.0,
+        }
+    }
+}
+
+/// Where the sun is, in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SunPosition {
+    /// above the horizon, negative at night
+    pub elevation: f64,
+    /// clockwise from north
+    pub azimuth: f64,
+}
+
+/// Fractional year in radians and the equation of time in minutes at `utc`,
+/// after the NOAA approximation.
+fn solar_day(utc: DateTime<Utc>) -> (f64, f64) {
+    let hour = utc.hour() as f64 + utc.minute() as f64 / 60.0 + utc.second() as f64 / 3600.0;
+    let gamma = 2.0 * PI / 365.0 * (utc.ordinal() as f64 - 1.0 + (hour - 12.0) / 24.0);
+    let equation_of_time = 229.18
+        * (0.000075 + 0.001868 * gamma.cos()
+            - 0.032077 * gamma.sin()
+            - 0.014615 * (2.0 * gamma).cos()
+            - 0.040849 * (2.0 * gamma).sin());
+    (gamma, equation_of_time)
+}
+
+/// Declination of the sun in radians.
+fn declination(gamma: f64) -> f64 {
+    0.006918 - 0.399912 * gamma.cos() + 0.070257 * gamma.sin() - 0.006758 * (2.0 * gamma).cos()
+        + 0.000907 * (2.0 * gamma).sin()
+        - 0.002697 * (3.0 * gamma).cos()
+        + 0.00148 * (3.0 * gamma).sin()
+}
+
+/// The position of the sun seen from `latitude`/`longitude` at `time`.
+pub fn sun_position(latitude: f64, longitude: f64, time: DateTime<Local>) -> SunPosition {
+    let utc = time.with_timezone(&Utc);
+    let (gamma, equation_of_time) = solar_day(utc);
+    let declination = declination(gamma);
+    let minutes = utc.hour() as f64 * 60.0 + utc.minute() as f64 + utc.second() as f64 / 60.0;
+    let true_solar_time = minutes + equation_of_time + 4.0 * longitude;
+    let hour_angle = (true_solar_time / 4.0 - 180.0).to_radians();
+    let latitude = latitude.to_radians();
+
+    let cos_zenith = (latitude.sin() * declination.sin()
+        + latitude.cos() * declination.cos() * hour_angle.cos())
+    .clamp(-1.0, 1.0);
+    let azimuth = hour_angle
+        .sin()
+        .atan2(hour_angle.cos() * latitude.sin() - declination.tan() * latitude.cos())
+        .to_degrees()
+        + 180.0;
+    SunPosition {
+        elevation: 90.0 - cos_zenith.acos().to_degrees(),
+        azimuth: azimuth.rem_euclid(360.0),
+    }
+}
+
+/// Sunrise and sunset at `latitude`/`longitude` on `date`, `None` during
+/// polar day or night.
+pub fn sunrise_sunset(
+    latitude: f64,
+    longitude: f64,
+    date: NaiveDate,
+) -> Option<(DateTime<Local>, DateTime<Local>)> {
+    let noon = Utc.from_utc_datetime(&date.and_time(NaiveTime::from_hms_opt(12, 0, 0)?));
+    let (gamma, equation_of_time) = solar_day(noon);
+    let declination = declination(gamma);
+    let latitude = latitude.to_radians();
+    // the sun's disc touches the horizon, including refraction
+    let cos_hour_angle = 90.833f64.to_radians().cos() / (latitude.cos() * declination.cos())
+        - latitude.tan() * declination.tan();
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+    let at = |minutes: f64| {
+        let midnight = Utc.from_utc_datetime(&date.and_time(NaiveTime::MIN));
+        (midnight + chrono::Duration::seconds((minutes * 60.0) as i64)).with_timezone(&Local)
+    };
+    Some((
+        at(720.0 - 4.0 * (longitude + hour_angle) - equation_of_time),
+        at(720.0 - 4.0 * (longitude - hour_angle) - equation_of_time),
+    ))
+}
+
+/// Direct normal and diffuse horizontal irradiance of a clear sky in W/m²,
+/// from the air mass the light passes (Kasten-Young, Meinel).
+fn clear_sky(sun: SunPosition) -> (f64, f64) {
+    if sun.elevation <= 0.0 {
+        return (0.0, 0.0);
+    }
+    let zenith = 90.0 - sun.elevation;
+    let air_mass = 1.0 / (zenith.to_radians().cos() + 0.50572 * (96.07995 - zenith).powf(-1.6364));
+    let direct = 1353.0 * 0.7f64.powf(air_mass.powf(0.678));
+    (direct, 0.1 * direct)
+}
+
+/// A number in 0..1 for `value`, always the same for the same seed.
+fn noise(seed: u64, value: i64) -> f64 {
+    // splitmix64
+    let mut x = seed ^ (value as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^= x >> 31;
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// DC power in W of panels with a peak power of `max_power` W (at 1000 W/m²
+/// and 25 °C) under an irradiance of `plane` W/m² at a cell temperature of `cell` °C.
+fn dc_power(max_power: u16, plane: f64, cell: f64) -> f64 {
+    let temperature_factor = 1.0 + TEMPERATURE_COEFFICIENT * (cell - 25.0);
+    (max_power as f64 * plane / 1000.0 * temperature_factor).clamp(0.0, max_power as f64)
+}
+
+/// What one input reports.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputReading {
+    pub voltage: f64,
+    pub current: f64,
+    pub power: f64,
+    /// Wh since midnight
+    pub yield_day: f64,
+    /// kWh
+    pub yield_total: f64,
+    /// power in percent of the peak power
+    pub irradiation: f64,
+    pub max_power: f64,
+}
+
+/// What an inverter reports, the summary and its inputs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InverterReading {
+    pub time: DateTime<Local>,
+    pub ac_voltage: f64,
+    pub ac_current: f64,
+    pub ac_power: f64,
+    pub frequency: f64,
+    pub power_factor: f64,
+    pub temperature: f64,
+    pub yield_total: f64,
+    pub yield_day: f64,
+    pub dc_power: f64,
+    pub efficiency: f64,
+    pub max_power: f64,
+    pub inputs: Vec<InputReading>,
+}
+
+impl InverterReading {
+    /// The values in the order of [`SUMMARY_FIELDS`], followed by one row per
+    /// input in the order of [`INPUT_FIELDS`], as in `InverterStatus::ch`.
+    pub fn ch(&self) -> Vec<Vec<f32>> {
+        let round = |value: f64, digits: i32| {
+            let factor = 10f64.powi(digits);
+            ((value * factor).round() / factor) as f32
+        };
+        let mut ch = vec![vec![
+            round(self.ac_voltage, 1),
+            round(self.ac_current, 2),
+            round(self.ac_power, 1),
+            round(self.frequency, 2),
+            round(self.power_factor, 3),
+            round(self.temperature, 1),
+            round(self.yield_total, 3),
+            round(self.yield_day, 0),
+            round(self.dc_power, 1),
+            round(self.efficiency, 3),
+            0.0,
+            round(self.max_power, 0),
+        ]];
+        ch.extend(self.inputs.iter().map(|input| {
+            vec![
+                round(input.voltage, 1),
+                round(input.current, 2),
+                round(input.power, 1),
+                round(input.yield_day, 0),
+                round(input.yield_total, 3),
+                round(input.irradiation, 3),
+                round(input.max_power, 0),
+            ]
+        }));
+        ch
+    }
+}
+
+/// Cumulated Wh of every input of every inverter at each [`YIELD_STEP`] of a day.
+#[derive(Debug)]
+struct DayYield {
+    midnight: DateTime<Local>,
+    /// by inverter, input and step
+    cumulated: Vec<Vec<Vec<f64>>>,
+}
+
+impl DayYield {
+    fn total(&self, inverter: usize, input: usize) -> f64 {
+        self.cumulated[inverter][input]
+            .last()
+            .copied()
+            .unwrap_or(0.0)
+    }
+}
+
+/// A simulated PV plant: inverters with strings of panels at a location,
+/// under a clear sky dimmed by clouds. The same plant, seed and time always
+/// give the same readings, in any order.
+#[derive(Debug, Clone)]
+pub struct PvPlant {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub inverters: Vec<SimulatedInverter>,
+    /// 0 for a clear sky, 1 for days on which clouds block most of the sun
+    pub cloudiness: f64,
+    pub seed: u64,
+    /// `YieldTotal` is each inverter's `initial_yield` at the start of this day
+    pub start: NaiveDate,
+    // shared between clones, the yield of a day only has to be integrated once
+    days: Arc<Mutex<HashMap<NaiveDate, Arc<DayYield>>>>,
+}
+
+impl PvPlant {
+    /// A plant at `latitude`/`longitude` without inverters.
+    pub fn new(latitude: f64, longitude: f64) -> Self {
+        Self {
+            latitude,
+            longitude,
+            inverters: Vec::new(),
+            cloudiness: 0.3,
+            seed: 0,
+            start: Local::now().date_naive(),
+            days: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Add `count` inverters with `strings` strings of `max_power` W each.
+    pub fn with_inverters(mut self, count: usize, strings: usize, max_power: u16) -> Self {
+        for _ in 0..count {
+            let id = self.inverters.len();
+            self.inverters.push(SimulatedInverter::new(
+                &format!("Inverter {}", id),
+                &format!("1161{:08}", 83771000 + id),
+                (1..=strings)
+                    .map(|string| PvString::new(&format!("Input {}", string), max_power))
+                    .collect(),
+            ));
+        }
+        self.reset()
+    }
+
+    /// A plant whose solar noon is around noon of the local time zone, useful
+    /// when only the shape of the day matters.
+    pub fn in_local_time_zone(latitude: f64) -> Self {
+        let offset = Local::now().offset().local_minus_utc() as f64;
+        Self::new(latitude, offset / 240.0)
+    }
+
+    pub fn with_inverter(mut self, inverter: SimulatedInverter) -> Self {
+        self.inverters.push(inverter);
+        self.reset()
+    }
+
+    /// Set the peak power of every string in W.
+    pub fn with_max_power(mut self, max_power: u16) -> Self {
+        for inverter in &mut self.inverters {
+            for string in &mut inverter.strings {
+                string.max_power = max_power;
+            }
+        }
+        self.reset()
+    }
+
+    pub fn with_cloudiness(mut self, cloudiness: f64) -> Self {
+        self.cloudiness = cloudiness.clamp(0.0, 1.0);
+        self.reset()
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self.reset()
+    }
+
+    pub fn starting_on(mut self, start: NaiveDate) -> Self {
+        self.start = start;
+        self.reset()
+    }
+
+    /// Forget integrated days after the plant changed.
+    fn reset(mut self) -> Self {
+        self.days = Arc::new(Mutex::new(HashMap::new()));
+        self
+    }
+
+    pub fn sun_position(&self, time: DateTime<Local>) -> SunPosition {
+        sun_position(self.latitude, self.longitude, time)
+    }
+
+    pub fn sunrise_sunset(&self, date: NaiveDate) -> Option<(DateTime<Local>, DateTime<Local>)> {
+        sunrise_sunset(self.latitude, self.longitude, date)
+    }
+
+    /// Share of the direct light that gets through the clouds at `time`.
+    fn clearness(&self, time: DateTime<Local>) -> f64 {
+        let step = CLOUD_MINUTES * 60;
+        let timestamp = time.timestamp();
+        let knot = timestamp.div_euclid(step);
+        let position = timestamp.rem_euclid(step) as f64 / step as f64;
+        let smooth = position * position * (3.0 - 2.0 * position);
+        let cover = noise(self.seed, knot) * (1.0 - smooth) + noise(self.seed, knot + 1) * smooth;
+        // some days are clearer than others
+        let day = time.date_naive().num_days_from_ce() as i64;
+        let overcast = noise(self.seed ^ 0xDA7, day);
+        1.0 - 0.9 * self.cloudiness * (0.4 + 0.6 * overcast) * cover
+    }
+
+    /// Air temperature in °C, coldest in January (or July in the south) and
+    /// warmest in the afternoon.
+    fn air_temperature(&self, time: DateTime<Local>) -> f64 {
+        let season = 2.0 * PI * (time.ordinal() as f64 - 20.0) / 365.0;
+        let hemisphere = if self.latitude < 0.0 { -1.0 } else { 1.0 };
+        let hour = time.hour() as f64 + time.minute() as f64 / 60.0;
+        10.0 - 9.0 * hemisphere * season.cos() + 5.0 * (2.0 * PI * (hour - 9.0) / 24.0).sin()
+    }
+
+    /// Irradiance on the panels of `string` in W/m² and the temperature of their cells.
+    fn irradiance(&self, string: &PvString, time: DateTime<Local>) -> (f64, f64) {
+        let sun = self.sun_position(time);
+        let (direct, diffuse) = clear_sky(sun);
+        let zenith = (90.0 - sun.elevation).to_radians();
+        let tilt = string.tilt.to_radians();
+        let incidence = zenith.cos() * tilt.cos()
+            + zenith.sin() * tilt.sin() * (sun.azimuth - string.azimuth).to_radians().cos();
+        let plane =
+            direct * incidence.max(0.0) * self.clearness(time) + diffuse * (1.0 + tilt.cos()) / 2.0;
+        let cell = self.air_temperature(time) + plane * (NOCT - 20.0) / 800.0;
+        (plane, cell)
+    }
+
+    /// DC power of `string` in W.
+    fn string_power(&self, string: &PvString, time: DateTime<Local>) -> f64 {
+        let (plane, cell) = self.irradiance(string, time);
+        dc_power(string.max_power, plane, cell)
+    }
+
+    fn midnight(date: NaiveDate) -> DateTime<Local> {
+        let midnight = date.and_time(NaiveTime::MIN);
+        Local
+            .from_local_datetime(&midnight)
+            .earliest()
+            .unwrap_or_else(|| Local.from_utc_datetime(&midnight))
+    }
+
+    fn day(&self, date: NaiveDate) -> Arc<DayYield> {
+        if let Some(day) = self.lock().get(&date) {
+            return day.clone();
+        }
+        let midnight = Self::midnight(date);
+        let steps = (Self::midnight(date + chrono::Duration::days(1)) - midnight).num_seconds()
+            / YIELD_STEP;
+        let cumulated = self
+            .inverters
+            .iter()
+            .map(|inverter| {
+                inverter
+                    .strings
+                    .iter()
+                    .map(|string| {
+                        let mut cumulated = vec![0.0];
+                        let mut previous = self.string_power(string, midnight);
+                        for step in 1..=steps {
+                            let time = midnight + chrono::Duration::seconds(step * YIELD_STEP);
+                            let power = self.string_power(string, time);
+                            let energy = (previous + power) / 2.0 * YIELD_STEP as f64 / 3600.0;
+                            cumulated.push(cumulated[cumulated.len() - 1] + energy);
+                            previous = power;
+                        }
+                        cumulated
+                    })
+                    .collect()
+            })
+            .collect();
+        let day = Arc::new(DayYield {
+            midnight,
+            cumulated,
+        });
+        self.lock().insert(date, day.clone());
+        day
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<NaiveDate, Arc<DayYield>>> {
+        self.days
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Wh produced by input `input` of `inverter` from midnight until `time`.
+    fn yield_day(&self, inverter: usize, input: usize, time: DateTime<Local>) -> f64 {
+        let day = self.day(time.date_naive());
+        let seconds = (time - day.midnight).num_seconds().max(0);
+        let cumulated = &day.cumulated[inverter][input];
+        let step = ((seconds / YIELD_STEP) as usize).min(cumulated.len() - 1);
+        let string = &self.inverters[inverter].strings[input];
+        let step_time = day.midnight + chrono::Duration::seconds(step as i64 * YIELD_STEP);
+        let partial = (self.string_power(string, step_time) + self.string_power(string, time))
+            / 2.0
+            * (time - step_time).num_seconds().max(0) as f64
+            / 3600.0;
+        cumulated[step] + partial
+    }
+
+    /// kWh produced by input `input` of `inverter` until `time`. It grows
+    /// with every day after the start day and shrinks for days before it.
+    fn yield_total(&self, inverter: usize, input: usize, time: DateTime<Local>) -> f64 {
+        let date = time.date_naive();
+        let strings = self.inverters[inverter].strings.len() as f64;
+        let mut total = self.inverters[inverter].initial_yield / strings;
+        let (first, last, sign) = match date >= self.start {
+            true => (self.start, date, 1.0),
+            false => (date, self.start, -1.0),
+        };
+        for day in first.iter_days().take_while(|day| *day < last) {
+            total += sign * self.day(day).total(inverter, input) / 1000.0;
+        }
+        total + self.yield_day(inverter, input, time) / 1000.0
+    }
+
+    /// What `inverter` reports at `time`, `None` for an unknown inverter.
+    pub fn reading(&self, inverter: usize, time: DateTime<Local>) -> Option<InverterReading> {
+        let simulated = self.inverters.get(inverter)?;
+        let inputs: Vec<InputReading> = simulated
+            .strings
+            .iter()
+            .enumerate()
+            .map(|(input, string)| {
+                let (plane, cell) = self.irradiance(string, time);
+                let power = self.string_power(string, time);
+                // the voltage of the panel at its maximum power point
+                let voltage = match power > 0.0 {
+                    true => {
+                        37.0 * (1.0 - 0.0035 * (cell - 25.0))
+                            * (0.9 + 0.1 * (plane / 1000.0).min(1.0))
+                    }
+                    false => 0.0,
+                };
+                InputReading {
+                    voltage,
+                    current: if voltage > 0.0 { power / voltage } else { 0.0 },
+                    power,
+                    yield_day: self.yield_day(inverter, input, time),
+                    yield_total: self.yield_total(inverter, input, time),
+                    irradiation: power / string.max_power.max(1) as f64 * 100.0,
+                    max_power: string.max_power as f64,
+                }
+            })
+            .collect();
+
+        let max_power: f64 = inputs.iter().map(|input| input.max_power).sum();
+        let dc_power: f64 = inputs.iter().map(|input| input.power).sum();
+        let load = dc_power / max_power.max(1.0);
+        // inverters are least efficient at low load
+        let efficiency = match dc_power > 0.0 {
+            true => 0.965 - 0.025 / (20.0 * load + 1.0),
+            false => 0.0,
+        };
+        let ac_power = dc_power * efficiency;
+        let producing = ac_power > 0.0;
+        let jitter = noise(self.seed ^ inverter as u64, time.timestamp()) - 0.5;
+        let ac_voltage = if producing { 230.0 + 4.0 * jitter } else { 0.0 };
+        Some(InverterReading {
+            time,
+            ac_voltage,
+            ac_current: if producing {
+                ac_power / ac_voltage
+            } else {
+                0.0
+            },
+            ac_power,
+            frequency: if producing { 50.0 + 0.04 * jitter } else { 0.0 },
+            power_factor: if producing { 1.0 } else { 0.0 },
+            temperature: self.air_temperature(time) + 5.0 + 25.0 * load,
+            yield_total: inputs.iter().map(|input| input.yield_total).sum(),
+            yield_day: inputs.iter().map(|input| input.yield_day).sum(),
+            dc_power,
+            efficiency: efficiency * 100.0,
+            max_power,
+            inputs,
+        })
+    }
+
+    /// The inverters as the DTU lists them, polled every `interval` seconds.
+    pub fn inverter_list(&self, interval: u64) -> InverterList {
+        InverterList {
+            inverter: self
+                .inverters
+                .iter()
+                .enumerate()
+                .map(|(id, inverter)| Inverter {
+                    enabled: true,
+                    id: id as u8,
+                    name: inverter.name.clone(),
+                    serial: inverter.serial.clone(),
+                    channels: inverter.strings.len() as u8,
+                    version: "10012".to_string(),
+                    ch_yield_cor: vec![0.0; inverter.strings.len()],
+                    ch_name: inverter
+                        .strings
+                        .iter()
+                        .map(|string| string.name.clone())
+                        .collect(),
+                    ch_max_pwr: inverter
+                        .strings
+                        .iter()
+                        .map(|string| Some(string.max_power))
+                        .collect(),
+                    ..Inverter::default()
+                })
+                .collect(),
+            interval: interval.to_string(),
+            retries: "5".to_string(),
+            max_num_inverters: self.inverters.len().max(4) as u8,
+            yld_eff: 1.0,
+            ..InverterList::default()
+        }
+    }
+
+    /// The field names and units, with `generic` describing the DTU.
+    pub fn live(&self, generic: Generic, refresh: u32) -> Live {
+        Live {
+            generic,
+            refresh,
+            ch0_fld_units: SUMMARY_FIELDS
+                .iter()
+                .map(|(_, unit)| unit.to_string())
+                .collect(),
+            ch0_fld_names: SUMMARY_FIELDS
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect(),
+            fld_units: INPUT_FIELDS
+                .iter()
+                .map(|(_, unit)| unit.to_string())
+                .collect(),
+            fld_names: INPUT_FIELDS
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect(),
+            iv: vec![true; self.inverters.len()],
+            ..Live::default()
+        }
+    }
+
+    /// The status of `inverter` as the DTU reports it after polling it at `time`.
+    pub fn status(&self, inverter: usize, time: DateTime<Local>) -> Option<InverterStatus> {
+        let reading = self.reading(inverter, time)?;
+        let simulated = &self.inverters[inverter];
+        let mut ch_name = vec!["AC".to_string()];
+        ch_name.extend(simulated.strings.iter().map(|string| string.name.clone()));
+        let mut ch_max_pwr = vec![None];
+        ch_max_pwr.extend(
+            simulated
+                .strings
+                .iter()
+                .map(|string| Some(string.max_power)),
+        );
+        Some(InverterStatus {
+            id: inverter as u8,
+            enabled: true,
+            name: simulated.name.clone(),
+            serial: simulated.serial.clone(),
+            version: "10012".to_string(),
+            power_limit_read: 100,
+            power_limit_ack: true,
+            ts_last_success: time.timestamp().max(0) as u64,
+            generation: 1,
+            status: if reading.ac_power > 0.0 { 3 } else { 0 },
+            alarm_cnt: 0,
+            ch: reading.ch(),
+            ch_name,
+            ch_max_pwr,
+            ..InverterStatus::default()
+        })
+    }
+
+    /// The datasets of `inverter` with one row per time in `times`, as if it
+    /// had been crawled at each of them.
+    pub fn datasets(&self, inverter: usize, times: &[DateTime<Local>]) -> Vec<(Channel, Dataset)> {
+        let Some(simulated) = self.inverters.get(inverter) else {
+            return Vec::new();
+        };
+        let live = self.live(Generic::default(), 0);
+        let mut datasets = vec![(
+            Channel::Summary,
+            Dataset::new(&live.ch0_fld_names, &live.ch0_fld_units),
+        )];
+        datasets.extend((0..simulated.strings.len()).map(|input| {
+            (
+                Channel::Input(input as u8),
+                Dataset::new(&live.fld_names, &live.fld_units),
+            )
+        }));
+        for time in times {
+            let Some(status) = self.status(inverter, *time) else {
+                continue;
+            };
+            let fields = status.fields(&live, simulated.strings.len(), None);
+            for ((_, dataset), values) in datasets.iter_mut().zip(&fields) {
+                dataset.insert_row(values, time, Some(*time));
+            }
+        }
+        datasets
+    }
+
+    /// Times every `step` seconds from sunrise to sunset of `date`, when a
+    /// DTU talks to its inverters.
+    pub fn daylight_times(&self, date: NaiveDate, step: i64) -> Vec<DateTime<Local>> {
+        let Some((sunrise, sunset)) = self.sunrise_sunset(date) else {
+            return Vec::new();
+        };
+        let step = chrono::Duration::seconds(step.max(1));
+        let mut times = Vec::new();
+        let mut time = sunrise;
+        while time <= sunset {
+            times.push(time);
+            time += step;
+        }
+        times
+    }
+}
+
+fn parsed<T: FromStr>(arg: &str, value: &str) -> Result<T, ErrorKind> {
+    value
+        .parse()
+        .map_err(|_| ErrorKind::InvalidArgument(format!("invalid value '{}' for {}", value, arg)))
+}
+
+/// `simulate [--from 2024-06-01] [--to 2024-06-30] [--step 300]
+/// [--latitude 48.1] [--longitude 11.6] [--inverters 1] [--strings 2]
+/// [--max-power 400] [--cloudiness 0.3] [--seed 0] [--out ./simulated]
+/// [--config <path>]` stores simulated rows from sunrise to sunset of every day,
+/// today if no days are given. The rows go to `--out` in the `[storage]` format
+/// of the configuration.
+pub async fn simulate_entrypoint(args: &[String]) -> Result<(), ErrorKind> {
+    let (mut out, mut config) = (DEFAULT_SIMULATION_DIR.to_string(), None);
+    let today = Local::now().date_naive();
+    let (mut from, mut to) = (None, None);
+    let (mut step, mut latitude, mut longitude) = (300, 48.1, 11.6);
+    let (mut inverters, mut strings, mut max_power) = (1, 2, 400);
+    let (mut cloudiness, mut seed) = (0.3, 0);
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or(ErrorKind::InvalidArgument(format!("{} needs a value", arg)));
+        match arg.as_str() {
+            "--from" => from = Some(parsed(arg, value?)?),
+            "--to" => to = Some(parsed(arg, value?)?),
+            "--step" => step = parsed(arg, value?)?,
+            "--latitude" => latitude = parsed(arg, value?)?,
+            "--longitude" => longitude = parsed(arg, value?)?,
+            "--inverters" => inverters = parsed(arg, value?)?,
+            "--strings" => strings = parsed(arg, value?)?,
+            "--max-power" => max_power = parsed(arg, value?)?,
+            "--cloudiness" => cloudiness = parsed(arg, value?)?,
+            "--seed" => seed = parsed(arg, value?)?,
+            "--out" => out = value?.clone(),
+            "--config" => config = Some(value?.clone()),
+            _ => {
+                return Err(ErrorKind::InvalidArgument(format!(
+                    "unknown argument '{}'",
+                    arg
+                )))
+            }
+        }
+    }
+    let from = from.or(to).unwrap_or(today);
+    let to = to.unwrap_or(from.max(today));
+    if to < from {
+        return Err(ErrorKind::InvalidArgument(
+            "--to is before --from".to_string(),
+        ));
+    }
+
+    let config = Config::load(config.as_deref())?;
+    if config.out_dir == out {
+        return Err(ErrorKind::InvalidArgument(format!(
+            "--out {} is the out_dir of the crawler, choose another directory",
+            out
+        )));
+    }
+    let mut storage = config.storage.open(&out, "")?;
+
+    let plant = PvPlant::new(latitude, longitude)
+        .with_inverters(inverters, strings, max_power)
+        .with_cloudiness(cloudiness)
+        .with_seed(seed)
+        .starting_on(from);
+    let list = plant.inverter_list(step as u64);
+    for date in from.iter_days().take_while(|date| *date <= to) {
+        let times = plant.daylight_times(date, step);
+        for inverter in &list.inverter {
+            for (channel, dataset) in plant.datasets(inverter.id as usize, &times) {
+                storage.store(inverter, &channel, &dataset).await?;
+            }
+        }
+        log::info!("simulated {} with {} rows per dataset", date, times.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::test_util::TempDir;
+
+    fn at(date: NaiveDate, hour: u32, minute: u32) -> DateTime<Local> {
+        Utc.from_utc_datetime(&date.and_hms_opt(hour, minute, 0).unwrap())
+            .with_timezone(&Local)
+    }
+
+    fn june() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 6, 21).unwrap()
+    }
+
+    /// A plant in Munich, where the sun is highest at about 11:15 UTC.
+    fn munich() -> PvPlant {
+        PvPlant::new(48.14, 11.58)
+            .with_inverters(2, 2, 400)
+            .with_seed(7)
+            .starting_on(june())
+    }
+
+    #[test]
+    fn sun_times() {
+        let (sunrise, sunset) = sunrise_sunset(48.14, 11.58, june()).unwrap();
+        let minutes = |time: DateTime<Local>, hour: u32, minute: u32| {
+            (time - at(june(), hour, minute)).num_minutes().abs()
+        };
+        assert!(minutes(sunrise, 3, 12) <= 3, "{}", sunrise);
+        assert!(minutes(sunset, 19, 17) <= 3, "{}", sunset);
+        assert!(sun_position(48.14, 11.58, at(june(), 11, 15)).elevation > 64.0);
+        assert!(sun_position(48.14, 11.58, at(june(), 0, 0)).elevation < 0.0);
+        // the sun does not set north of the arctic circle in june
+        assert_eq!(sunrise_sunset(78.2, 15.6, june()), None);
+    }
+
+    #[test]
+    fn plausible_day() {
+        let plant = munich();
+        let power = |hour| plant.reading(0, at(june(), hour, 15)).unwrap().ac_power;
+        assert_eq!(power(1), 0.0);
+        assert!(power(7) > 0.0);
+        assert!(power(11) > power(7));
+        assert!(power(11) > 400.0 && power(11) <= 800.0);
+
+        let noon = plant.reading(0, at(june(), 11, 15)).unwrap();
+        assert!(noon
+            .inputs
+            .iter()
+            .all(|input| input.power <= input.max_power));
+        assert!(noon.efficiency > 90.0 && noon.efficiency < 100.0);
+        assert!(noon.temperature > 20.0);
+
+        // the same seed gives the same readings, another seed other clouds
+        let clouded = munich().with_cloudiness(1.0);
+        assert_eq!(plant.reading(0, noon.time), munich().reading(0, noon.time));
+        assert_ne!(
+            clouded.reading(0, noon.time),
+            clouded.clone().with_seed(8).reading(0, noon.time)
+        );
+        assert!(plant.reading(2, noon.time).is_none());
+    }
+
+    #[test]
+    fn hotter_cells_produce_less() {
+        assert_eq!(dc_power(400, 1000.0, 25.0), 400.0);
+        assert!(dc_power(400, 1000.0, 60.0) < 0.9 * 400.0);
+        assert!(dc_power(400, 1000.0, 0.0) <= 400.0);
+        assert_eq!(dc_power(400, 0.0, 25.0), 0.0);
+    }
+
+    #[test]
+    fn panels_face_the_sun() {
+        let time = at(june(), 2, 0);
+        let south = PvString::new("south", 400);
+        let north = PvString::new("north", 400).with_orientation(30.0, 0.0);
+        let sydney = PvPlant::new(-33.87, 151.21).with_inverter(SimulatedInverter::new(
+            "roof",
+            "1",
+            vec![south, north],
+        ));
+        let reading = sydney.reading(0, time).unwrap();
+        assert!(reading.inputs[1].power > reading.inputs[0].power);
+    }
+
+    #[test]
+    fn yields() {
+        let plant = munich();
+        let reading = |date: NaiveDate, hour: i64, minute: i64| {
+            let midnight = PvPlant::midnight(date);
+            plant
+                .reading(0, midnight + chrono::Duration::minutes(hour * 60 + minute))
+                .unwrap()
+        };
+        let day = june();
+        let next_day = day.succ_opt().unwrap();
+
+        assert_eq!(reading(day, 0, 0).yield_day, 0.0);
+        assert!((reading(day, 0, 0).yield_total - 1000.0).abs() < 1e-9);
+        let evening = reading(day, 23, 59);
+        assert!(evening.yield_day > 3000.0, "{}", evening.yield_day);
+        // YieldDay starts over at midnight, YieldTotal keeps growing
+        let morning = reading(next_day, 0, 1);
+        assert!(morning.yield_day < 1.0);
+        assert!(morning.yield_total >= evening.yield_total);
+
+        let mut previous = reading(day.pred_opt().unwrap(), 0, 0);
+        for minutes in (0..3 * 24 * 60).step_by(37) {
+            let current = reading(day.pred_opt().unwrap(), 0, minutes);
+            assert!(current.yield_total >= previous.yield_total, "{}", minutes);
+            assert!(
+                current.yield_day >= previous.yield_day || current.yield_day < 1.0,
+                "{}",
+                minutes
+            );
+            previous = current;
+        }
+        let input_total: f64 = evening.inputs.iter().map(|input| input.yield_total).sum();
+        assert!((input_total - evening.yield_total).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn dtu_responses_and_datasets() {
+        let plant = munich();
+        let list = plant.inverter_list(60);
+        assert_eq!(list.inverter[1].ch_max_pwr, vec![Some(400), Some(400)]);
+        let live = plant.live(Generic::default(), 60);
+        let time = at(june(), 11, 0);
+        let status = plant.status(1, time).unwrap();
+
+        // what the mock DTU serves parses like a real DTU's answer
+        let json = serde_json::to_string(&status).unwrap();
+        let parsed: InverterStatus = serde_json::from_str(&json).unwrap();
+        let fields = parsed.fields(&live, 2, None);
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0]["P_AC"].unit, "W");
+        assert!(fields[0]["P_AC"].value > 0.0);
+
+        let times = plant.daylight_times(june(), 600);
+        assert!(times.len() > 90);
+        let datasets = plant.datasets(1, &times);
+        assert_eq!(datasets.len(), 3);
+        assert_eq!(datasets[2].0, Channel::Input(1));
+        for (_, dataset) in &datasets {
+            assert_eq!(dataset.rows().len(), times.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn simulate_into_separate_directory() {
+        let folder = TempDir::new("simulate");
+        let out_dir = folder.join("out");
+        let config_file = folder.join("ahoy.toml");
+        std::fs::create_dir_all(folder.path()).unwrap();
+        std::fs::write(&config_file, format!("out_dir = {:?}\n", out_dir)).unwrap();
+        let args = |out: &str| {
+            ["--from", "2024-06-21", "--step", "3600", "--out", out]
+                .iter()
+                .chain(&["--config", config_file.as_str()])
+                .map(|arg| arg.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(simulate_entrypoint(&args(&out_dir)).await.is_err());
+        let simulated = folder.join("simulated");
+        simulate_entrypoint(&args(&simulated)).await.unwrap();
+        assert!(std::fs::read_dir(&simulated).unwrap().next().is_some());
+        assert!(!std::path::Path::new(&out_dir).exists());
+    }
+}
@@ -0,0 +1,118 @@
+use super::mqtt_sink::topic_level;
+use crate::{Channel, CrawledInverter, MqttConfig, PayloadFormat};
+
+use serde_json::{json, Value};
+
+/// Map a unit reported by the DTU to the home assistant `device_class` and
+/// `state_class` of the sensor.
+pub fn sensor_classes(unit: &str) -> (Option<&'static str>, Option<&'static str>) {
+    match unit {
+        "W" => (Some("power"), Some("measurement")),
+        "var" => (Some("reactive_power"), Some("measurement")),
+        "Wh" | "kWh" => (Some("energy"), Some("total_increasing")),
+        "V" => (Some("voltage"), Some("measurement")),
+        "A" => (Some("current"), Some("measurement")),
+        "Hz" => (Some("frequency"), Some("measurement")),
+        "°C" => (Some("temperature"), Some("measurement")),
+        // the power factor is the only field without unit
+        "" => (Some("power_factor"), Some("measurement")),
+        _ => (None, Some("measurement")),
+    }
+}
+
+/// Retained discovery configs announcing one device per inverter with one
+/// sensor per field, published below `discovery_prefix`.
+pub fn discovery_messages(
+    config: &MqttConfig,
+    discovery_prefix: &str,
+    inverter: &CrawledInverter,
+) -> Vec<(String, String)> {
+    let original_inverter = inverter.inverter();
+    let node_id = format!("ahoy_{}", original_inverter.serial);
+    let device = json!({
+        "identifiers": [node_id],
+        "name": inverter.name,
+        "manufacturer": "Hoymiles",
+        "serial_number": original_inverter.serial,
+        "sw_version": original_inverter.version,
+    });
+
+    let mut messages = Vec::new();
+    for (channel, dataset) in inverter.datasets() {
+        let channel_label = match (channel, channel.name(original_inverter)) {
+            (Channel::Summary, _) => None,
+            (_, Some(name)) if !name.is_empty() => Some(name.to_string()),
+            (Channel::Input(index), _) => Some(format!("Input {}", index + 1)),
+        };
+        for field in dataset.fields() {
+            let object_id = format!("{}_{}", channel, topic_level(&field.name));
+            let (device_class, state_class) = sensor_classes(&field.unit);
+
+            let mut sensor = json!({
+                "name": match &channel_label {
+                    Some(label) => format!("{} {}", label, field.name),
+                    None => field.name.clone(),
+                },
+                "unique_id": format!("{}_{}", node_id, object_id),
+                "state_topic": config.state_topic(inverter, &channel, &field.name),
+                "availability_topic": config.availability_topic(),
+                "device": device,
+            });
+            if !field.unit.is_empty() {
+                sensor["unit_of_measurement"] = json!(field.unit);
+            }
+            if let Some(device_class) = device_class {
+                sensor["device_class"] = json!(device_class);
+            }
+            if let Some(state_class) = state_class {
+                sensor["state_class"] = json!(state_class);
+            }
+            if config.payload_format == PayloadFormat::Json {
+                sensor["value_template"] = Value::from("{{ value_json.value }}");
+            }
+
+            messages.push((
+                format!(
+                    "{}/sensor/{}/{}/config",
+                    discovery_prefix, node_id, object_id
+                ),
+                sensor.to_string(),
+            ));
+        }
+    }
+    messages
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::AhoyApi;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn discovery() {
+        let inverter = CrawledInverter::fetch(Arc::new(AhoyApi::offline()), 0)
+            .await
+            .unwrap();
+        let config = MqttConfig::new("localhost".to_string());
+
+        let messages = discovery_messages(&config, "homeassistant", &inverter);
+        let (_, payload) = messages
+            .iter()
+            .find(|(topic, _)| topic == "homeassistant/sensor/ahoy_114184511809/1_YieldDay/config")
+            .unwrap();
+        let sensor: Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(sensor["name"], "B YieldDay");
+        assert_eq!(sensor["device_class"], "energy");
+        assert_eq!(sensor["state_class"], "total_increasing");
+        assert_eq!(sensor["unit_of_measurement"], "Wh");
+        assert_eq!(sensor["state_topic"], "ahoy/PV Microinverte/1/YieldDay");
+        assert_eq!(sensor["device"]["identifiers"][0], "ahoy_114184511809");
+
+        assert_eq!(
+            sensor_classes("°C"),
+            (Some("temperature"), Some("measurement"))
+        );
+        assert_eq!(sensor_classes("%"), (None, Some("measurement")));
+    }
+}
@@ -0,0 +1,339 @@
+use crate::{
+    config::{env_var, override_from_env},
+    CrawledInverter, ErrorKind, Sink,
+};
+
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+
+use std::{collections::VecDeque, fmt::Write, time::Duration};
+
+/// The server to write to, the `[influx]` section of the configuration file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InfluxConfig {
+    /// base url of the server, e.g. `http://localhost:8086`
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: Option<String>,
+    /// lines sent with one request
+    pub batch_size: usize,
+    /// lines kept while the server is unreachable, the oldest are dropped first
+    pub max_buffered: usize,
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        Self::new(String::new(), String::new(), String::new())
+    }
+}
+
+impl InfluxConfig {
+    pub fn new(url: String, org: String, bucket: String) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            org,
+            bucket,
+            token: None,
+            batch_size: 5000,
+            max_buffered: 100_000,
+        }
+    }
+
+    /// Override `config` with `INFLUX_URL`, `INFLUX_ORG`, `INFLUX_BUCKET`,
+    /// `INFLUX_TOKEN`, `INFLUX_BATCH_SIZE` and `INFLUX_MAX_BUFFERED`. Without
+    /// a configuration, `INFLUX_URL` creates one.
+    pub fn apply_env(config: Option<Self>) -> Result<Option<Self>, ErrorKind> {
+        let mut config = match (config, env_var::<String>("INFLUX_URL")?) {
+            (Some(config), Some(url)) => Self { url, ..config },
+            (Some(config), None) => config,
+            (None, Some(url)) => Self::new(url, String::new(), String::new()),
+            (None, None) => return Ok(None),
+        };
+        config.url = config.url.trim_end_matches('/').to_string();
+        override_from_env(&mut config.org, "INFLUX_ORG")?;
+        override_from_env(&mut config.bucket, "INFLUX_BUCKET")?;
+        if let Some(token) = env_var("INFLUX_TOKEN")? {
+            config.token = Some(token);
+        }
+        override_from_env(&mut config.batch_size, "INFLUX_BATCH_SIZE")?;
+        override_from_env(&mut config.max_buffered, "INFLUX_MAX_BUFFERED")?;
+        Ok(Some(config))
+    }
+
+    /// What is wrong with the settings, see [`Config::problems`](crate::Config::problems).
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.url.is_empty() {
+            problems.push("influx.url must be set".to_string());
+        }
+        if self.bucket.is_empty() {
+            problems.push("influx.bucket must be set".to_string());
+        }
+        if self.batch_size == 0 {
+            problems.push("influx.batch_size must be at least 1".to_string());
+        }
+        problems
+    }
+
+    fn write_url(&self) -> String {
+        format!("{}/api/v2/write", self.url)
+    }
+}
+
+/// Escape a measurement name for line protocol.
+fn escape_measurement(value: &str) -> String {
+    value.replace(',', "\\,").replace(' ', "\\ ")
+}
+
+/// Escape a tag key, tag value or field key for line protocol.
+fn escape_key(value: &str) -> String {
+    value
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// Writes every crawled row to InfluxDB (or anything else that speaks its v2
+/// write API, e.g. VictoriaMetrics). Lines that could not be written are kept
+/// and sent again with the next crawl.
+pub struct InfluxSink {
+    config: InfluxConfig,
+    client: Client,
+    buffer: VecDeque<String>,
+}
+
+impl InfluxSink {
+    pub fn new(config: InfluxConfig) -> Self {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .unwrap_or_default();
+        Self {
+            config,
+            client,
+            buffer: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> &InfluxConfig {
+        &self.config
+    }
+
+    /// Lines waiting to be written.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// The latest row of each dataset of `inverter` in line protocol: one
+    /// measurement per inverter, tagged with serial, channel and channel name.
+    pub fn lines(inverter: &CrawledInverter) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (channel, dataset) in inverter.datasets() {
+            let Some(row) = dataset.rows().last() else {
+                continue;
+            };
+            let fields: Vec<String> = dataset
+                .fields()
+                .iter()
+                .zip(&row.values)
+                .filter_map(|(field, value)| {
+                    let value = value.filter(|value| value.is_finite())?;
+                    Some(format!("{}={}", escape_key(&field.name), value))
+                })
+                .collect();
+            if fields.is_empty() {
+                continue;
+            }
+
+            let mut line = escape_measurement(&inverter.name);
+            let _ = write!(
+                line,
+                ",serial={},channel={}",
+                escape_key(&inverter.inverter().serial),
+                channel
+            );
+            if let Some(name) = channel
+                .name(inverter.inverter())
+                .filter(|name| !name.is_empty())
+            {
+                let _ = write!(line, ",channel_name={}", escape_key(name));
+            }
+            let timestamp = row.measured_at.unwrap_or(row.crawled_at);
+            let _ = write!(
+                line,
+                " {} {}",
+                fields.join(","),
+                timestamp.timestamp_nanos_opt().unwrap_or_default()
+            );
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// Send the buffered lines in batches, stopping at the first batch the
+    /// server does not accept.
+    pub async fn flush(&mut self) -> Result<(), ErrorKind> {
+        while !self.buffer.is_empty() {
+            let count = self.buffer.len().min(self.config.batch_size.max(1));
+            let body = self
+                .buffer
+                .iter()
+                .take(count)
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            let mut request = self
+                .client
+                .post(self.config.write_url())
+                .query(&[
+                    ("org", self.config.org.as_str()),
+                    ("bucket", self.config.bucket.as_str()),
+                    ("precision", "ns"),
+                ])
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .body(body);
+            if let Some(token) = &self.config.token {
+                request = request.header("Authorization", format!("Token {}", token));
+            }
+
+            let response = request
+                .send()
+                .await
+                .map_err(|err| ErrorKind::CouldNotPublish(err.to_string()))?;
+            match response.status() {
+                status if status.is_success() => {
+                    self.buffer.drain(..count);
+                }
+                // the lines themselves are rejected, sending them again would not help
+                StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE => {
+                    self.buffer.drain(..count);
+                    let message = response.text().await.unwrap_or_default();
+                    return Err(ErrorKind::CouldNotPublish(format!(
+                        "dropped {} lines: {}",
+                        count, message
+                    )));
+                }
+                status => {
+                    return Err(ErrorKind::CouldNotPublish(format!(
+                        "influx responded with {}, {} lines buffered",
+                        status,
+                        self.buffer.len()
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for InfluxSink {
+    fn name(&self) -> &str {
+        "influx"
+    }
+
+    async fn publish(&mut self, inverter: &CrawledInverter) -> Result<(), ErrorKind> {
+        self.buffer.extend(Self::lines(inverter));
+        let overflow = self.buffer.len().saturating_sub(self.config.max_buffered);
+        if overflow > 0 {
+            log::warn!("influx buffer full, dropping {} lines", overflow);
+            self.buffer.drain(..overflow);
+        }
+        self.flush().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::test_util::crawled_inverter;
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
+
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    #[tokio::test]
+    async fn lines() {
+        let inverter = crawled_inverter().await;
+        let lines = InfluxSink::lines(&inverter);
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0]
+            .starts_with("PV\\ Microinverte,serial=114184511809,channel=summary U_AC=239.7,"));
+        assert!(
+            lines[2].starts_with("PV\\ Microinverte,serial=114184511809,channel=1,channel_name=B ")
+        );
+        assert!(lines[2].contains("YieldDay=19"));
+    }
+
+    /// A stand-in for the write API that fails the first request.
+    async fn serve_write_api() -> (SocketAddr, Arc<Mutex<Vec<(String, String)>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let requests = requests.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let requests = requests.clone();
+                    async move {
+                        let query = request.uri().query().unwrap_or_default().to_string();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        let mut requests = requests.lock().unwrap();
+                        requests.push((query, String::from_utf8_lossy(&body).to_string()));
+                        let status = match requests.len() {
+                            1 => 503,
+                            _ => 204,
+                        };
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        (address, received)
+    }
+
+    #[tokio::test]
+    async fn retry_buffered_lines() {
+        let inverter = crawled_inverter().await;
+        let (address, requests) = serve_write_api().await;
+        let mut config = InfluxConfig::new(
+            format!("http://{}", address),
+            "home".to_string(),
+            "pv".to_string(),
+        );
+        config.batch_size = 4;
+        let mut sink = InfluxSink::new(config);
+
+        assert!(sink.publish(&inverter).await.is_err());
+        assert_eq!(sink.buffered(), 3);
+
+        sink.publish(&inverter).await.unwrap();
+        assert_eq!(sink.buffered(), 0);
+
+        let requests = requests.lock().unwrap();
+        // the failed request, then the six buffered lines in batches of four
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].0, "org=home&bucket=pv&precision=ns");
+        assert_eq!(requests[1].1.lines().count(), 4);
+        assert_eq!(requests[2].1.lines().count(), 2);
+    }
+}
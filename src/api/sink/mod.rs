@@ -0,0 +1,25 @@
+pub mod home_assistant;
+mod influx_sink;
+mod mqtt_sink;
+
+pub use influx_sink::{InfluxConfig, InfluxSink};
+pub use mqtt_sink::{MqttConfig, MqttSink, PayloadFormat};
+
+use crate::{CrawledInverter, ErrorKind};
+
+use async_trait::async_trait;
+
+/// Receives the result of every successful crawl, in addition to the
+/// batched writes to the [`StorageBackend`](crate::StorageBackend).
+#[async_trait]
+pub trait Sink: Send {
+    /// The name inverters select the sink by in their `sinks` setting, see
+    /// [`InverterConfig`](crate::InverterConfig). Unnamed sinks only receive
+    /// inverters without that setting.
+    fn name(&self) -> &str {
+        ""
+    }
+
+    /// Publish the latest row of each dataset of `inverter`.
+    async fn publish(&mut self, inverter: &CrawledInverter) -> Result<(), ErrorKind>;
+}
@@ -0,0 +1,370 @@
+use super::home_assistant::discovery_messages;
+use crate::{
+    config::{env_var, override_from_env},
+    Channel, CrawledInverter, ErrorKind, Sink,
+};
+
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use serde_json::json;
+
+use std::{collections::HashSet, str::FromStr, time::Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    /// only the value, e.g. `12.5`
+    Plain,
+    /// `{"value": 12.5, "unit": "W", "timestamp": "..."}`
+    Json,
+}
+
+impl FromStr for PayloadFormat {
+    type Err = ErrorKind;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "plain" => Ok(PayloadFormat::Plain),
+            "json" => Ok(PayloadFormat::Json),
+            _ => Err(ErrorKind::InvalidArgument(format!(
+                "unknown payload format {:?}, expected plain or json",
+                value
+            ))),
+        }
+    }
+}
+
+/// The broker and topics to publish to, the `[mqtt]` section of the
+/// configuration file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// username and password
+    pub credentials: Option<(String, String)>,
+    pub topic_prefix: String,
+    pub payload_format: PayloadFormat,
+    pub retain: bool,
+    /// publish home assistant discovery configs below this prefix
+    pub discovery_prefix: Option<String>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self::new(String::new())
+    }
+}
+
+impl MqttConfig {
+    pub fn new(host: String) -> Self {
+        Self {
+            host,
+            port: 1883,
+            client_id: "ahoy-dtu-stats".to_string(),
+            credentials: None,
+            topic_prefix: "ahoy".to_string(),
+            payload_format: PayloadFormat::Plain,
+            retain: true,
+            discovery_prefix: None,
+        }
+    }
+
+    /// Override `config` with `MQTT_HOST`, `MQTT_PORT`, `MQTT_CLIENT_ID`,
+    /// `MQTT_USERNAME` and `MQTT_PASSWORD`, `MQTT_TOPIC_PREFIX`, `MQTT_PAYLOAD`
+    /// (`plain` or `json`), `MQTT_RETAIN` and `MQTT_HOME_ASSISTANT` with
+    /// `MQTT_DISCOVERY_PREFIX`. Without a configuration, `MQTT_HOST` creates one.
+    pub fn apply_env(config: Option<Self>) -> Result<Option<Self>, ErrorKind> {
+        let mut config = match (config, env_var::<String>("MQTT_HOST")?) {
+            (Some(config), Some(host)) => Self { host, ..config },
+            (Some(config), None) => config,
+            (None, Some(host)) => Self::new(host),
+            (None, None) => return Ok(None),
+        };
+        override_from_env(&mut config.port, "MQTT_PORT")?;
+        override_from_env(&mut config.client_id, "MQTT_CLIENT_ID")?;
+        if let (Some(username), Some(password)) =
+            (env_var("MQTT_USERNAME")?, env_var("MQTT_PASSWORD")?)
+        {
+            config.credentials = Some((username, password));
+        }
+        override_from_env(&mut config.topic_prefix, "MQTT_TOPIC_PREFIX")?;
+        config.topic_prefix = config.topic_prefix.trim_end_matches('/').to_string();
+        override_from_env(&mut config.payload_format, "MQTT_PAYLOAD")?;
+        override_from_env(&mut config.retain, "MQTT_RETAIN")?;
+        match env_var("MQTT_HOME_ASSISTANT")? {
+            Some(true) => {
+                config.discovery_prefix =
+                    Some(env_var("MQTT_DISCOVERY_PREFIX")?.unwrap_or("homeassistant".to_string()))
+            }
+            Some(false) => config.discovery_prefix = None,
+            None => {}
+        }
+        Ok(Some(config))
+    }
+
+    /// What is wrong with the settings, see [`Config::problems`](crate::Config::problems).
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.host.is_empty() {
+            problems.push("mqtt.host must be set".to_string());
+        }
+        if self.port == 0 {
+            problems.push("mqtt.port must not be 0".to_string());
+        }
+        problems
+    }
+
+    /// Retained topic that is `online` while the crawler is connected and set
+    /// to `offline` by the broker (last will) once the connection is lost.
+    pub fn availability_topic(&self) -> String {
+        format!("{}/status", self.topic_prefix)
+    }
+
+    /// The topic the values of `field` of `inverter` are published to, with
+    /// the DTU as an additional level if it is named.
+    pub fn state_topic(
+        &self,
+        inverter: &CrawledInverter,
+        channel: &Channel,
+        field: &str,
+    ) -> String {
+        let inverter_levels = match inverter.dtu.is_empty() {
+            true => topic_level(&inverter.name),
+            false => format!(
+                "{}/{}",
+                topic_level(&inverter.dtu),
+                topic_level(&inverter.name)
+            ),
+        };
+        format!(
+            "{}/{}/{}/{}",
+            self.topic_prefix,
+            inverter_levels,
+            channel,
+            topic_level(field)
+        )
+    }
+}
+
+/// Publishes every crawled value to `<prefix>/<inverter>/<channel>/<field>`,
+/// or `<prefix>/<dtu>/<inverter>/<channel>/<field>` for named DTUs.
+pub struct MqttSink {
+    config: MqttConfig,
+    client: AsyncClient,
+    /// serials of the inverters home assistant discovery was published for
+    announced: HashSet<String>,
+}
+
+impl MqttSink {
+    /// Connect to the broker. The connection is driven by a background task
+    /// that reconnects on errors and announces availability on every connect.
+    pub fn connect(config: MqttConfig) -> Self {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(
+            config.availability_topic(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some((username, password)) = &config.credentials {
+            options.set_credentials(username, password);
+        }
+
+        let (client, mut event_loop) = AsyncClient::new(options, 64);
+
+        let availability_client = client.clone();
+        let availability_topic = config.availability_topic();
+        tokio::spawn(async move {
+            loop {
+                match event_loop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        log::info!("connected to mqtt broker");
+                        if let Err(e) = availability_client.try_publish(
+                            &availability_topic,
+                            QoS::AtLeastOnce,
+                            true,
+                            "online",
+                        ) {
+                            log::error!("could not publish availability: {:?}", e);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::error!("mqtt connection error: {:?}", e);
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                    }
+                }
+            }
+        });
+
+        Self {
+            config,
+            client,
+            announced: HashSet::new(),
+        }
+    }
+
+    pub fn config(&self) -> &MqttConfig {
+        &self.config
+    }
+
+    pub fn client(&self) -> &AsyncClient {
+        &self.client
+    }
+
+    /// Queue a message without waiting. The queue is only drained while the
+    /// broker is connected, so a full queue fails instead of blocking the crawl.
+    fn send(&self, topic: String, retain: bool, payload: String) -> Result<(), ErrorKind> {
+        self.client
+            .try_publish(topic, QoS::AtLeastOnce, retain, payload)
+            .map_err(|err| ErrorKind::CouldNotPublish(err.to_string()))
+    }
+
+    /// The topics and payloads for the latest values of `inverter`.
+    pub fn messages(config: &MqttConfig, inverter: &CrawledInverter) -> Vec<(String, String)> {
+        let mut messages = Vec::new();
+        for (channel, dataset) in inverter.datasets() {
+            let Some(row) = dataset.rows().last() else {
+                continue;
+            };
+            for (field, value) in dataset.fields().iter().zip(&row.values) {
+                let Some(value) = value else {
+                    continue;
+                };
+                let topic = config.state_topic(inverter, &channel, &field.name);
+                let payload = match config.payload_format {
+                    PayloadFormat::Plain => value.to_string(),
+                    PayloadFormat::Json => json!({
+                        "value": value,
+                        "unit": field.unit,
+                        "timestamp": row.measured_at.unwrap_or(row.crawled_at).to_rfc3339(),
+                    })
+                    .to_string(),
+                };
+                messages.push((topic, payload));
+            }
+        }
+        messages
+    }
+}
+
+/// Replace characters that have a special meaning in topics.
+pub(crate) fn topic_level(name: &str) -> String {
+    name.replace(['/', '+', '#'], "_")
+}
+
+#[async_trait]
+impl Sink for MqttSink {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    async fn publish(&mut self, inverter: &CrawledInverter) -> Result<(), ErrorKind> {
+        if let Some(discovery_prefix) = &self.config.discovery_prefix {
+            let serial = &inverter.inverter().serial;
+            if !self.announced.contains(serial) {
+                for (topic, payload) in discovery_messages(&self.config, discovery_prefix, inverter)
+                {
+                    self.send(topic, true, payload)?;
+                }
+                self.announced.insert(serial.clone());
+            }
+        }
+        for (topic, payload) in Self::messages(&self.config, inverter) {
+            self.send(topic, self.config.retain, payload)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::test_util::crawled_inverter;
+
+    #[tokio::test]
+    async fn messages() {
+        let inverter = crawled_inverter().await;
+        let mut config = MqttConfig::new("localhost".to_string());
+
+        let messages = MqttSink::messages(&config, &inverter);
+        assert!(messages.contains(&(
+            "ahoy/PV Microinverte/summary/P_AC".to_string(),
+            "0".to_string()
+        )));
+        assert!(messages.contains(&(
+            "ahoy/PV Microinverte/1/YieldDay".to_string(),
+            "19".to_string()
+        )));
+
+        config.payload_format = PayloadFormat::Json;
+        let messages = MqttSink::messages(&config, &inverter);
+        let (_, payload) = messages
+            .iter()
+            .find(|(topic, _)| topic == "ahoy/PV Microinverte/0/U_DC")
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["unit"], "V");
+
+        let inverter = inverter.with_dtu("roof");
+        let messages = MqttSink::messages(&config, &inverter);
+        assert!(messages
+            .iter()
+            .any(|(topic, _)| topic == "ahoy/roof/PV Microinverte/summary/P_AC"));
+    }
+
+    /// Needs a broker, e.g. `mosquitto -p 1883`, run with `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn publish_to_local_broker() {
+        let inverter = crawled_inverter().await;
+        let config = MqttConfig::new("localhost".to_string());
+
+        let mut options = MqttOptions::new("ahoy-dtu-stats-test", "localhost", 1883);
+        options.set_keep_alive(Duration::from_secs(5));
+        let (subscriber, mut events) = AsyncClient::new(options, 64);
+        subscriber
+            .subscribe("ahoy/PV Microinverte/#", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+
+        let mut sink = MqttSink::connect(config);
+        sink.publish(&inverter).await.unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Event::Incoming(Packet::Publish(publish)) = events.poll().await.unwrap() {
+                    break publish.topic;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(received.starts_with("ahoy/PV Microinverte/"));
+    }
+
+    #[tokio::test]
+    async fn unreachable_broker() {
+        let inverter = crawled_inverter().await;
+        let mut config = MqttConfig::new("127.0.0.1".to_string());
+        config.port = 9;
+        let mut sink = MqttSink::connect(config);
+
+        let mut results = Vec::new();
+        for _ in 0..5 {
+            let publish = sink.publish(&inverter);
+            results.push(
+                tokio::time::timeout(Duration::from_secs(5), publish)
+                    .await
+                    .expect("publish blocked"),
+            );
+        }
+        assert!(results
+            .iter()
+            .any(|result| matches!(result, Err(ErrorKind::CouldNotPublish(_)))));
+    }
+}
@@ -0,0 +1,395 @@
+use crate::{
+    create_file_with_full_path, Channel, Dataset, ErrorKind, Inverter, Partitioning,
+    RetentionPolicy, Row, StorageBackend,
+};
+
+use async_trait::async_trait;
+use chrono::{Local, NaiveDate};
+use csv::{ReaderBuilder, Writer};
+
+use std::{collections::BTreeMap, fs, path::Path, str::FromStr};
+
+/// What to do when the header of an existing csv file is missing columns of
+/// the dataset that is stored, e.g. after a firmware upgrade of the DTU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SchemaChangePolicy {
+    /// continue in a new file `{channel}.v{n}.csv`
+    #[default]
+    Roll,
+    /// rewrite the existing file with a header containing the old and the new columns
+    Migrate,
+}
+
+impl FromStr for SchemaChangePolicy {
+    type Err = ErrorKind;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "roll" => Ok(SchemaChangePolicy::Roll),
+            "migrate" => Ok(SchemaChangePolicy::Migrate),
+            _ => Err(ErrorKind::InvalidArgument(format!(
+                "unknown schema change policy {:?}, expected roll or migrate",
+                value
+            ))),
+        }
+    }
+}
+
+/// Writes one csv file per channel to `{folder}/{inverter name}/{channel}.csv`,
+/// or to a partition directory in between if partitioning is enabled.
+/// Values are written by column name, so rows are only appended to a file
+/// whose header contains every field of the dataset.
+#[derive(Debug, Clone)]
+pub struct CsvStorage {
+    folder_path: String,
+    schema_policy: SchemaChangePolicy,
+    partitioning: Partitioning,
+    retention: Option<RetentionPolicy>,
+    /// the day retention was last applied on
+    retention_applied_on: Option<NaiveDate>,
+}
+
+fn csv_error(err: impl ToString) -> ErrorKind {
+    ErrorKind::CouldNotWriteToCsv(err.to_string())
+}
+
+/// The path of version `version` of a channel's csv file, where version 1 is
+/// the unversioned `{base}.csv`.
+fn versioned_path(base: &str, version: u32) -> String {
+    match version {
+        1 => format!("{}.csv", base),
+        version => format!("{}.v{}.csv", base, version),
+    }
+}
+
+/// The header of the csv file at `path`, `None` if it does not exist or is empty.
+fn read_header(path: &str) -> Result<Option<Vec<String>>, ErrorKind> {
+    if fs::metadata(path).map_or(true, |metadata| metadata.len() == 0) {
+        return Ok(None);
+    }
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)
+        .map_err(|err| ErrorKind::CouldNotReadCsv(err.to_string()))?;
+    match reader.records().next() {
+        Some(record) => Ok(Some(
+            record
+                .map_err(|err| ErrorKind::CouldNotReadCsv(err.to_string()))?
+                .iter()
+                .map(str::to_string)
+                .collect(),
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Rewrite the file at `path` with `header`, moving every value to the column
+/// of the same name.
+fn migrate_file(path: &str, old_header: &[String], header: &[String]) -> Result<(), ErrorKind> {
+    let mut reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_path(path)
+        .map_err(|err| ErrorKind::CouldNotReadCsv(err.to_string()))?;
+    let positions: Vec<Option<usize>> = header
+        .iter()
+        .map(|column| old_header.iter().position(|old| old == column))
+        .collect();
+
+    let temporary_path = format!("{}.migrating", path);
+    let mut writer = Writer::from_path(&temporary_path).map_err(csv_error)?;
+    writer.write_record(header).map_err(csv_error)?;
+    for record in reader.records() {
+        let record = record.map_err(|err| ErrorKind::CouldNotReadCsv(err.to_string()))?;
+        writer
+            .write_record(positions.iter().map(|position| {
+                position
+                    .and_then(|position| record.get(position))
+                    .unwrap_or("")
+            }))
+            .map_err(csv_error)?;
+    }
+    writer.flush().map_err(csv_error)?;
+    fs::rename(&temporary_path, path).map_err(csv_error)
+}
+
+fn format_row(row: &Row) -> Vec<String> {
+    [
+        row.crawled_at.format("%F %T").to_string(),
+        row.measured_at
+            .map(|measured_at| measured_at.format("%F %T").to_string())
+            .unwrap_or_default(),
+    ]
+    .into_iter()
+    .chain(row.values.iter().map(|value| match value {
+        Some(value) => value.to_string(),
+        None => "".to_string(),
+    }))
+    .collect()
+}
+
+impl CsvStorage {
+    pub fn new(folder_path: String) -> Self {
+        Self {
+            folder_path,
+            schema_policy: SchemaChangePolicy::default(),
+            partitioning: Partitioning::default(),
+            retention: None,
+            retention_applied_on: None,
+        }
+    }
+
+    pub fn with_partitioning(mut self, partitioning: Partitioning) -> Self {
+        self.partitioning = partitioning;
+        self
+    }
+
+    /// Apply `retention` to old partitions once a day. Has no effect without
+    /// partitioning.
+    pub fn with_retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    pub fn with_schema_policy(mut self, schema_policy: SchemaChangePolicy) -> Self {
+        self.schema_policy = schema_policy;
+        self
+    }
+
+    /// Find the file the rows of a channel go to and its header, rolling over
+    /// to a new version or migrating the latest one if its header does not
+    /// fit `columns`.
+    fn prepare_file(
+        &self,
+        base: &str,
+        columns: &[String],
+    ) -> Result<(String, Vec<String>), ErrorKind> {
+        let mut version = 1;
+        while Path::new(&versioned_path(base, version + 1)).exists() {
+            version += 1;
+        }
+        let path = versioned_path(base, version);
+
+        let header = match read_header(&path)? {
+            None => return Ok((path, columns.to_vec())),
+            Some(header) => header,
+        };
+        if columns.iter().all(|column| header.contains(column)) {
+            return Ok((path, header));
+        }
+
+        match self.schema_policy {
+            SchemaChangePolicy::Roll => {
+                let path = versioned_path(base, version + 1);
+                log::warn!("fields changed, continuing in {}", path);
+                Ok((path, columns.to_vec()))
+            }
+            SchemaChangePolicy::Migrate => {
+                let mut migrated = columns.to_vec();
+                migrated.extend(
+                    header
+                        .iter()
+                        .filter(|column| !columns.contains(column))
+                        .cloned(),
+                );
+                log::warn!("fields changed, migrating {}", path);
+                migrate_file(&path, &header, &migrated)?;
+                Ok((path, migrated))
+            }
+        }
+    }
+
+    fn write_rows(&self, base: &str, columns: &[String], rows: &[&Row]) -> Result<(), ErrorKind> {
+        let (csv_path, header) = self.prepare_file(base, columns)?;
+        let positions: Vec<Option<usize>> = header
+            .iter()
+            .map(|column| columns.iter().position(|name| name == column))
+            .collect();
+
+        let file = create_file_with_full_path(csv_path, true, true)?;
+        let metadata = file.metadata().map_err(csv_error)?;
+
+        let mut writer = Writer::from_writer(file);
+
+        if metadata.len() == 0 {
+            writer.write_record(&header).map_err(csv_error)?;
+        }
+        for row in rows {
+            let record = format_row(row);
+            writer
+                .write_record(positions.iter().map(|position| match position {
+                    Some(position) => record[*position].as_str(),
+                    None => "",
+                }))
+                .map_err(csv_error)?;
+        }
+        writer.flush().map_err(csv_error)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for CsvStorage {
+    async fn store(
+        &mut self,
+        inverter: &Inverter,
+        channel: &Channel,
+        dataset: &Dataset,
+    ) -> Result<(), ErrorKind> {
+        let columns: Vec<String> = ["timestamp", "measured_at"]
+            .into_iter()
+            .map(str::to_string)
+            .chain(dataset.fields().iter().map(|field| field.name.clone()))
+            .collect();
+
+        let mut partitions: BTreeMap<Option<String>, Vec<&Row>> = BTreeMap::new();
+        for row in dataset.rows() {
+            partitions
+                .entry(self.partitioning.partition_of(&row.crawled_at))
+                .or_default()
+                .push(row);
+        }
+        for (partition, rows) in partitions {
+            let base = match partition {
+                Some(partition) => format!(
+                    "{}/{}/{}/{}",
+                    self.folder_path, inverter.name, partition, channel
+                ),
+                None => format!("{}/{}/{}", self.folder_path, inverter.name, channel),
+            };
+            self.write_rows(&base, &columns, &rows)?;
+        }
+
+        let today = Local::now().date_naive();
+        if let Some(retention) = self.retention {
+            if self.retention_applied_on != Some(today) {
+                self.retention_applied_on = Some(today);
+                let touched = retention.apply(&self.folder_path, self.partitioning, today)?;
+                if touched > 0 {
+                    log::info!(
+                        "applied {:?} to {} old partitions",
+                        retention.action,
+                        touched
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        api::test_util::{inverter, TempDir},
+        UnitValue,
+    };
+    use chrono::Local;
+    use std::collections::HashMap;
+
+    fn dataset(names: &[&str]) -> Dataset {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let units = vec!["W".to_string(); names.len()];
+        let mut dataset = Dataset::new(&names, &units);
+        let values = names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| (name.clone(), UnitValue::new(index as f32, "W".to_string())))
+            .collect::<HashMap<_, _>>();
+        let now = Local::now();
+        dataset.insert_row(&values, &now, Some(now));
+        dataset
+    }
+
+    #[tokio::test]
+    async fn changed_fields() {
+        let temp_dir = TempDir::new("schema");
+        let folder = temp_dir.path();
+        let inverter = inverter("inverter", 1);
+        let header = |path: &str| read_header(&format!("{}/{}", folder, path)).unwrap();
+
+        let mut storage = CsvStorage::new(format!("{}/roll", folder));
+        storage
+            .store(&inverter, &Channel::Summary, &dataset(&["A", "B"]))
+            .await
+            .unwrap();
+        // reordered fields are written by name
+        storage
+            .store(&inverter, &Channel::Summary, &dataset(&["B", "A"]))
+            .await
+            .unwrap();
+        storage
+            .store(&inverter, &Channel::Summary, &dataset(&["A", "C"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            header("roll/inverter/summary.csv").unwrap()[2..],
+            ["A", "B"]
+        );
+        assert_eq!(
+            header("roll/inverter/summary.v2.csv").unwrap()[2..],
+            ["A", "C"]
+        );
+        let content = fs::read_to_string(format!("{}/roll/inverter/summary.csv", folder)).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert!(lines[1].ends_with(",0,1"));
+        assert!(lines[2].ends_with(",1,0"));
+
+        let mut storage = CsvStorage::new(format!("{}/migrate", folder))
+            .with_schema_policy(SchemaChangePolicy::Migrate);
+        storage
+            .store(&inverter, &Channel::Summary, &dataset(&["A", "B"]))
+            .await
+            .unwrap();
+        storage
+            .store(&inverter, &Channel::Summary, &dataset(&["C", "A"]))
+            .await
+            .unwrap();
+        let content =
+            fs::read_to_string(format!("{}/migrate/inverter/summary.csv", folder)).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert!(lines[0].ends_with(",C,A,B"));
+        assert!(lines[1].ends_with(",,0,1"));
+        assert!(lines[2].ends_with(",0,1,"));
+
+        // files from before measured_at was recorded change their schema
+        let legacy = |policy: &str| {
+            let path = format!("{}/legacy-{}/inverter/summary.csv", folder, policy);
+            fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+            fs::write(&path, "timestamp,A,B\n2024-01-20 12:00:00,5,6\n").unwrap();
+            path
+        };
+        let measured_at = Local::now().format("%F").to_string();
+
+        let rolled = legacy("roll");
+        CsvStorage::new(format!("{}/legacy-roll", folder))
+            .store(&inverter, &Channel::Summary, &dataset(&["A", "B"]))
+            .await
+            .unwrap();
+        assert_eq!(fs::read_to_string(&rolled).unwrap().lines().count(), 2);
+        let content = fs::read_to_string(versioned_path(&rolled[..rolled.len() - 4], 2)).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines[0], "timestamp,measured_at,A,B");
+        assert!(lines[1]
+            .split(',')
+            .nth(1)
+            .unwrap()
+            .starts_with(&measured_at));
+
+        let migrated = legacy("migrate");
+        CsvStorage::new(format!("{}/legacy-migrate", folder))
+            .with_schema_policy(SchemaChangePolicy::Migrate)
+            .store(&inverter, &Channel::Summary, &dataset(&["A", "B"]))
+            .await
+            .unwrap();
+        let content = fs::read_to_string(&migrated).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines[0], "timestamp,measured_at,A,B");
+        assert_eq!(lines[1], "2024-01-20 12:00:00,,5,6");
+        assert!(lines[2]
+            .split(',')
+            .nth(1)
+            .unwrap()
+            .starts_with(&measured_at));
+    }
+}
@@ -0,0 +1,164 @@
+mod csv_storage;
+#[cfg(feature = "parquet")]
+mod parquet_storage;
+mod partition;
+mod sqlite_storage;
+
+pub use csv_storage::{CsvStorage, SchemaChangePolicy};
+#[cfg(feature = "parquet")]
+pub use parquet_storage::{convert_csv_tree, export_entrypoint, ParquetStorage};
+pub use partition::{Partitioning, RetentionAction, RetentionPolicy};
+pub use sqlite_storage::SqliteStorage;
+
+use crate::{
+    config::{env_var, from_str, override_from_env},
+    dtu_folder, Channel, Dataset, ErrorKind, Inverter,
+};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use std::str::FromStr;
+
+/// A place crawled datasets are persisted to.
+#[async_trait]
+pub trait StorageBackend: Send {
+    /// Persist all rows of `dataset`. The rows are removed from the dataset by
+    /// the caller once this returns successfully, so a backend may be handed
+    /// the same rows again after an error.
+    async fn store(
+        &mut self,
+        inverter: &Inverter,
+        channel: &Channel,
+        dataset: &Dataset,
+    ) -> Result<(), ErrorKind>;
+}
+
+/// Where crawled rows are persisted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageKind {
+    #[default]
+    Csv,
+    Sqlite,
+    /// only available with the `parquet` feature
+    Parquet,
+}
+
+impl FromStr for StorageKind {
+    type Err = ErrorKind;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "csv" => Ok(StorageKind::Csv),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            "parquet" => Ok(StorageKind::Parquet),
+            _ => Err(ErrorKind::InvalidArgument(format!(
+                "unknown storage backend {:?}, expected csv, sqlite or parquet",
+                value
+            ))),
+        }
+    }
+}
+
+/// The storage backend and its settings, the `[storage]` section of the
+/// configuration file.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    #[serde(deserialize_with = "from_str")]
+    pub backend: StorageKind,
+    /// what the csv backend does when the DTU reports different fields
+    #[serde(deserialize_with = "from_str")]
+    pub schema_change: SchemaChangePolicy,
+    /// how the csv files are split up over time
+    #[serde(deserialize_with = "from_str")]
+    pub partitioning: Partitioning,
+    /// days after which csv partitions are compressed or deleted
+    pub retention_days: Option<u32>,
+    #[serde(deserialize_with = "from_str")]
+    pub retention_action: RetentionAction,
+    /// defaults to `ahoy.sqlite` in the folder of the DTU
+    pub sqlite_path: Option<String>,
+    pub parquet_row_group_size: Option<usize>,
+}
+
+impl StorageConfig {
+    /// Override the settings with `STORAGE_BACKEND` (`csv`, `sqlite` or
+    /// `parquet`), `CSV_SCHEMA_CHANGE` (`roll` or `migrate`),
+    /// `CSV_PARTITIONING` (`none`, `day`, `month` or `hive`),
+    /// `CSV_RETENTION_DAYS`, `CSV_RETENTION_ACTION` (`compress` or `delete`),
+    /// `SQLITE_PATH` and `PARQUET_ROW_GROUP_SIZE`.
+    pub fn apply_env(&mut self) -> Result<(), ErrorKind> {
+        override_from_env(&mut self.backend, "STORAGE_BACKEND")?;
+        override_from_env(&mut self.schema_change, "CSV_SCHEMA_CHANGE")?;
+        override_from_env(&mut self.partitioning, "CSV_PARTITIONING")?;
+        if let Some(days) = env_var("CSV_RETENTION_DAYS")? {
+            self.retention_days = Some(days);
+        }
+        override_from_env(&mut self.retention_action, "CSV_RETENTION_ACTION")?;
+        if let Some(path) = env_var("SQLITE_PATH")? {
+            self.sqlite_path = Some(path);
+        }
+        if let Some(rows) = env_var("PARQUET_ROW_GROUP_SIZE")? {
+            self.parquet_row_group_size = Some(rows);
+        }
+        Ok(())
+    }
+
+    /// What is wrong with the settings, see [`Config::problems`](crate::Config::problems).
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.retention_days == Some(0) {
+            problems.push("storage.retention_days must be at least 1".to_string());
+        }
+        if self.parquet_row_group_size == Some(0) {
+            problems.push("storage.parquet_row_group_size must be at least 1".to_string());
+        }
+        if self.backend == StorageKind::Parquet && cfg!(not(feature = "parquet")) {
+            problems
+                .push("storage.backend parquet needs a build with the parquet feature".to_string());
+        }
+        problems
+    }
+
+    /// Open the storage of the DTU named `dtu`, whose files go to `{out_dir}/{dtu}`.
+    pub fn open(&self, out_dir: &str, dtu: &str) -> Result<Box<dyn StorageBackend>, ErrorKind> {
+        if let Some(problem) = self.problems().into_iter().next() {
+            return Err(ErrorKind::InvalidConfig(problem));
+        }
+        let out_dir = dtu_folder(out_dir, dtu);
+        match self.backend {
+            StorageKind::Csv => {
+                let mut storage = CsvStorage::new(out_dir)
+                    .with_schema_policy(self.schema_change)
+                    .with_partitioning(self.partitioning);
+                if let Some(max_age_days) = self.retention_days {
+                    storage = storage.with_retention(RetentionPolicy {
+                        max_age_days,
+                        action: self.retention_action,
+                    });
+                }
+                Ok(Box::new(storage))
+            }
+            StorageKind::Sqlite => {
+                let path = match &self.sqlite_path {
+                    Some(path) => path.clone(),
+                    None => format!("{}/ahoy.sqlite", out_dir),
+                };
+                Ok(Box::new(SqliteStorage::open(&path)?))
+            }
+            #[cfg(feature = "parquet")]
+            StorageKind::Parquet => {
+                let mut storage = ParquetStorage::new(out_dir);
+                if let Some(rows) = self.parquet_row_group_size {
+                    storage = storage.with_row_group_size(rows);
+                }
+                Ok(Box::new(storage))
+            }
+            #[cfg(not(feature = "parquet"))]
+            StorageKind::Parquet => Err(ErrorKind::InvalidConfig(
+                "the parquet feature is disabled".to_string(),
+            )),
+        }
+    }
+}
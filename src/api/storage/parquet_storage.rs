@@ -0,0 +1,416 @@
+use crate::{
+    api::{
+        cli::out_dir,
+        report::{find_channel_files, open_csv},
+    },
+    dtu_folder, Channel, Dataset, EmptyField, ErrorKind, Inverter, Row, StorageBackend,
+};
+
+use arrow_array::{ArrayRef, Float32Array, RecordBatch, TimestampMillisecondArray};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use async_trait::async_trait;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    path::Path,
+    sync::Arc,
+};
+
+/// Rows per row group if nothing else is configured.
+pub const DEFAULT_ROW_GROUP_SIZE: usize = 64 * 1024;
+
+/// Units the Ahoy firmware reports for its fields, used when converting csv
+/// files, which do not record units.
+const AHOY_UNITS: &[(&str, &str)] = &[
+    ("U_AC", "V"),
+    ("I_AC", "A"),
+    ("P_AC", "W"),
+    ("Q_AC", "var"),
+    ("F_AC", "Hz"),
+    ("PF_AC", ""),
+    ("Temp", "°C"),
+    ("YieldTotal", "kWh"),
+    ("YieldDay", "Wh"),
+    ("P_DC", "W"),
+    ("Efficiency", "%"),
+    ("U_DC", "V"),
+    ("I_DC", "A"),
+    ("Irradiation", "%"),
+    ("MaxPower", "W"),
+];
+
+fn parquet_error(err: impl ToString) -> ErrorKind {
+    ErrorKind::CouldNotWriteToParquet(err.to_string())
+}
+
+/// The schema of a dataset: the crawl time and the time the DTU heard from
+/// the inverter as UTC timestamps, followed by one nullable float column per
+/// field with its unit in the column metadata.
+fn schema(fields: &[EmptyField], metadata: HashMap<String, String>) -> Arc<Schema> {
+    let timestamp = DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into()));
+    let columns: Vec<Field> = [
+        Field::new("timestamp", timestamp.clone(), false),
+        Field::new("measured_at", timestamp, true),
+    ]
+    .into_iter()
+    .chain(fields.iter().map(|field| {
+        Field::new(&field.name, DataType::Float32, true)
+            .with_metadata(HashMap::from([("unit".to_string(), field.unit.clone())]))
+    }))
+    .collect();
+    Arc::new(Schema::new_with_metadata(columns, metadata))
+}
+
+fn record_batch(schema: &Arc<Schema>, rows: &[&Row]) -> Result<RecordBatch, ErrorKind> {
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(
+            TimestampMillisecondArray::from(
+                rows.iter()
+                    .map(|row| row.crawled_at.timestamp_millis())
+                    .collect::<Vec<_>>(),
+            )
+            .with_timezone("UTC"),
+        ),
+        Arc::new(
+            TimestampMillisecondArray::from(
+                rows.iter()
+                    .map(|row| row.measured_at.map(|time| time.timestamp_millis()))
+                    .collect::<Vec<_>>(),
+            )
+            .with_timezone("UTC"),
+        ),
+    ];
+    for index in 0..schema.fields().len() - 2 {
+        columns.push(Arc::new(Float32Array::from(
+            rows.iter()
+                .map(|row| row.values.get(index).copied().flatten())
+                .collect::<Vec<_>>(),
+        )));
+    }
+    RecordBatch::try_new(schema.clone(), columns).map_err(parquet_error)
+}
+
+/// Write `batches` to `path` in row groups of `row_group_size` rows. The file
+/// is written next to `path` first, so `path` is always a complete file.
+fn write_file(
+    path: &Path,
+    schema: &Arc<Schema>,
+    batches: &[RecordBatch],
+    row_group_size: usize,
+) -> Result<(), ErrorKind> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|_| ErrorKind::CouldNotCreateFolder(parent.display().to_string()))?;
+    }
+    let temporary_path = path.with_extension("parquet.writing");
+    let file = File::create(&temporary_path)
+        .map_err(|_| ErrorKind::CouldNotCreateFile(temporary_path.display().to_string()))?;
+    let properties = WriterProperties::builder()
+        .set_max_row_group_size(row_group_size)
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer =
+        ArrowWriter::try_new(file, schema.clone(), Some(properties)).map_err(parquet_error)?;
+    for batch in batches {
+        writer.write(batch).map_err(parquet_error)?;
+    }
+    writer.close().map_err(parquet_error)?;
+    fs::rename(&temporary_path, path).map_err(parquet_error)
+}
+
+/// The path of the next part file in the directory `day`.
+fn next_part(day: &str) -> String {
+    let parts = fs::read_dir(day)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_name().to_string_lossy().ends_with(".parquet"))
+                .count()
+        })
+        .unwrap_or(0);
+    format!("{}/part-{:05}.parquet", day, parts)
+}
+
+/// Writes the rows of every store to a new part file
+/// `{folder}/{inverter name}/{channel}/{yyyy-mm-dd}/part-{n}.parquet`, as
+/// parquet files cannot be appended to. Each part carries its own schema,
+/// readers like duckdb or pyarrow merge the parts of a day by column name.
+#[derive(Debug, Clone)]
+pub struct ParquetStorage {
+    folder_path: String,
+    row_group_size: usize,
+}
+
+impl ParquetStorage {
+    pub fn new(folder_path: String) -> Self {
+        Self {
+            folder_path,
+            row_group_size: DEFAULT_ROW_GROUP_SIZE,
+        }
+    }
+
+    pub fn with_row_group_size(mut self, row_group_size: usize) -> Self {
+        self.row_group_size = row_group_size;
+        self
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ParquetStorage {
+    async fn store(
+        &mut self,
+        inverter: &Inverter,
+        channel: &Channel,
+        dataset: &Dataset,
+    ) -> Result<(), ErrorKind> {
+        let mut metadata = HashMap::from([
+            ("inverter".to_string(), inverter.name.clone()),
+            ("serial".to_string(), inverter.serial.clone()),
+            ("channel".to_string(), channel.to_string()),
+        ]);
+        if let Some(name) = channel.name(inverter) {
+            metadata.insert("channel_name".to_string(), name.to_string());
+        }
+        let schema = schema(dataset.fields(), metadata);
+
+        let mut days: BTreeMap<String, Vec<&Row>> = BTreeMap::new();
+        for row in dataset.rows() {
+            days.entry(row.crawled_at.format("%Y-%m-%d").to_string())
+                .or_default()
+                .push(row);
+        }
+        for (day, rows) in days {
+            let day = format!("{}/{}/{}/{}", self.folder_path, inverter.name, channel, day);
+            let path = next_part(&day);
+            let batch = record_batch(&schema, &rows)?;
+            write_file(Path::new(&path), &schema, &[batch], self.row_group_size)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_time(value: &str) -> Option<DateTime<Local>> {
+    let time = NaiveDateTime::parse_from_str(value, "%F %T").ok()?;
+    Local.from_local_datetime(&time).earliest()
+}
+
+/// Convert the csv files of every channel below `csv_folder` into one parquet
+/// file per channel at `{parquet_folder}/{inverter name}/{channel}.parquet`,
+/// or `{parquet_folder}/{dtu}/{inverter name}/{channel}.parquet` for named DTUs.
+/// Columns of all versions and partitions of a channel are merged by name.
+/// Returns the number of files written.
+pub fn convert_csv_tree(
+    csv_folder: &str,
+    parquet_folder: &str,
+    row_group_size: usize,
+) -> Result<usize, ErrorKind> {
+    let mut written = 0;
+    for ((dtu, inverter, channel), paths) in find_channel_files(csv_folder)? {
+        let mut fields: Vec<EmptyField> = Vec::new();
+        let mut rows: Vec<Row> = Vec::new();
+        for path in paths {
+            let read_error =
+                |err: csv::Error| ErrorKind::CouldNotReadCsv(format!("{:?}: {}", path, err));
+            let mut reader = open_csv(&path)?;
+            let headers = reader.headers().map_err(read_error)?.clone();
+            // files written before `measured_at` existed start their fields in the second column
+            let first_field = match headers.get(1) {
+                Some("measured_at") => 2,
+                _ => 1,
+            };
+            let mut positions = Vec::new();
+            for name in headers.iter().skip(first_field) {
+                let position = match fields.iter().position(|field| field.name == name) {
+                    Some(position) => position,
+                    None => {
+                        let unit = AHOY_UNITS
+                            .iter()
+                            .find(|(field, _)| *field == name)
+                            .map(|(_, unit)| unit.to_string())
+                            .unwrap_or_default();
+                        fields.push(EmptyField {
+                            name: name.to_string(),
+                            unit,
+                        });
+                        fields.len() - 1
+                    }
+                };
+                positions.push(position);
+            }
+
+            for record in reader.records() {
+                let record = record.map_err(read_error)?;
+                let Some(crawled_at) = record.get(0).and_then(parse_time) else {
+                    continue;
+                };
+                let mut values = vec![None; fields.len()];
+                for (position, value) in positions.iter().zip(record.iter().skip(first_field)) {
+                    if let Some(slot) = values.get_mut(*position) {
+                        *slot = value.parse().ok();
+                    }
+                }
+                rows.push(Row {
+                    values,
+                    crawled_at,
+                    measured_at: match first_field {
+                        2 => record.get(1).and_then(parse_time),
+                        _ => None,
+                    },
+                });
+            }
+        }
+        rows.sort_by_key(|row| row.crawled_at);
+
+        let mut metadata = HashMap::from([
+            ("inverter".to_string(), inverter.clone()),
+            ("channel".to_string(), channel.clone()),
+        ]);
+        if !dtu.is_empty() {
+            metadata.insert("dtu".to_string(), dtu.clone());
+        }
+        let schema = schema(&fields, metadata);
+        let batches = rows
+            .chunks(row_group_size)
+            .map(|chunk| record_batch(&schema, &chunk.iter().collect::<Vec<_>>()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let path = format!(
+            "{}/{}/{}.parquet",
+            dtu_folder(parquet_folder, &dtu),
+            inverter,
+            channel
+        );
+        write_file(Path::new(&path), &schema, &batches, row_group_size)?;
+        log::info!("wrote {} rows to {}", rows.len(), path);
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// Run the `export` subcommand: `export [--dir <csv folder>] --out <parquet folder>
+/// [--row-group-size <rows>] [--config <path>]`. The csv folder defaults to the
+/// configured `out_dir`.
+pub fn export_entrypoint(args: &[String]) -> Result<(), ErrorKind> {
+    let mut csv_folder = None;
+    let mut config = None;
+    let mut parquet_folder = None;
+    let mut row_group_size = DEFAULT_ROW_GROUP_SIZE;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or(ErrorKind::InvalidArgument(format!("{} needs a value", arg)))
+        };
+        match arg.as_str() {
+            "--dir" => csv_folder = Some(value()?.clone()),
+            "--config" => config = Some(value()?.clone()),
+            "--out" => parquet_folder = Some(value()?.clone()),
+            "--row-group-size" => {
+                row_group_size = value()?
+                    .parse()
+                    .map_err(|_| ErrorKind::InvalidArgument(format!("invalid {}", arg)))?
+            }
+            _ => {
+                return Err(ErrorKind::InvalidArgument(format!(
+                    "unknown argument '{}'",
+                    arg
+                )))
+            }
+        }
+    }
+    let parquet_folder =
+        parquet_folder.ok_or(ErrorKind::InvalidArgument("--out is required".to_string()))?;
+
+    let csv_folder = out_dir(csv_folder, config.as_deref())?;
+    let written = convert_csv_tree(&csv_folder, &parquet_folder, row_group_size)?;
+    println!("wrote {} parquet files to {}", written, parquet_folder);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        api::test_util::{inverter, TempDir},
+        CsvStorage, UnitValue,
+    };
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    /// The schema and record batches of the parquet file at `path`.
+    fn read_file(path: &Path) -> Result<(Arc<Schema>, Vec<RecordBatch>), ErrorKind> {
+        let file = File::open(path)
+            .map_err(|_| ErrorKind::CouldNotOpenFile(path.display().to_string()))?;
+        let builder = ParquetRecordBatchReaderBuilder::try_new(file).map_err(parquet_error)?;
+        let schema = builder.schema().clone();
+        let batches = builder
+            .build()
+            .map_err(parquet_error)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(parquet_error)?;
+        Ok((schema, batches))
+    }
+
+    #[tokio::test]
+    async fn store_and_convert() {
+        let temp_dir = TempDir::new("parquet");
+        let folder = temp_dir.path();
+        let inverter = inverter("inverter", 1);
+        let mut dataset = Dataset::new(
+            &["P_DC".to_string(), "U_DC".to_string()],
+            &["W".to_string(), "V".to_string()],
+        );
+        let values = HashMap::from([("P_DC".to_string(), UnitValue::new(120.5, "W".to_string()))]);
+        let now = Local::now();
+        dataset.insert_row(&values, &now, None);
+
+        let mut storage = ParquetStorage::new(format!("{}/parquet", folder));
+        storage
+            .store(&inverter, &Channel::Input(0), &dataset)
+            .await
+            .unwrap();
+        storage
+            .store(&inverter, &Channel::Input(0), &dataset)
+            .await
+            .unwrap();
+        let day = format!("{}/parquet/inverter/0/{}", folder, now.format("%Y-%m-%d"));
+        assert_eq!(fs::read_dir(&day).unwrap().count(), 2);
+        let (schema, batches) =
+            read_file(Path::new(&format!("{}/part-00001.parquet", day))).unwrap();
+        assert_eq!(batches.iter().map(RecordBatch::num_rows).sum::<usize>(), 1);
+        assert_eq!(
+            schema.field_with_name("P_DC").unwrap().metadata()["unit"],
+            "W"
+        );
+
+        dataset
+            .save_to(
+                &mut CsvStorage::new(format!("{}/csv", folder)),
+                &inverter,
+                &Channel::Input(0),
+            )
+            .await
+            .unwrap();
+        let written = convert_csv_tree(
+            &format!("{}/csv", folder),
+            &format!("{}/converted", folder),
+            10,
+        )
+        .unwrap();
+        assert_eq!(written, 1);
+        let (schema, batches) = read_file(Path::new(&format!(
+            "{}/converted/inverter/0.parquet",
+            folder
+        )))
+        .unwrap();
+        assert_eq!(schema.fields().len(), 4);
+        let p_dc = batches[0]
+            .column(2)
+            .as_any()
+            .downcast_ref::<Float32Array>()
+            .unwrap();
+        assert_eq!(p_dc.value(0), 120.5);
+    }
+}
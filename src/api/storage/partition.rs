@@ -0,0 +1,267 @@
+use crate::ErrorKind;
+
+use chrono::{DateTime, Datelike, Local, NaiveDate};
+use flate2::{write::GzEncoder, Compression};
+
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// How the csv files of an inverter are split up over time. Partitions are
+/// directories below `{folder}/{inverter name}/`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Partitioning {
+    /// everything in `{channel}.csv`
+    #[default]
+    None,
+    /// `{yyyy-mm-dd}/{channel}.csv`
+    Day,
+    /// `{yyyy-mm}/{channel}.csv`
+    Month,
+    /// `year={yyyy}/month={mm}/day={dd}/{channel}.csv`
+    Hive,
+}
+
+impl FromStr for Partitioning {
+    type Err = ErrorKind;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(Partitioning::None),
+            "day" => Ok(Partitioning::Day),
+            "month" => Ok(Partitioning::Month),
+            "hive" => Ok(Partitioning::Hive),
+            _ => Err(ErrorKind::InvalidArgument(format!(
+                "unknown partitioning {:?}, expected none, day, month or hive",
+                value
+            ))),
+        }
+    }
+}
+
+impl Partitioning {
+    /// The directory (relative to the inverter's folder) rows crawled at
+    /// `time` are written to, `None` without partitioning.
+    pub fn partition_of(&self, time: &DateTime<Local>) -> Option<String> {
+        match self {
+            Partitioning::None => None,
+            Partitioning::Day => Some(time.format("%Y-%m-%d").to_string()),
+            Partitioning::Month => Some(time.format("%Y-%m").to_string()),
+            Partitioning::Hive => Some(time.format("year=%Y/month=%m/day=%d").to_string()),
+        }
+    }
+
+    /// The last day covered by the partition at `relative_path`, `None` if
+    /// the path is not a partition of this scheme.
+    fn last_day_of(&self, relative_path: &str) -> Option<NaiveDate> {
+        match self {
+            Partitioning::None => None,
+            Partitioning::Day => NaiveDate::parse_from_str(relative_path, "%Y-%m-%d").ok(),
+            Partitioning::Month => {
+                let first =
+                    NaiveDate::parse_from_str(&format!("{}-01", relative_path), "%Y-%m-%d").ok()?;
+                let next = match first.month() {
+                    12 => NaiveDate::from_ymd_opt(first.year() + 1, 1, 1)?,
+                    month => NaiveDate::from_ymd_opt(first.year(), month + 1, 1)?,
+                };
+                next.pred_opt()
+            }
+            Partitioning::Hive => {
+                NaiveDate::parse_from_str(relative_path, "year=%Y/month=%m/day=%d").ok()
+            }
+        }
+    }
+
+    /// All partitions below `inverter_path` with the last day they cover.
+    fn partitions(&self, inverter_path: &Path) -> Vec<(PathBuf, NaiveDate)> {
+        let depth = match self {
+            Partitioning::None => return Vec::new(),
+            Partitioning::Day | Partitioning::Month => 1,
+            Partitioning::Hive => 3,
+        };
+        let mut directories = vec![(inverter_path.to_path_buf(), String::new())];
+        for _ in 0..depth {
+            directories = directories
+                .into_iter()
+                .flat_map(|(path, relative)| {
+                    fs::read_dir(path)
+                        .into_iter()
+                        .flatten()
+                        .flatten()
+                        .filter(|entry| entry.path().is_dir())
+                        .map(move |entry| {
+                            let name = entry.file_name().to_string_lossy().to_string();
+                            let relative = match relative.is_empty() {
+                                true => name,
+                                false => format!("{}/{}", relative, name),
+                            };
+                            (entry.path(), relative)
+                        })
+                })
+                .collect();
+        }
+        directories
+            .into_iter()
+            .filter_map(|(path, relative)| Some((path, self.last_day_of(&relative)?)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RetentionAction {
+    Delete,
+    /// replace every `.csv` file with a `.csv.gz`
+    #[default]
+    Compress,
+}
+
+impl FromStr for RetentionAction {
+    type Err = ErrorKind;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "delete" => Ok(RetentionAction::Delete),
+            "compress" => Ok(RetentionAction::Compress),
+            _ => Err(ErrorKind::InvalidArgument(format!(
+                "unknown retention action {:?}, expected compress or delete",
+                value
+            ))),
+        }
+    }
+}
+
+/// Deletes or compresses partitions that ended more than `max_age_days` ago.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub max_age_days: u32,
+    pub action: RetentionAction,
+}
+
+fn retention_error(path: &Path, err: io::Error) -> ErrorKind {
+    ErrorKind::CouldNotApplyRetention(format!("{}: {}", path.display(), err))
+}
+
+fn compress_file(path: &Path) -> Result<(), ErrorKind> {
+    let gz_path = path.with_extension("csv.gz");
+    let mut source = File::open(path).map_err(|err| retention_error(path, err))?;
+    let target = File::create(&gz_path).map_err(|err| retention_error(&gz_path, err))?;
+    let mut encoder = GzEncoder::new(target, Compression::default());
+    io::copy(&mut source, &mut encoder).map_err(|err| retention_error(path, err))?;
+    encoder
+        .finish()
+        .map_err(|err| retention_error(&gz_path, err))?;
+    fs::remove_file(path).map_err(|err| retention_error(path, err))
+}
+
+impl RetentionPolicy {
+    /// Apply the policy to the partitions of every inverter below
+    /// `folder_path`. Returns the number of partitions that were touched.
+    pub fn apply(
+        &self,
+        folder_path: &str,
+        partitioning: Partitioning,
+        today: NaiveDate,
+    ) -> Result<usize, ErrorKind> {
+        let Some(cutoff) = today.checked_sub_days(chrono::Days::new(self.max_age_days as u64))
+        else {
+            return Ok(0);
+        };
+        let mut touched = 0;
+        for inverter in fs::read_dir(folder_path)
+            .map_err(|err| retention_error(Path::new(folder_path), err))?
+            .flatten()
+            .filter(|entry| entry.path().is_dir())
+        {
+            for (partition, last_day) in partitioning.partitions(&inverter.path()) {
+                if last_day >= cutoff {
+                    continue;
+                }
+                match self.action {
+                    RetentionAction::Delete => {
+                        fs::remove_dir_all(&partition)
+                            .map_err(|err| retention_error(&partition, err))?;
+                        // drop the `month=` and `year=` directories once they are empty
+                        let mut parent = partition.parent();
+                        while let Some(directory) =
+                            parent.filter(|directory| *directory != inverter.path())
+                        {
+                            if fs::remove_dir(directory).is_err() {
+                                break;
+                            }
+                            parent = directory.parent();
+                        }
+                        touched += 1;
+                    }
+                    RetentionAction::Compress => {
+                        let files: Vec<PathBuf> = fs::read_dir(&partition)
+                            .map_err(|err| retention_error(&partition, err))?
+                            .flatten()
+                            .map(|entry| entry.path())
+                            .filter(|path| path.extension().is_some_and(|ext| ext == "csv"))
+                            .collect();
+                        for file in &files {
+                            compress_file(file)?;
+                        }
+                        if !files.is_empty() {
+                            touched += 1;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(touched)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::test_util::TempDir;
+
+    #[test]
+    fn retention() {
+        let temp_dir = TempDir::new("retention");
+        let folder = Path::new(temp_dir.path());
+        for partition in ["year=2024/month=05/day=31", "year=2024/month=06/day=10"] {
+            let path = folder.join("inverter").join(partition);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("summary.csv"), "timestamp\n").unwrap();
+        }
+        let folder_path = folder.to_str().unwrap();
+        let today = NaiveDate::from_ymd_opt(2024, 6, 12).unwrap();
+
+        let mut policy = RetentionPolicy {
+            max_age_days: 7,
+            action: RetentionAction::Compress,
+        };
+        assert_eq!(
+            policy
+                .apply(folder_path, Partitioning::Hive, today)
+                .unwrap(),
+            1
+        );
+        let old = folder.join("inverter/year=2024/month=05/day=31");
+        assert!(old.join("summary.csv.gz").exists());
+        assert!(!old.join("summary.csv").exists());
+        assert!(folder
+            .join("inverter/year=2024/month=06/day=10/summary.csv")
+            .exists());
+
+        policy.action = RetentionAction::Delete;
+        assert_eq!(
+            policy
+                .apply(folder_path, Partitioning::Hive, today)
+                .unwrap(),
+            1
+        );
+        assert!(!folder.join("inverter/year=2024/month=05").exists());
+
+        assert_eq!(
+            Partitioning::Month.last_day_of("2024-02"),
+            NaiveDate::from_ymd_opt(2024, 2, 29)
+        );
+    }
+}
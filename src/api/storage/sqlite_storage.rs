@@ -0,0 +1,286 @@
+use crate::{create_file_with_full_path, Channel, Dataset, ErrorKind, Inverter, StorageBackend};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use std::collections::HashMap;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS inverters (
+    id INTEGER PRIMARY KEY,
+    serial TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS channels (
+    id INTEGER PRIMARY KEY,
+    inverter_id INTEGER NOT NULL REFERENCES inverters(id),
+    channel TEXT NOT NULL,
+    name TEXT,
+    UNIQUE (inverter_id, channel)
+);
+CREATE TABLE IF NOT EXISTS fields (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    unit TEXT NOT NULL,
+    UNIQUE (name, unit)
+);
+CREATE TABLE IF NOT EXISTS readings (
+    channel_id INTEGER NOT NULL REFERENCES channels(id),
+    field_id INTEGER NOT NULL REFERENCES fields(id),
+    timestamp INTEGER NOT NULL,
+    value REAL,
+    measured_at INTEGER,
+    PRIMARY KEY (channel_id, field_id, timestamp)
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS readings_timestamp ON readings (timestamp);
+";
+
+/// Recreated on every start, so it follows columns added by [`migrate`].
+const VIEW: &str = "
+DROP VIEW IF EXISTS readings_view;
+CREATE VIEW readings_view AS
+    SELECT
+        datetime(readings.timestamp, 'unixepoch', 'localtime') AS time,
+        datetime(readings.measured_at, 'unixepoch', 'localtime') AS measured_at,
+        inverters.serial AS serial,
+        inverters.name AS inverter,
+        channels.channel AS channel,
+        channels.name AS channel_name,
+        fields.name AS field,
+        fields.unit AS unit,
+        readings.value AS value
+    FROM readings
+    JOIN channels ON channels.id = readings.channel_id
+    JOIN inverters ON inverters.id = channels.inverter_id
+    JOIN fields ON fields.id = readings.field_id;
+";
+
+/// Stores readings in normalized tables of a sqlite database. Timestamps are
+/// stored as unix seconds, `readings_view` joins everything for ad-hoc queries.
+pub struct SqliteStorage {
+    connection: Connection,
+    channel_ids: HashMap<(String, Channel), i64>,
+    field_ids: HashMap<(String, String), i64>,
+}
+
+fn sql_error(err: rusqlite::Error) -> ErrorKind {
+    ErrorKind::CouldNotWriteToDatabase(err.to_string())
+}
+
+/// Add the columns databases created by earlier versions are missing.
+fn migrate(connection: &Connection) -> rusqlite::Result<()> {
+    let has_measured_at: bool = connection.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('readings') WHERE name = 'measured_at'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_measured_at {
+        log::info!("adding measured_at to the readings table");
+        connection.execute("ALTER TABLE readings ADD COLUMN measured_at INTEGER", [])?;
+    }
+    Ok(())
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<Self, ErrorKind> {
+        if path != ":memory:" {
+            create_file_with_full_path(path.to_string(), true, false)?;
+        }
+        let connection = Connection::open(path).map_err(sql_error)?;
+        Self::from_connection(connection)
+    }
+
+    pub fn from_connection(connection: Connection) -> Result<Self, ErrorKind> {
+        connection.execute_batch(SCHEMA).map_err(sql_error)?;
+        migrate(&connection).map_err(sql_error)?;
+        connection.execute_batch(VIEW).map_err(sql_error)?;
+        Ok(Self {
+            connection,
+            channel_ids: HashMap::new(),
+            field_ids: HashMap::new(),
+        })
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    fn channel_id(
+        transaction: &Transaction,
+        inverter: &Inverter,
+        channel: &Channel,
+    ) -> rusqlite::Result<i64> {
+        let inverter_id: i64 = transaction.query_row(
+            "INSERT INTO inverters (serial, name) VALUES (?1, ?2)
+            ON CONFLICT (serial) DO UPDATE SET name = excluded.name
+            RETURNING id",
+            params![inverter.serial, inverter.name],
+            |row| row.get(0),
+        )?;
+        transaction.query_row(
+            "INSERT INTO channels (inverter_id, channel, name) VALUES (?1, ?2, ?3)
+            ON CONFLICT (inverter_id, channel) DO UPDATE SET name = excluded.name
+            RETURNING id",
+            params![inverter_id, channel.to_string(), channel.name(inverter)],
+            |row| row.get(0),
+        )
+    }
+
+    fn field_id(transaction: &Transaction, name: &str, unit: &str) -> rusqlite::Result<i64> {
+        let existing = transaction
+            .query_row(
+                "SELECT id FROM fields WHERE name = ?1 AND unit = ?2",
+                params![name, unit],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match existing {
+            Some(id) => Ok(id),
+            None => {
+                transaction.execute(
+                    "INSERT INTO fields (name, unit) VALUES (?1, ?2)",
+                    params![name, unit],
+                )?;
+                Ok(transaction.last_insert_rowid())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SqliteStorage {
+    async fn store(
+        &mut self,
+        inverter: &Inverter,
+        channel: &Channel,
+        dataset: &Dataset,
+    ) -> Result<(), ErrorKind> {
+        let transaction = self.connection.transaction().map_err(sql_error)?;
+
+        let channel_id = match self.channel_ids.get(&(inverter.serial.clone(), *channel)) {
+            Some(id) => *id,
+            None => {
+                let id = Self::channel_id(&transaction, inverter, channel).map_err(sql_error)?;
+                self.channel_ids
+                    .insert((inverter.serial.clone(), *channel), id);
+                id
+            }
+        };
+
+        let mut field_ids = Vec::new();
+        for field in dataset.fields() {
+            let key = (field.name.clone(), field.unit.clone());
+            let id = match self.field_ids.get(&key) {
+                Some(id) => *id,
+                None => {
+                    let id = Self::field_id(&transaction, &field.name, &field.unit)
+                        .map_err(sql_error)?;
+                    self.field_ids.insert(key, id);
+                    id
+                }
+            };
+            field_ids.push(id);
+        }
+
+        {
+            let mut insert = transaction
+                .prepare_cached(
+                    "INSERT OR REPLACE INTO readings
+                    (channel_id, field_id, timestamp, value, measured_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                )
+                .map_err(sql_error)?;
+            for row in dataset.rows() {
+                for (field_id, value) in field_ids.iter().zip(&row.values) {
+                    insert
+                        .execute(params![
+                            channel_id,
+                            field_id,
+                            row.crawled_at.timestamp(),
+                            value,
+                            row.measured_at.map(|measured_at| measured_at.timestamp())
+                        ])
+                        .map_err(sql_error)?;
+                }
+            }
+        }
+
+        let result = transaction.commit().map_err(sql_error);
+        if result.is_err() {
+            // the cached ids might belong to rows that were rolled back
+            self.channel_ids.clear();
+            self.field_ids.clear();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{api::test_util::inverter, UnitValue};
+    use chrono::{Local, TimeZone};
+
+    #[tokio::test]
+    async fn store_dataset() {
+        let inverter = inverter("PV Microinverte", 2);
+        let mut dataset = Dataset::new(
+            &["P_DC".to_string(), "U_DC".to_string()],
+            &["W".to_string(), "V".to_string()],
+        );
+        let values = HashMap::from([
+            ("P_DC".to_string(), UnitValue::new(120.5, "W".to_string())),
+            ("U_DC".to_string(), UnitValue::new(31.2, "V".to_string())),
+        ]);
+        let measured_at = Local.timestamp_opt(1705764469, 0).unwrap();
+        dataset.insert_row(&values, &Local::now(), Some(measured_at));
+
+        let mut storage = SqliteStorage::open(":memory:").unwrap();
+        dataset
+            .save_to(&mut storage, &inverter, &Channel::Input(1))
+            .await
+            .unwrap();
+        assert!(dataset.is_empty());
+
+        let (channel_name, value): (String, f64) = storage
+            .connection()
+            .query_row(
+                "SELECT channel_name, value FROM readings_view WHERE field = 'P_DC'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(channel_name, "B");
+        assert_eq!(value, 120.5);
+        let stored: i64 = storage
+            .connection()
+            .query_row("SELECT measured_at FROM readings LIMIT 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(stored, 1705764469);
+    }
+
+    #[test]
+    fn migrate_readings() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE readings (
+                    channel_id INTEGER NOT NULL,
+                    field_id INTEGER NOT NULL,
+                    timestamp INTEGER NOT NULL,
+                    value REAL,
+                    PRIMARY KEY (channel_id, field_id, timestamp)
+                ) WITHOUT ROWID;
+                INSERT INTO readings VALUES (1, 1, 1705764469, 120.5);",
+            )
+            .unwrap();
+        let storage = SqliteStorage::from_connection(connection).unwrap();
+        let measured_at: Option<i64> = storage
+            .connection()
+            .query_row("SELECT measured_at FROM readings", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(measured_at, None);
+    }
+}
@@ -0,0 +1,73 @@
+//! Fixtures shared by the tests.
+
+use crate::{AhoyApi, CrawledInverter, Inverter, MockDtu, RetryPolicy};
+
+use chrono::{Local, Timelike};
+
+use std::{fs, path::Path, process, sync::Arc};
+
+/// A directory below the temporary directory, removed with everything in it
+/// when dropped, also if the test fails.
+pub(crate) struct TempDir(String);
+
+impl TempDir {
+    /// `ahoy-{name}-{pid}`, emptied if a previous run left it behind.
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("ahoy-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        Self(path.display().to_string())
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.0
+    }
+
+    /// The path of `name` in the directory.
+    pub(crate) fn join(&self, name: &str) -> String {
+        Path::new(&self.0).join(name).display().to_string()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// An inverter as listed by an Ahoy DTU, with serial 114184511809 and the
+/// inputs A, B, … .
+pub(crate) fn inverter(name: &str, channels: usize) -> Inverter {
+    serde_json::from_value(serde_json::json!({
+        "enabled": true,
+        "id": 0,
+        "name": name,
+        "serial": "114184511809",
+        "channels": channels,
+        "version": "10010",
+        "ch_yield_cor": vec![0; channels],
+        "ch_name": (0..channels)
+            .map(|input| ((b'A' + input as u8) as char).to_string())
+            .collect::<Vec<_>>(),
+        "ch_max_pwr": vec![540; channels],
+    }))
+    .unwrap()
+}
+
+/// The inverter of the offline fixtures after one crawl.
+pub(crate) async fn crawled_inverter() -> CrawledInverter {
+    let mut inverter = CrawledInverter::fetch(Arc::new(AhoyApi::offline()), 0)
+        .await
+        .unwrap();
+    inverter.crawl().await.unwrap();
+    inverter
+}
+
+/// A mock DTU at noon whose inverters report a new measurement every 10ms.
+pub(crate) fn mock_dtu(inverters: usize, channels: usize) -> (MockDtu, AhoyApi) {
+    let dtu = MockDtu::new(inverters, channels)
+        .starting_at(Local::now().with_hour(12).unwrap())
+        .with_interval(1)
+        .with_speed(100.0);
+    let api = AhoyApi::new(dtu.spawn().unwrap()).with_retry_policy(RetryPolicy::none());
+    (dtu, api)
+}
@@ -0,0 +1,17 @@
+use ahoy_dtu_stats::{ErrorKind, MockDtu};
+
+use std::{env, net::SocketAddr};
+
+/// Serve a simulated Ahoy DTU on `MOCK_ADDRESS` (default `127.0.0.1:8080`),
+/// configured as described in `MockDtu::from_env`.
+#[tokio::main]
+async fn main() -> Result<(), ErrorKind> {
+    dotenv::dotenv().ok();
+    env_logger::init();
+
+    let address = env::var("MOCK_ADDRESS").unwrap_or("127.0.0.1:8080".to_string());
+    let address: SocketAddr = address
+        .parse()
+        .map_err(|err| ErrorKind::InvalidConfig(format!("MOCK_ADDRESS={:?}: {}", address, err)))?;
+    MockDtu::from_env()?.serve(address).await
+}
@@ -2,4 +2,6 @@ pub mod api;
 
 pub use api::ahoy::*;
 pub use api::crawler::*;
+pub use api::sink::*;
+pub use api::storage::*;
 pub use api::*;
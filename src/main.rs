@@ -1,8 +1,43 @@
 #[allow(unused_imports)]
-use ahoy_dtu_stats::{entrypoint, ErrorKind};
+use ahoy_dtu_stats::{
+    config_entrypoint, crawl_entrypoint, entrypoint, list_entrypoint, once_entrypoint,
+    report_entrypoint, status_entrypoint, ErrorKind, USAGE,
+};
 
 #[tokio::main]
 #[cfg(not(test))]
 async fn main() -> Result<(), ErrorKind> {
-    entrypoint().await
+    dotenv::dotenv().ok();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => entrypoint().await,
+        // flags without a command configure the crawler
+        Some(arg) if arg.starts_with("--") && arg != "--help" => crawl_entrypoint(&args).await,
+        Some("crawl") => crawl_entrypoint(&args[1..]).await,
+        Some("once") => once_entrypoint(&args[1..]).await,
+        Some("list") => list_entrypoint(&args[1..]).await,
+        Some("status") => status_entrypoint(&args[1..]).await,
+        Some("report") => report_entrypoint(&args[1..]),
+        Some("config") => config_entrypoint(&args[1..]),
+        #[cfg(feature = "mock")]
+        Some("simulate") => ahoy_dtu_stats::simulate_entrypoint(&args[1..]).await,
+        #[cfg(not(feature = "mock"))]
+        Some("simulate") => Err(ErrorKind::InvalidArgument(
+            "simulate needs the mock feature".to_string(),
+        )),
+        #[cfg(feature = "parquet")]
+        Some("export") => ahoy_dtu_stats::export_entrypoint(&args[1..]),
+        #[cfg(not(feature = "parquet"))]
+        Some("export") => Err(ErrorKind::InvalidArgument(
+            "export needs the parquet feature".to_string(),
+        )),
+        Some("help" | "--help" | "-h") => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        Some(command) => Err(ErrorKind::InvalidArgument(format!(
+            "unknown command '{}', see `help`",
+            command
+        ))),
+    }
 }